@@ -1,7 +1,19 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
-use securefs::{config, key_manager::KeyManager, storagefile_ops::SecureFileOps};
+use securefs::{
+    config,
+    error::SecureFsError,
+    file_keys::{DeleteMode, KeyTable},
+    key_manager::{write_secret_file, KeyManager, Recipient},
+    key_provider::{self, FileKeyProvider, KeyProvider},
+    keyring::{self, KeyringKeyProvider},
+    shamir::KeyShare,
+    storagefile_ops::SecureFileOps,
+    util,
+    vault::VaultOptions,
+    write_mode::{FileVersion, WriteMode},
+};
 use std::io::{self, Write};
 use std::path::PathBuf;
 use tokio::fs;
@@ -32,6 +44,18 @@ enum Commands {
         /// Encryption key file path
         #[arg(short, long, default_value = "./securefs.key")]
         key_path: String,
+
+        /// Compress new files by default
+        #[arg(short, long)]
+        compress: bool,
+
+        /// Encrypt file and directory names on disk
+        #[arg(long)]
+        encrypt_names: bool,
+
+        /// Store the key with this helper command instead of a key file
+        #[arg(long)]
+        key_command: Option<String>,
     },
 
     /// Encrypt a file
@@ -50,6 +74,18 @@ enum Commands {
         /// Use streaming mode for large files (>10MB recommended)
         #[arg(short, long)]
         stream: bool,
+
+        /// Encrypt to this recipient public key instead of the master key (repeatable)
+        #[arg(short, long = "recipient", value_name = "RECIPIENT")]
+        recipients: Vec<String>,
+
+        /// Fail if a file with this name already exists
+        #[arg(long)]
+        no_clobber: bool,
+
+        /// Only replace the file if it is still at this version
+        #[arg(long, value_name = "VERSION", conflicts_with = "no_clobber")]
+        if_version: Option<FileVersion>,
     },
 
     /// Decrypt a file
@@ -64,27 +100,232 @@ enum Commands {
         /// Use streaming mode for large files
         #[arg(short, long)]
         stream: bool,
+
+        /// Identity file for files encrypted to recipients
+        #[arg(short, long)]
+        identity: Option<PathBuf>,
     },
 
     /// List all encrypted files
     List {
+        /// Only list files in this directory (e.g. docs/2026)
+        dir: Option<String>,
+
         /// Show detailed information
         #[arg(short, long)]
         verbose: bool,
+
+        /// Show files as a directory tree
+        #[arg(short, long, conflicts_with = "verbose")]
+        tree: bool,
     },
 
     /// Remove an encrypted file
     Remove {
-        /// Encrypted filename to remove
+        /// Encrypted filename (or directory, with --recursive) to remove
         name: String,
 
+        /// Remove a directory and everything in it
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Destroy the file's key, so no copy of it can be decrypted again
+        /// (needs `key_table` in the config)
+        #[arg(long, conflicts_with = "recursive")]
+        shred: bool,
+
         /// Skip confirmation prompt
         #[arg(short = 'y', long)]
         yes: bool,
     },
 
+    /// Rename or move a stored file, with its metadata and history
+    #[command(visible_alias = "mv")]
+    Rename {
+        /// Encrypted filename in storage
+        from: String,
+
+        /// New name; must not exist yet
+        to: String,
+    },
+
+    /// Copy a stored file under a new name
+    #[command(visible_alias = "cp")]
+    Copy {
+        /// Encrypted filename in storage
+        from: String,
+
+        /// Name of the copy; must not exist yet
+        to: String,
+    },
+
+    /// Show, set or remove the tags of a stored file
+    Tag {
+        /// Encrypted filename in storage
+        name: String,
+
+        /// Tags to set, as KEY=VALUE
+        set: Vec<String>,
+
+        /// Tags to remove, by key
+        #[arg(short, long)]
+        remove: Vec<String>,
+    },
+
+    /// List the stored versions of a file, newest first
+    History {
+        /// Encrypted filename in storage
+        name: String,
+    },
+
+    /// Make an earlier version of a file current again
+    Restore {
+        /// Encrypted filename in storage
+        name: String,
+
+        /// Version to restore, as listed by `history`
+        version: FileVersion,
+    },
+
+    /// List, restore or permanently delete files in the trash
+    Trash {
+        #[command(subcommand)]
+        action: TrashCommands,
+    },
+
     /// Show storage status and statistics
     Status,
+
+    /// Seal plaintext metadata sidecars left by earlier versions
+    MigrateMetadata,
+
+    /// Re-encrypt every file under a new master key (resumes if interrupted)
+    RotateKey {
+        /// Number of files to re-encrypt concurrently
+        #[arg(short, long, default_value_t = 4)]
+        jobs: usize,
+
+        /// Skip confirmation prompt
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+
+    /// Manage keys and recipient identities
+    Key {
+        #[command(subcommand)]
+        action: KeyCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TrashCommands {
+    /// List deleted files, purging those past retention
+    List,
+
+    /// Move a deleted file or directory back to where it was
+    Restore {
+        /// Trash entry ID, as listed by `trash list`
+        id: String,
+    },
+
+    /// Permanently delete everything in the trash
+    Empty {
+        /// Skip confirmation prompt
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommands {
+    /// Generate an X25519 identity and print its recipient public key
+    GenerateIdentity {
+        /// Identity file to create
+        output: PathBuf,
+    },
+
+    /// Print the recipient public key for an identity file
+    Recipient {
+        /// Identity file to read
+        identity: PathBuf,
+    },
+
+    /// Copy the master key into a Linux kernel keyring
+    LoadKeyring {
+        /// Key description to store under
+        #[arg(short, long, default_value = keyring::DEFAULT_DESCRIPTION)]
+        description: String,
+
+        /// Target keyring: user, session or persistent
+        #[arg(short, long, default_value = "user")]
+        keyring: keyring::KeyringKind,
+
+        /// Expire the key after this many seconds
+        #[arg(short, long)]
+        timeout: Option<u32>,
+
+        /// Key file to load (defaults to the configured key source)
+        #[arg(short, long)]
+        from: Option<PathBuf>,
+    },
+
+    /// Split the master key into Shamir shares for backup
+    Split {
+        /// Total number of shares to create
+        #[arg(short = 'n', long)]
+        shares: u8,
+
+        /// Number of shares required to recover the key
+        #[arg(short, long)]
+        threshold: u8,
+
+        /// Write each share to its own file in this directory instead of stdout
+        #[arg(short, long)]
+        out_dir: Option<PathBuf>,
+    },
+
+    /// Recover a key file from Shamir shares
+    Combine {
+        /// Files containing one share each (reads shares from stdin if omitted)
+        shares: Vec<PathBuf>,
+
+        /// Key file to write (defaults to the configured key_path)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print the master key as a mnemonic or paper key for offline backup
+    #[command(group(clap::ArgGroup::new("format").required(true)))]
+    Export {
+        /// 24-word BIP39 mnemonic
+        #[arg(long, group = "format")]
+        mnemonic: bool,
+
+        /// Checksummed base32 paper key
+        #[arg(long, group = "format")]
+        paper: bool,
+    },
+
+    /// Remove the master key from a key helper (`key_command`)
+    Erase {
+        /// Skip confirmation prompt
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
+
+    /// Rebuild the key file from a mnemonic or paper key
+    Import {
+        /// File containing the backup (reads stdin if omitted)
+        input: Option<PathBuf>,
+
+        /// Key file to write (defaults to the configured key_path)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Write the key even if it does not decrypt the files in storage
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 #[tokio::main]
@@ -104,26 +345,95 @@ async fn main() -> Result<()> {
         Commands::Init {
             storage_dir,
             key_path,
-        } => cmd_init(&cli.config, &storage_dir, &key_path).await,
+            compress,
+            encrypt_names,
+            key_command,
+        } => {
+            let options = VaultOptions {
+                compression: compress,
+                encrypted_names: encrypt_names,
+            };
+            cmd_init(&cli.config, &storage_dir, &key_path, options, key_command).await
+        }
 
         Commands::Encrypt {
             input,
             output,
             compress,
             stream,
-        } => cmd_encrypt(&cli.config, &input, output.as_deref(), compress, stream).await,
+            recipients,
+            no_clobber,
+            if_version,
+        } => {
+            let mode = match (no_clobber, if_version) {
+                (true, _) => WriteMode::CreateNew,
+                (false, Some(version)) => WriteMode::IfMatch(version),
+                (false, None) => WriteMode::Overwrite,
+            };
+            cmd_encrypt(&cli.config, &input, output.as_deref(), compress, stream, &recipients, mode).await
+        }
 
         Commands::Decrypt {
             name,
             output,
             stream,
-        } => cmd_decrypt(&cli.config, &name, output.as_ref(), stream).await,
+            identity,
+        } => cmd_decrypt(&cli.config, &name, output.as_ref(), stream, identity.as_ref()).await,
+
+        Commands::List { dir, verbose, tree } => cmd_list(&cli.config, dir.as_deref(), verbose, tree).await,
+
+        Commands::Remove {
+            name,
+            recursive,
+            shred,
+            yes,
+        } => cmd_remove(&cli.config, &name, recursive, shred, yes).await,
+
+        Commands::Rename { from, to } => cmd_rename(&cli.config, &from, &to).await,
 
-        Commands::List { verbose } => cmd_list(&cli.config, verbose).await,
+        Commands::Copy { from, to } => cmd_copy(&cli.config, &from, &to).await,
 
-        Commands::Remove { name, yes } => cmd_remove(&cli.config, &name, yes).await,
+        Commands::Tag { name, set, remove } => cmd_tag(&cli.config, &name, &set, &remove).await,
+
+        Commands::History { name } => cmd_history(&cli.config, &name).await,
+
+        Commands::Restore { name, version } => cmd_restore(&cli.config, &name, version).await,
+
+        Commands::Trash { action } => match action {
+            TrashCommands::List => cmd_trash_list(&cli.config).await,
+            TrashCommands::Restore { id } => cmd_trash_restore(&cli.config, &id).await,
+            TrashCommands::Empty { yes } => cmd_trash_empty(&cli.config, yes).await,
+        },
 
         Commands::Status => cmd_status(&cli.config).await,
+
+        Commands::MigrateMetadata => cmd_migrate_metadata(&cli.config).await,
+
+        Commands::RotateKey { jobs, yes } => cmd_rotate_key(&cli.config, jobs, yes).await,
+
+        Commands::Key { action } => match action {
+            KeyCommands::GenerateIdentity { output } => cmd_key_generate_identity(&output).await,
+            KeyCommands::Recipient { identity } => cmd_key_recipient(&identity).await,
+            KeyCommands::LoadKeyring {
+                description,
+                keyring,
+                timeout,
+                from,
+            } => cmd_key_load_keyring(&cli.config, &description, keyring, timeout, from).await,
+            KeyCommands::Split {
+                shares,
+                threshold,
+                out_dir,
+            } => cmd_key_split(&cli.config, shares, threshold, out_dir.as_ref()).await,
+            KeyCommands::Combine { shares, output } => {
+                cmd_key_combine(&cli.config, &shares, output).await
+            }
+            KeyCommands::Export { mnemonic, paper: _ } => cmd_key_export(&cli.config, mnemonic).await,
+            KeyCommands::Erase { yes } => cmd_key_erase(&cli.config, yes).await,
+            KeyCommands::Import { input, output, force } => {
+                cmd_key_import(&cli.config, input.as_ref(), output, force).await
+            }
+        },
     }
 }
 
@@ -154,14 +464,20 @@ fn create_spinner(message: &str) -> ProgressBar {
 }
 
 /// Initialize SecureFS configuration and generate encryption key
-async fn cmd_init(config_path: &str, storage_dir: &str, key_path: &str) -> Result<()> {
+async fn cmd_init(
+    config_path: &str,
+    storage_dir: &str,
+    key_path: &str,
+    options: VaultOptions,
+    key_command: Option<String>,
+) -> Result<()> {
     println!("Initializing SecureFS...");
 
     // Create config
-    let cfg = config::Config {
-        key_path: key_path.to_string(),
-        storage_dir: storage_dir.to_string(),
-    };
+    let mut cfg = config::Config::new(key_path, storage_dir);
+    if let Some(command) = &key_command {
+        cfg = cfg.with_key_command(command);
+    }
 
     // Check if config already exists
     if fs::try_exists(config_path).await.unwrap_or(false) {
@@ -172,20 +488,27 @@ async fn cmd_init(config_path: &str, storage_dir: &str, key_path: &str) -> Resul
     }
 
     // Check if key already exists
-    if fs::try_exists(key_path).await.unwrap_or(false) {
+    if key_command.is_none() && fs::try_exists(key_path).await.unwrap_or(false) {
         anyhow::bail!(
             "Key file '{}' already exists. Remove it first or use a different path.",
             key_path
         );
     }
 
-    // Create storage directory
-    fs::create_dir_all(storage_dir)
-        .await
-        .with_context(|| format!("creating storage directory '{}'", storage_dir))?;
-
-    // Generate encryption key (KeyManager will create it)
-    let _km = KeyManager::new(&cfg).await?;
+    // Generate encryption key, then bind the storage directory to it
+    let km = KeyManager::create(&cfg).await?;
+    let key_id = hex::encode(km.key_id());
+    let key_id_bytes = km.key_id();
+    if let Err(e) = SecureFileOps::init_with_options(km, storage_dir, options).await {
+        // The new key never encrypted anything; don't leave it behind
+        if key_command.is_some() {
+            let provider = key_provider::from_config(&cfg);
+            tokio::task::spawn_blocking(move || provider.erase(&key_id_bytes)).await?.ok();
+        } else {
+            fs::remove_file(key_path).await.ok();
+        }
+        return Err(e);
+    }
 
     // Write config file
     let config_json = serde_json::to_string_pretty(&cfg)?;
@@ -195,8 +518,12 @@ async fn cmd_init(config_path: &str, storage_dir: &str, key_path: &str) -> Resul
 
     println!("Initialization complete!");
     println!("Config:  {}", config_path);
-    println!("Key:     {}", key_path);
+    match &key_command {
+        Some(command) => println!("Key:     held by key helper '{}'", command),
+        None => println!("Key:     {}", key_path),
+    }
     println!("Storage: {}", storage_dir);
+    println!("Key ID:  {}", key_id);
     println!();
     println!("IMPORTANT: Keep your key file secure and backed up!");
     println!("Without it, your encrypted files cannot be recovered.");
@@ -211,10 +538,28 @@ async fn cmd_encrypt(
     output: Option<&str>,
     compress: bool,
     stream: bool,
+    recipients: &[String],
+    mode: WriteMode,
 ) -> Result<()> {
     let cfg = config::Config::load(config_path)?;
-    let km = KeyManager::new(&cfg).await?;
-    let ops = SecureFileOps::new(km, cfg.storage_dir).with_compression(compress);
+    let ops = if recipients.is_empty() {
+        let km = open_key(&cfg).await?;
+        open_store(&cfg, km).await?
+    } else {
+        // Write-only: the master key is never loaded
+        let recipients = recipients
+            .iter()
+            .map(|r| r.parse::<Recipient>())
+            .collect::<Result<Vec<_>>>()?;
+        SecureFileOps::keyless(cfg.storage_dir).await?.with_recipients(recipients)
+    };
+    let ops = match cfg.history {
+        Some(policy) => ops.with_history(policy),
+        None => ops,
+    };
+    // --compress forces compression on; otherwise use the vault's default
+    let compress = compress || ops.compression();
+    let ops = ops.with_compression(compress);
 
     // Determine output name
     let output_name = match output {
@@ -237,18 +582,19 @@ async fn cmd_encrypt(
     // Create progress bar
     let pb = create_progress_bar(input_size, &format!("Encrypting{}", compress_str));
 
-    if stream {
+    let version = if stream {
         // Streaming mode for large files
         let mut file = fs::File::open(input)
             .await
             .with_context(|| format!("opening {:?}", input))?;
 
-        let bytes = ops
-            .write_encrypted_stream(&output_name, &mut file)
+        let (bytes, version) = ops
+            .write_encrypted_stream_with(&output_name, &mut file, mode)
             .await?;
 
         pb.set_position(bytes);
         pb.finish_with_message(format!("Encrypted {} bytes ({})", bytes, mode_str));
+        version
     } else {
         // In-memory mode for smaller files
         let data = fs::read(input)
@@ -256,12 +602,21 @@ async fn cmd_encrypt(
             .with_context(|| format!("reading {:?}", input))?;
 
         pb.set_position(data.len() as u64 / 2); // Show reading progress
-        ops.write_encrypted(&output_name, &data).await?;
+        let version = ops.write_encrypted_with(&output_name, &data, mode).await?;
         pb.set_position(input_size);
         pb.finish_with_message(format!("Encrypted {} bytes ({})", data.len(), mode_str));
+        version
+    };
+
+    // Recipient-only stores can't seal metadata
+    if recipients.is_empty() {
+        let source = fs::canonicalize(input).await.unwrap_or_else(|_| input.clone());
+        ops.set_source_path(&output_name, Some(source.to_string_lossy().into_owned()))
+            .await?;
     }
 
     println!("  {} -> {}", input.display(), output_name);
+    println!("  Version: {}", version);
     Ok(())
 }
 
@@ -271,10 +626,19 @@ async fn cmd_decrypt(
     name: &str,
     output: Option<&PathBuf>,
     stream: bool,
+    identity: Option<&PathBuf>,
 ) -> Result<()> {
     let cfg = config::Config::load(config_path)?;
-    let km = KeyManager::new(&cfg).await?;
-    let ops = SecureFileOps::new(km, cfg.storage_dir);
+    let ops = match identity {
+        Some(path) => {
+            let identity = KeyManager::load_identity(path).await?;
+            SecureFileOps::keyless(cfg.storage_dir).await?.with_identity(identity)
+        }
+        None => {
+            let km = open_key(&cfg).await?;
+            open_store(&cfg, km).await?
+        }
+    };
 
     // Use spinner since we don't know the decrypted size ahead of time
     let spinner = create_spinner(&format!("Decrypting {}...", name));
@@ -306,7 +670,7 @@ async fn cmd_decrypt(
         }
     } else {
         // In-memory mode
-        let data = ops.read_encrypted(name).await?;
+        let (data, _) = ops.read_encrypted_auto(name).await?;
 
         match output {
             Some(output_path) => {
@@ -332,12 +696,15 @@ async fn cmd_decrypt(
 }
 
 /// List all encrypted files
-async fn cmd_list(config_path: &str, verbose: bool) -> Result<()> {
+async fn cmd_list(config_path: &str, dir: Option<&str>, verbose: bool, tree: bool) -> Result<()> {
     let cfg = config::Config::load(config_path)?;
-    let km = KeyManager::new(&cfg).await?;
-    let ops = SecureFileOps::new(km, cfg.storage_dir);
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
 
-    let files = ops.list_files().await?;
+    let files = match dir {
+        Some(dir) => ops.list_dir(dir, true).await?,
+        None => ops.list_files().await?,
+    };
 
     if files.is_empty() {
         println!("No encrypted files found");
@@ -347,13 +714,51 @@ async fn cmd_list(config_path: &str, verbose: bool) -> Result<()> {
     println!("Encrypted files ({} total):", files.len());
     println!();
 
-    if verbose {
-        println!("{:<40} {:>12} {:>10}", "FILENAME", "SIZE (bytes)", "METADATA");
-        println!("{}", "─".repeat(64));
-
+    if tree {
+        let strip = dir.map(|d| format!("{}/", d.trim_end_matches('/'))).unwrap_or_default();
+        let mut root = TreeNode::default();
+        for (name, size, _) in &files {
+            root.insert(name.strip_prefix(&strip).unwrap_or(name), *size);
+        }
+        println!("{}", dir.unwrap_or(&cfg.storage_dir));
+        root.print("");
+    } else if verbose {
         for (name, size, has_meta) in files {
-            let meta_status = if has_meta { "yes" } else { "no" };
-            println!("{:<40} {:>12} {:>10}", name, size, meta_status);
+            println!("{}", name);
+            let meta = match ops.get_metadata(&name).await {
+                Ok(meta) => meta,
+                Err(e) => {
+                    let reason = if has_meta { e.to_string() } else { "none".to_string() };
+                    println!("  Stored:   {} bytes", size);
+                    println!("  Metadata: {}", reason);
+                    println!();
+                    continue;
+                }
+            };
+            let ratio = meta
+                .compression_ratio()
+                .map(|r| format!(" (ratio {:.2})", r))
+                .unwrap_or_default();
+            println!("  Size:     {} bytes, {} stored{}", meta.size, size, ratio);
+            if let Some(created) = meta.created {
+                println!("  Created:  {}", format_age(created));
+            }
+            if let Some(modified) = meta.modified {
+                println!("  Modified: {}", format_age(modified));
+            }
+            if let Some(content_type) = &meta.content_type {
+                println!("  Type:     {}", content_type);
+            }
+            if let Some(sha256) = &meta.sha256 {
+                println!("  SHA-256:  {}", sha256);
+            }
+            if let Some(source) = &meta.source_path {
+                println!("  Source:   {}", source);
+            }
+            if !meta.tags.is_empty() {
+                println!("  Tags:     {}", format_tags(&meta.tags));
+            }
+            println!();
         }
     } else {
         for (name, size, _) in files {
@@ -365,19 +770,37 @@ async fn cmd_list(config_path: &str, verbose: bool) -> Result<()> {
 }
 
 /// Remove an encrypted file
-async fn cmd_remove(config_path: &str, name: &str, yes: bool) -> Result<()> {
+async fn cmd_remove(config_path: &str, name: &str, recursive: bool, shred: bool, yes: bool) -> Result<()> {
     let cfg = config::Config::load(config_path)?;
-    let km = KeyManager::new(&cfg).await?;
-    let ops = SecureFileOps::new(km, cfg.storage_dir);
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
+    let ops = match cfg.trash {
+        Some(policy) => ops.with_trash(policy),
+        None => ops,
+    };
 
     // Check if file exists
     if !ops.exists(name).await {
         anyhow::bail!("File '{}' not found in storage", name);
     }
 
+    let prompt = if recursive {
+        let count = ops.list_dir(name, true).await?.len();
+        format!("Delete directory '{}' and its {} file(s)?", name, count)
+    } else {
+        format!("Delete '{}'?", name)
+    };
+
     // Confirm deletion unless --yes flag is set
     if !yes {
-        print!("Delete '{}'? This cannot be undone. [y/N]: ", name);
+        let warning = if shred {
+            "Its key will be destroyed. This cannot be undone."
+        } else if cfg.trash.is_some() {
+            "It will be moved to the trash."
+        } else {
+            "This cannot be undone."
+        };
+        print!("{} {} [y/N]: ", prompt, warning);
         io::stdout().flush()?;
 
         let mut response = String::new();
@@ -389,18 +812,248 @@ async fn cmd_remove(config_path: &str, name: &str, yes: bool) -> Result<()> {
         }
     }
 
-    ops.delete_file(name).await?;
+    if recursive {
+        ops.remove_dir(name, true).await?;
+    } else if shred {
+        ops.delete_file_with(name, DeleteMode::Shred).await?;
+    } else {
+        ops.delete_file(name).await?;
+    }
 
-    println!("Deleted '{}'", name);
+    if shred {
+        println!("Shredded '{}'", name);
+    } else if cfg.trash.is_some() {
+        println!("Moved '{}' to the trash", name);
+    } else {
+        println!("Deleted '{}'", name);
+    }
 
     Ok(())
 }
 
+/// Rename a stored file
+async fn cmd_rename(config_path: &str, from: &str, to: &str) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
+    ops.rename(from, to).await?;
+    println!("Renamed '{}' to '{}'", from, to);
+    Ok(())
+}
+
+/// Show, set or remove the tags of a stored file
+async fn cmd_tag(config_path: &str, name: &str, set: &[String], remove: &[String]) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
+
+    let set = set
+        .iter()
+        .map(|tag| {
+            tag.split_once('=')
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .with_context(|| format!("tag '{}' is not KEY=VALUE", tag))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let meta = if set.is_empty() && remove.is_empty() {
+        ops.get_metadata(name).await?
+    } else {
+        ops.update_tags(name, |tags| {
+            for key in remove {
+                tags.remove(key);
+            }
+            tags.extend(set);
+        })
+        .await?
+    };
+
+    if meta.tags.is_empty() {
+        println!("'{}' has no tags", name);
+    } else {
+        for (key, value) in &meta.tags {
+            println!("{}={}", key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Copy a stored file
+async fn cmd_copy(config_path: &str, from: &str, to: &str) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
+    let spinner = create_spinner(&format!("Copying {}...", from));
+    let result = ops.copy(from, to).await;
+    spinner.finish_and_clear();
+    result?;
+    println!("Copied '{}' to '{}'", from, to);
+    Ok(())
+}
+
+/// Open the store with the configured trash policy
+async fn open_trash(config_path: &str) -> Result<SecureFileOps> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
+    Ok(ops.with_trash(cfg.trash.unwrap_or_default()))
+}
+
+/// List the trash
+async fn cmd_trash_list(config_path: &str) -> Result<()> {
+    let ops = open_trash(config_path).await?;
+    let purged = ops.purge_trash().await?;
+    if purged > 0 {
+        println!("Purged {} expired entr{}", purged, if purged == 1 { "y" } else { "ies" });
+    }
+
+    let entries = ops.list_trash().await?;
+    if entries.is_empty() {
+        println!("Trash is empty");
+        return Ok(());
+    }
+
+    println!("{:<22} {:<40} {:>10} {:>12}", "ID", "NAME", "DELETED", "SIZE (bytes)");
+    println!("{}", "─".repeat(87));
+    for entry in entries {
+        let name = if entry.is_dir { format!("{}/", entry.name) } else { entry.name };
+        println!("{:<22} {:<40} {:>10} {:>12}", entry.id, name, format_age(entry.deleted_at), entry.size);
+    }
+    Ok(())
+}
+
+/// Restore an entry from the trash
+async fn cmd_trash_restore(config_path: &str, id: &str) -> Result<()> {
+    let ops = open_trash(config_path).await?;
+    let name = ops.restore_trash(id).await?;
+    println!("Restored '{}'", name);
+    Ok(())
+}
+
+/// Empty the trash
+async fn cmd_trash_empty(config_path: &str, yes: bool) -> Result<()> {
+    let ops = open_trash(config_path).await?;
+    let count = ops.list_trash().await?.len();
+    if count == 0 {
+        println!("Trash is empty");
+        return Ok(());
+    }
+
+    if !yes {
+        print!("Permanently delete {} item(s) in the trash? This cannot be undone. [y/N]: ", count);
+        io::stdout().flush()?;
+
+        let mut response = String::new();
+        io::stdin().read_line(&mut response)?;
+
+        if !response.trim().eq_ignore_ascii_case("y") {
+            println!("Cancelled.");
+            return Ok(());
+        }
+    }
+
+    let removed = ops.empty_trash().await?;
+    println!("Permanently deleted {} item(s)", removed);
+    Ok(())
+}
+
+/// List the versions of a file
+async fn cmd_history(config_path: &str, name: &str) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
+
+    let versions = ops.list_versions(name).await?;
+    if versions.is_empty() {
+        anyhow::bail!("File '{}' not found in storage", name);
+    }
+    if cfg.history.is_none() {
+        println!("Note: version history is disabled; set \"history\" in the config to keep replaced versions");
+        println!();
+    }
+
+    println!("{:<34} {:>16} {:>12}", "VERSION", "REPLACED", "SIZE (bytes)");
+    println!("{}", "─".repeat(64));
+    for info in versions {
+        let replaced = match info.archived_at {
+            Some(at) => format_age(at),
+            None => "current".to_string(),
+        };
+        println!("{:<34} {:>16} {:>12}", info.version, replaced, info.size);
+    }
+    Ok(())
+}
+
+/// Restore an earlier version of a file
+async fn cmd_restore(config_path: &str, name: &str, version: FileVersion) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
+    // Restoring without history would lose the current version
+    let ops = ops.with_history(cfg.history.unwrap_or_default());
+
+    let restored = ops.restore(name, version).await?;
+    println!("Restored '{}' to version {}", name, version);
+    println!("  Version: {}", restored);
+    Ok(())
+}
+
+/// How long ago `at` was, e.g. "3h ago"
+fn format_age(at: std::time::SystemTime) -> String {
+    let secs = at.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+    match secs {
+        0..60 => format!("{}s ago", secs),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// Tags as `key=value` pairs, for `list -v`
+fn format_tags(tags: &std::collections::BTreeMap<String, String>) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Directory tree of stored files, for `list --tree`
+#[derive(Default)]
+struct TreeNode {
+    dirs: std::collections::BTreeMap<String, TreeNode>,
+    files: Vec<(String, u64)>,
+}
+
+impl TreeNode {
+    fn insert(&mut self, name: &str, size: u64) {
+        match name.split_once('/') {
+            Some((dir, rest)) => self.dirs.entry(dir.to_string()).or_default().insert(rest, size),
+            None => self.files.push((name.to_string(), size)),
+        }
+    }
+
+    fn print(&self, indent: &str) {
+        let count = self.dirs.len() + self.files.len();
+        let entries = self
+            .dirs
+            .iter()
+            .map(|(name, node)| (format!("{}/", name), Some(node)))
+            .chain(self.files.iter().map(|(name, size)| (format!("{} ({} bytes)", name, size), None)));
+        for (i, (label, node)) in entries.enumerate() {
+            let last = i + 1 == count;
+            println!("{}{}{}", indent, if last { "└── " } else { "├── " }, label);
+            if let Some(node) = node {
+                node.print(&format!("{}{}", indent, if last { "    " } else { "│   " }));
+            }
+        }
+    }
+}
+
 /// Show storage status and statistics
 async fn cmd_status(config_path: &str) -> Result<()> {
     let cfg = config::Config::load(config_path)?;
-    let km = KeyManager::new(&cfg).await?;
-    let ops = SecureFileOps::new(km, cfg.storage_dir.clone());
+    let km = open_key(&cfg).await?;
+    let key_id = hex::encode(km.key_id());
+    let ops = open_store(&cfg, km).await?;
 
     println!("SecureFS Status");
     println!();
@@ -408,13 +1061,19 @@ async fn cmd_status(config_path: &str) -> Result<()> {
     // Config info
     println!("Configuration:");
     println!("  Config file:   {}", config_path);
-    println!("  Key file:      {}", cfg.key_path);
+    println!("  Key source:    {}", key_provider::from_config(&cfg).describe());
     println!("  Storage dir:   {}", cfg.storage_dir);
     println!();
 
-    // Check if key exists
-    let key_exists = fs::try_exists(&cfg.key_path).await.unwrap_or(false);
-    println!("Key Status:      {}", if key_exists { "Present" } else { "Missing" });
+    println!("Key ID:          {}", key_id);
+    match ops.vault() {
+        Some(vault) => {
+            println!("Vault:           format v{}, key verified", vault.settings.format_version);
+            println!("  Compression:   {}", if vault.settings.compression { "on" } else { "off" });
+            println!("  Names:         {}", if vault.settings.encrypted_names { "encrypted" } else { "plaintext" });
+        }
+        None => println!("Vault:           no descriptor (created before vault descriptors)"),
+    }
     println!();
 
     // File statistics
@@ -436,5 +1095,441 @@ async fn cmd_status(config_path: &str) -> Result<()> {
         println!("WARNING: {} file(s) missing metadata", orphaned);
     }
 
+    let legacy = ops.legacy_sidecars().await?;
+    if !legacy.is_empty() {
+        println!();
+        println!("WARNING: {} plaintext metadata sidecar(s) from an earlier version:", legacy.len());
+        for path in &legacy {
+            println!("  {}", path.display());
+        }
+        println!("Run 'securefs migrate-metadata' to seal them");
+    }
+
+    Ok(())
+}
+
+/// Seal legacy plaintext sidecars under .securefs-meta and delete them
+async fn cmd_migrate_metadata(config_path: &str) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let ops = open_store(&cfg, km).await?;
+
+    let (sealed, removed) = ops.migrate_legacy_metadata().await?;
+    println!("Sealed {} legacy metadata sidecar(s)", sealed);
+    if removed > 0 {
+        println!("Removed {} sidecar(s) of missing or already sealed files", removed);
+    }
+    Ok(())
+}
+
+/// Re-encrypt the store under a new key, then swap the key files
+async fn cmd_rotate_key(config_path: &str, jobs: usize, yes: bool) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    if cfg.key_source.is_some() || cfg.key_command.is_some() {
+        anyhow::bail!(
+            "rotate-key only manages key files (key_path); for other key sources use \
+             SecureFileOps::reencrypt_all and store the new key yourself"
+        );
+    }
+
+    // The new key is written to disk before any file is touched, so an
+    // interrupted rotation can always be resumed
+    let new_path = rotation_key_path(&cfg);
+    let new_provider = FileKeyProvider::new(&new_path).with_permissions(cfg.key_permissions);
+    let new_key = match tokio::task::spawn_blocking(move || new_provider.load()).await?? {
+        Some(key_file) => {
+            println!("Resuming rotation to key {} ({})", hex::encode(key_file.key_id), new_path.display());
+            key_file
+        }
+        None => {
+            let old_km = open_key(&cfg).await?;
+            if !yes {
+                print!(
+                    "Re-encrypt every file in {} and replace key {}? [y/N]: ",
+                    cfg.storage_dir,
+                    hex::encode(old_km.key_id())
+                );
+                io::stdout().flush()?;
+                let mut response = String::new();
+                io::stdin().read_line(&mut response)?;
+                if !response.trim().eq_ignore_ascii_case("y") {
+                    println!("Cancelled.");
+                    return Ok(());
+                }
+            }
+            let provider = std::sync::Arc::new(FileKeyProvider::new(&new_path));
+            let km = KeyManager::create_with_provider(provider.clone()).await?;
+            println!("Generated new key {} ({})", hex::encode(km.key_id()), new_path.display());
+            tokio::task::spawn_blocking(move || provider.load())
+                .await??
+                .context("new key vanished after it was written")?
+        }
+    };
+    let new_km = KeyManager::from_key_file(&new_key);
+
+    let old_km = open_key(&cfg).await?;
+    let old_key_id = hex::encode(old_km.key_id());
+    // Named after the key, so older retired keys that backups may still need are kept
+    let old_path = PathBuf::from(format!("{}.{}.old", cfg.key_path, old_key_id));
+    match open_store(&cfg, old_km).await {
+        Ok(ops) => {
+            let spinner = create_spinner(&format!("Re-encrypting {} with {} jobs...", cfg.storage_dir, jobs));
+            let report = match ops.reencrypt_all(&new_km, jobs).await {
+                Ok(report) => report,
+                Err(e) => {
+                    spinner.finish_and_clear();
+                    return Err(e.context(format!(
+                        "rotation incomplete; key {} still opens the store, rerun `securefs rotate-key` to resume",
+                        old_key_id
+                    )));
+                }
+            };
+            spinner.finish_with_message(format!(
+                "Re-encrypted {} file(s), {} already done",
+                report.rotated, report.resumed
+            ));
+            if report.own_key > 0 {
+                println!("Rewrapped the keys of {} file(s) with keys of their own", report.own_key);
+            }
+            if !report.skipped.is_empty() {
+                println!("Skipped {} recipient file(s), which don't use the master key", report.skipped.len());
+            }
+        }
+        // A previous run finished the files but stopped before swapping keys
+        Err(e) if matches!(e.downcast_ref(), Some(SecureFsError::KeyMismatch { .. })) => {
+            SecureFileOps::new(KeyManager::from_key_file(&new_key), cfg.storage_dir.clone())
+                .await
+                .map_err(|_| e)?;
+            println!("Store already re-encrypted; finishing key swap");
+        }
+        Err(e) => return Err(e),
+    }
+
+    // Keep the old key until the new one is in place
+    let old_key = zeroize::Zeroizing::new(
+        fs::read(&cfg.key_path)
+            .await
+            .with_context(|| format!("reading {}", cfg.key_path))?,
+    );
+    match fs::read(&old_path).await.map(zeroize::Zeroizing::new) {
+        // Saved by a run interrupted before the swap
+        Ok(saved) if saved == old_key => {}
+        Ok(_) => anyhow::bail!("{:?} already exists and holds another key; move it away first", old_path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => write_secret_file(&old_path, &old_key)
+            .with_context(|| format!("creating {:?}", old_path))?,
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("reading {:?}", old_path))),
+    }
+    // Durable before and after the swap, like `AtomicFile`: a crash must not
+    // bring back the old key once the files are under the new one
+    fs::File::open(&new_path)
+        .await
+        .with_context(|| format!("opening {:?}", new_path))?
+        .sync_all()
+        .await
+        .with_context(|| format!("syncing {:?}", new_path))?;
+    fs::rename(&new_path, &cfg.key_path)
+        .await
+        .with_context(|| format!("moving {:?} to {}", new_path, cfg.key_path))?;
+    util::sync_parent(std::path::Path::new(&cfg.key_path)).await?;
+
+    println!("Rotation complete!");
+    println!("Key:     {} (key ID {})", cfg.key_path, hex::encode(new_km.key_id()));
+    println!("Old key: {} (key ID {})", old_path.display(), old_key_id);
+    println!();
+    println!("Back up the new key now. Destroy the old key once no backups need it.");
+    Ok(())
+}
+
+/// Generate a recipient identity file
+async fn cmd_key_generate_identity(output: &PathBuf) -> Result<()> {
+    if fs::try_exists(output).await.unwrap_or(false) {
+        anyhow::bail!(
+            "Identity file '{}' already exists. Remove it first or use a different path.",
+            output.display()
+        );
+    }
+
+    let identity = KeyManager::generate_identity(output).await?;
+
+    println!("Identity: {}", output.display());
+    println!("Recipient: {}", identity.recipient());
+    println!();
+    println!("Share the recipient with producers; keep the identity file private.");
+
+    Ok(())
+}
+
+/// Print the recipient for an identity file
+async fn cmd_key_recipient(identity: &PathBuf) -> Result<()> {
+    let identity = KeyManager::load_identity(identity).await?;
+    println!("{}", identity.recipient());
+    Ok(())
+}
+
+/// Load the master key into a kernel keyring
+async fn cmd_key_load_keyring(
+    config_path: &str,
+    description: &str,
+    kind: keyring::KeyringKind,
+    timeout: Option<u32>,
+    from: Option<PathBuf>,
+) -> Result<()> {
+    let source: std::sync::Arc<dyn KeyProvider> = match from {
+        Some(path) => std::sync::Arc::new(FileKeyProvider::new(path)),
+        None => key_provider::from_config(&config::Config::load(config_path)?),
+    };
+    let target = KeyringKeyProvider::new(description)
+        .with_keyring(kind)
+        .with_timeout(timeout);
+
+    let source_name = source.describe();
+    let target_name = target.describe();
+    tokio::task::spawn_blocking(move || {
+        let key_file = source
+            .load()?
+            .with_context(|| format!("no key found in {}", source.describe()))?;
+        target.store(&key_file)
+    })
+    .await??;
+
+    println!("Loaded key from {} into {}", source_name, target_name);
+    if let Some(secs) = timeout {
+        println!("The key expires in {} seconds.", secs);
+    }
+    println!();
+    println!("To use it, set in your config:");
+    println!(
+        "  \"key_source\": {{ \"type\": \"keyring\", \"description\": \"{}\", \"keyring\": \"{}\" }}",
+        description, kind
+    );
+
+    Ok(())
+}
+
+/// Split the master key into Shamir shares
+async fn cmd_key_split(
+    config_path: &str,
+    shares: u8,
+    threshold: u8,
+    out_dir: Option<&PathBuf>,
+) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let key_shares = km.split(shares, threshold)?;
+
+    println!(
+        "Split key {} into {} shares; any {} recover it.",
+        hex::encode(km.key_id()),
+        shares,
+        threshold
+    );
+    println!();
+
+    match out_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("creating share directory {:?}", dir))?;
+            for share in &key_shares {
+                let path = dir.join(format!("share-{}-of-{}.txt", share.index, shares));
+                write_secret_file(&path, format!("{}\n", share.encode().as_str()).as_bytes())
+                    .with_context(|| format!("creating {:?}", path))?;
+                println!("  share {} -> {}", share.index, path.display());
+            }
+        }
+        None => {
+            for share in &key_shares {
+                println!("{}", share.encode().as_str());
+            }
+        }
+    }
+
+    println!();
+    println!("Give each share to a different custodian. Shares are secret.");
     Ok(())
 }
+
+/// Recover a key file from Shamir shares
+async fn cmd_key_combine(config_path: &str, share_files: &[PathBuf], output: Option<PathBuf>) -> Result<()> {
+    let mut lines = Vec::new();
+    if share_files.is_empty() {
+        eprintln!("Paste shares, one per line, then press Ctrl-D:");
+        for line in io::stdin().lines() {
+            lines.push(line?);
+        }
+    } else {
+        for path in share_files {
+            let text = fs::read_to_string(path)
+                .await
+                .with_context(|| format!("reading share {:?}", path))?;
+            lines.extend(text.lines().map(String::from));
+        }
+    }
+
+    let shares = lines
+        .iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| KeyShare::parse(l).map_err(anyhow::Error::from))
+        .collect::<Result<Vec<_>>>()?;
+
+    let key_file = KeyManager::combine(&shares)?;
+    let output = match output {
+        Some(path) => path,
+        None => PathBuf::from(config::Config::load(config_path)?.key_path),
+    };
+    FileKeyProvider::new(&output)
+        .store(&key_file)
+        .with_context(|| format!("writing recovered key to {:?} (it must not already exist)", output))?;
+
+    println!("Recovered key {} -> {}", hex::encode(key_file.key_id), output.display());
+    Ok(())
+}
+
+/// Print the master key in a hand-copyable form
+async fn cmd_key_export(config_path: &str, mnemonic: bool) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    let km = open_key(&cfg).await?;
+    let backup = if mnemonic {
+        km.export_mnemonic()
+    } else {
+        km.export_paper_key()
+    };
+
+    // Only the backup goes to stdout so it can be redirected to a file
+    println!("{}", backup.as_str());
+    eprintln!();
+    eprintln!("Key ID: {}", hex::encode(km.key_id()));
+    eprintln!("Write this down and store it offline. Anyone holding it can decrypt your files.");
+    Ok(())
+}
+
+/// Erase the master key from its source
+async fn cmd_key_erase(config_path: &str, yes: bool) -> Result<()> {
+    let cfg = config::Config::load(config_path)?;
+    if cfg.key_command.is_none() {
+        anyhow::bail!("key erase only applies to a key_command helper; delete key files directly");
+    }
+    let provider = key_provider::from_config(&cfg);
+    let source = provider.describe();
+
+    let key_id = {
+        let provider = provider.clone();
+        tokio::task::spawn_blocking(move || provider.load())
+            .await??
+            .map(|k| k.key_id)
+            .with_context(|| format!("no key found in {}", source))?
+    };
+
+    if !yes {
+        print!(
+            "Erase key {} from {}? Files encrypted with it become unreadable without a backup. [y/N]: ",
+            hex::encode(key_id),
+            source
+        );
+        io::stdout().flush()?;
+        let mut response = String::new();
+        io::stdin().read_line(&mut response)?;
+        if !response.trim().eq_ignore_ascii_case("y") {
+            println!("Cancelled.");
+            return Ok(());
+        }
+    }
+
+    tokio::task::spawn_blocking(move || provider.erase(&key_id)).await??;
+    println!("Erased key {} from {}", hex::encode(key_id), source);
+    Ok(())
+}
+
+/// Rebuild the key file from a mnemonic or paper key
+async fn cmd_key_import(
+    config_path: &str,
+    input: Option<&PathBuf>,
+    output: Option<PathBuf>,
+    force: bool,
+) -> Result<()> {
+    let text = match input {
+        Some(path) => fs::read_to_string(path)
+            .await
+            .with_context(|| format!("reading backup {:?}", path))?,
+        None => {
+            eprintln!("Enter the mnemonic or paper key, then press Ctrl-D:");
+            io::read_to_string(io::stdin())?
+        }
+    };
+    let text = zeroize::Zeroizing::new(text);
+    let backup: String = text
+        .lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let backup = zeroize::Zeroizing::new(backup);
+
+    let key_file = KeyManager::import_backup(&backup)?;
+    let key_id = hex::encode(key_file.key_id);
+    println!("Key ID: {}", key_id);
+
+    let cfg = config::Config::load(config_path)?;
+    let check = match SecureFileOps::new(KeyManager::from_key_file(&key_file), cfg.storage_dir.clone()).await {
+        Ok(ops) => ops.verify_key().await?,
+        Err(e) if matches!(e.downcast_ref(), Some(SecureFsError::KeyMismatch { .. })) => Some(false),
+        Err(e) => return Err(e),
+    };
+    match check {
+        Some(true) => println!("Verified: the key decrypts files in {}", cfg.storage_dir),
+        Some(false) if force => {
+            println!("WARNING: the key does not decrypt files in {}; writing it anyway", cfg.storage_dir)
+        }
+        Some(false) => anyhow::bail!(
+            "key {} does not decrypt files in {}; check the backup or pass --force",
+            key_id,
+            cfg.storage_dir
+        ),
+        None => println!("Note: {} has no files to verify the key against", cfg.storage_dir),
+    }
+
+    let output = output.unwrap_or_else(|| PathBuf::from(&cfg.key_path));
+    FileKeyProvider::new(&output)
+        .store(&key_file)
+        .with_context(|| format!("writing restored key to {:?} (it must not already exist)", output))?;
+
+    println!("Restored key {} -> {}", key_id, output.display());
+    Ok(())
+}
+
+/// Open the store with the configured key table, if any. While a
+/// `rotate-key` run is unfinished, the new key it wrote is used for the
+/// files it already re-encrypted.
+async fn open_store(cfg: &config::Config, km: KeyManager) -> Result<SecureFileOps> {
+    let table = cfg.key_table.as_ref().map(|path| KeyTable::new(&km, path));
+    let ops = SecureFileOps::new(km, cfg.storage_dir.clone()).await?;
+    let ops = match table {
+        Some(table) => ops.with_key_table(table),
+        None => ops,
+    };
+    let managed = cfg.key_source.is_none() && cfg.key_command.is_none();
+    if !managed || !securefs::rotation::in_progress(std::path::Path::new(&cfg.storage_dir)).await {
+        return Ok(ops);
+    }
+    let provider = FileKeyProvider::new(rotation_key_path(cfg)).with_permissions(cfg.key_permissions);
+    Ok(match tokio::task::spawn_blocking(move || provider.load()).await?? {
+        Some(new_key) => ops.with_rotation_key(KeyManager::from_key_file(&new_key)),
+        None => ops,
+    })
+}
+
+/// Where `rotate-key` keeps the new key until the rotation is finished
+fn rotation_key_path(cfg: &config::Config) -> PathBuf {
+    PathBuf::from(format!("{}.new", cfg.key_path))
+}
+
+/// Open the configured master key, pointing at `init` if it does not exist
+async fn open_key(cfg: &config::Config) -> Result<KeyManager> {
+    KeyManager::open(cfg).await.map_err(|e| {
+        if matches!(e.downcast_ref(), Some(SecureFsError::KeyNotFound(_))) {
+            e.context("check key_path in your config, or run `securefs init` to create a new key")
+        } else {
+            e
+        }
+    })
+}
@@ -9,6 +9,9 @@
 //! - `SECUREFS_STORAGE_DIR`: Override storage directory path
 //! - `SECUREFS_CONFIG`: Override config file path
 
+use crate::history::HistoryPolicy;
+use crate::keyring::KeyringKind;
+use crate::trash::TrashPolicy;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::env;
@@ -25,6 +28,76 @@ pub const ENV_CONFIG_PATH: &str = "SECUREFS_CONFIG";
 pub struct Config {
     pub key_path: String,
     pub storage_dir: String,
+    /// Where the master key comes from; defaults to the file at `key_path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_source: Option<KeySource>,
+    /// External key helper, run as `<key_command> get|store|erase` (see [`crate::key_helper`]).
+    /// Takes the place of `key_source`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_command: Option<String>,
+    /// How to treat key files with unsafe permissions or ownership
+    #[serde(default, skip_serializing_if = "KeyPermissions::is_strict")]
+    pub key_permissions: KeyPermissions,
+    /// Keep replaced versions of files (see [`crate::history`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<HistoryPolicy>,
+    /// Move deleted files to the trash (see [`crate::trash`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash: Option<TrashPolicy>,
+    /// Key table for per-file keys, enabling crypto-shredding deletes (see
+    /// [`crate::file_keys`]). Keep it outside `storage_dir`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_table: Option<String>,
+}
+
+/// Policy for key files that other users could read or replace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyPermissions {
+    /// Refuse to load the key (default)
+    #[default]
+    Strict,
+    /// Log a warning and load the key anyway
+    Warn,
+}
+
+impl KeyPermissions {
+    fn is_strict(&self) -> bool {
+        *self == Self::Strict
+    }
+}
+
+/// Master key source selection (see [`crate::key_provider`])
+///
+/// ```json
+/// { "key_source": { "type": "env", "var": "SECUREFS_KEY" } }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeySource {
+    /// Key file at `path`
+    File { path: String },
+    /// Hex-encoded key in environment variable `var`
+    Env { var: String },
+    /// Key piped on standard input
+    Stdin,
+    /// Key read from inherited file descriptor `fd`
+    Fd { fd: i32 },
+    /// Key held in a Linux kernel keyring under `description`
+    Keyring {
+        description: String,
+        #[serde(default)]
+        keyring: KeyringKind,
+        /// Expire newly stored keys after this many seconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_secs: Option<u32>,
+    },
+    /// Key printed on stdout by `command`
+    Command {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
 }
 
 impl Default for Config {
@@ -32,6 +105,12 @@ impl Default for Config {
         Self {
             key_path: "./securefs.key".to_string(),
             storage_dir: "./storage".to_string(),
+            key_source: None,
+            key_command: None,
+            key_permissions: KeyPermissions::Strict,
+            history: None,
+            trash: None,
+            key_table: None,
         }
     }
 }
@@ -88,9 +167,32 @@ impl Config {
 
     /// Validate configuration values
     pub fn validate(&self) -> Result<()> {
-        // Validate key_path is not empty
-        if self.key_path.trim().is_empty() {
-            anyhow::bail!("key_path cannot be empty");
+        // Validate the selected key source
+        if let Some(command) = &self.key_command {
+            if command.trim().is_empty() {
+                anyhow::bail!("key_command cannot be empty");
+            }
+            if self.key_source.is_some() {
+                anyhow::bail!("set either key_command or key_source, not both");
+            }
+        }
+        match &self.key_source {
+            None if self.key_command.is_none() && self.key_path.trim().is_empty() => {
+                anyhow::bail!("key_path cannot be empty")
+            }
+            Some(KeySource::File { path }) if path.trim().is_empty() => {
+                anyhow::bail!("key_source.path cannot be empty")
+            }
+            Some(KeySource::Env { var }) if var.trim().is_empty() => {
+                anyhow::bail!("key_source.var cannot be empty")
+            }
+            Some(KeySource::Keyring { description, .. }) if description.trim().is_empty() => {
+                anyhow::bail!("key_source.description cannot be empty")
+            }
+            Some(KeySource::Command { command, .. }) if command.trim().is_empty() => {
+                anyhow::bail!("key_source.command cannot be empty")
+            }
+            _ => {}
         }
 
         // Validate storage_dir is not empty
@@ -126,6 +228,48 @@ impl Config {
         Self {
             key_path: key_path.into(),
             storage_dir: storage_dir.into(),
+            key_source: None,
+            key_command: None,
+            key_permissions: KeyPermissions::Strict,
+            history: None,
+            trash: None,
+            key_table: None,
         }
     }
+
+    /// Set the policy for insecure key files
+    pub fn with_key_permissions(mut self, policy: KeyPermissions) -> Self {
+        self.key_permissions = policy;
+        self
+    }
+
+    /// Keep replaced versions of files, pruned by `policy`
+    pub fn with_history(mut self, policy: HistoryPolicy) -> Self {
+        self.history = Some(policy);
+        self
+    }
+
+    /// Move deleted files to the trash, purged by `policy`
+    pub fn with_trash(mut self, policy: TrashPolicy) -> Self {
+        self.trash = Some(policy);
+        self
+    }
+
+    /// Give new files keys of their own, kept in the table at `path`
+    pub fn with_key_table(mut self, path: impl Into<String>) -> Self {
+        self.key_table = Some(path.into());
+        self
+    }
+
+    /// Use an external key helper command
+    pub fn with_key_command(mut self, command: impl Into<String>) -> Self {
+        self.key_command = Some(command.into());
+        self
+    }
+
+    /// Select a non-default key source
+    pub fn with_key_source(mut self, source: KeySource) -> Self {
+        self.key_source = Some(source);
+        self
+    }
 }
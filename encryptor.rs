@@ -14,6 +14,7 @@ use anyhow::{bail, Result};
 use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
 use chacha20poly1305::XChaCha20Poly1305;
 use chacha20poly1305::XNonce;
+use crate::secret::SharedCipher;
 use flate2::write::{GzDecoder, GzEncoder};
 use flate2::Compression;
 use std::io::Write;
@@ -23,12 +24,23 @@ use std::io::Write;
 /// - Supports additional authenticated data (AAD) so you can bind metadata
 #[derive(Clone)]
 pub struct Encryptor {
-    cipher: XChaCha20Poly1305,
+    cipher: SharedCipher,
+    legacy_cipher: Option<SharedCipher>,
 }
 
 impl Encryptor {
-    pub fn new(cipher: XChaCha20Poly1305) -> Self {
-        Self { cipher }
+    pub fn new(cipher: SharedCipher) -> Self {
+        Self {
+            cipher,
+            legacy_cipher: None,
+        }
+    }
+
+    /// Also try `legacy` when decrypting, for buffers written before `cipher`
+    /// became a derived subkey. The buffer format has no header to tell them apart.
+    pub fn with_legacy_cipher(mut self, legacy: SharedCipher) -> Self {
+        self.legacy_cipher = Some(legacy);
+        self
     }
 
     /// Encrypts `plaintext`, prepending the 24-byte nonce to the ciphertext.
@@ -65,15 +77,14 @@ impl Encryptor {
         let (nonce_bytes, data) = ciphertext.split_at(24);
         #[allow(deprecated)]
         let nonce = XNonce::from_slice(nonce_bytes);
-        let pt = match aad {
-            Some(a) => self
-                .cipher
-                .decrypt(nonce, Payload { msg: data, aad: a })
-                .map_err(|e| anyhow::anyhow!(e))?,
-            None => self
-                .cipher
-                .decrypt(nonce, data)
-                .map_err(|e| anyhow::anyhow!(e))?,
+        let open = |cipher: &XChaCha20Poly1305| match aad {
+            Some(a) => cipher.decrypt(nonce, Payload { msg: data, aad: a }),
+            None => cipher.decrypt(nonce, data),
+        };
+        let pt = match (open(&self.cipher), &self.legacy_cipher) {
+            (Ok(pt), _) => pt,
+            (Err(_), Some(legacy)) => open(legacy).map_err(|e| anyhow::anyhow!(e))?,
+            (Err(e), None) => return Err(anyhow::anyhow!(e)),
         };
         Ok(pt)
     }
@@ -98,13 +109,15 @@ impl Encryptor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::secret::LockedBox;
     use chacha20poly1305::KeyInit;
+    use std::sync::Arc;
 
     fn make_encryptor() -> Encryptor {
         // deterministic key for tests
         let key = [0x42u8; 32];
         let cipher = XChaCha20Poly1305::new_from_slice(&key).expect("valid key");
-        Encryptor::new(cipher)
+        Encryptor::new(Arc::new(LockedBox::new(cipher)))
     }
 
     #[test]
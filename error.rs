@@ -7,6 +7,14 @@ pub enum SecureFsError {
     #[error("Key error: {0}")]
     Key(String),
 
+    /// No key exists at the configured source
+    #[error("No key found in {0}")]
+    KeyNotFound(String),
+
+    /// The loaded key is not the one the vault was created with
+    #[error("Wrong key for this vault: it was created with key {expected} but key {found} is loaded")]
+    KeyMismatch { expected: String, found: String },
+
     /// Encryption operation failures
     #[error("Encryption error: {0}")]
     Encryption(String),
@@ -26,6 +34,26 @@ pub enum SecureFsError {
     /// Configuration errors
     #[error("Config error: {0}")]
     Config(String),
+
+    /// Key file with unsafe permissions, ownership or location
+    #[error("Insecure key file {path}: {reason}")]
+    InsecureKeyFile { path: String, reason: String },
+
+    /// Storage name that could escape the storage root or is reserved
+    #[error("Invalid storage name {name:?}: {reason}")]
+    InvalidName { name: String, reason: String },
+
+    /// A create-only write found an existing file
+    #[error("File {0:?} already exists")]
+    AlreadyExists(String),
+
+    /// A conditional write found the file at another version
+    #[error("File {name:?} changed: expected version {expected}, found {found}")]
+    VersionMismatch { name: String, expected: String, found: String },
+
+    /// A file encrypted under a key of its own whose key is not in the key table
+    #[error("File key {0} was destroyed or is missing from the key table; the file can't be decrypted")]
+    FileKeyMissing(String),
 }
 
 impl SecureFsError {
@@ -52,6 +80,20 @@ impl SecureFsError {
     pub fn config(msg: impl Into<String>) -> Self {
         Self::Config(msg.into())
     }
+
+    pub fn insecure_key_file(path: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::InsecureKeyFile {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn invalid_name(name: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::InvalidName {
+            name: name.into(),
+            reason: reason.into(),
+        }
+    }
 }
 
 impl From<std::io::Error> for SecureFsError {
@@ -0,0 +1,413 @@
+//! Per-file keys and crypto-shredding.
+//!
+//! When a store is opened with a [`KeyTable`] (see
+//! [`SecureFileOps::with_key_table`](crate::storagefile_ops::SecureFileOps::with_key_table)),
+//! every file written with the master key is encrypted under a fresh random
+//! key of its own instead, kept in the table wrapped by the master key.
+//! Deleting a file with [`DeleteMode::Shred`] destroys the keys of the file
+//! and its archived versions before unlinking anything, so copies of the
+//! ciphertext that outlive the deletion (in backups, snapshots or SSD
+//! remapped blocks) can no longer be decrypted, even with the master key.
+//!
+//! Shredding is only as strong as the deletion of the table entry: keep the
+//! table outside the storage directory, on storage that is not backed up or
+//! snapshotted with it.
+//!
+//! ## Format
+//!
+//! A file with its own key is an envelope around a V1 or V2 file encrypted
+//! under the subkeys of that key:
+//!
+//! ```text
+//! [version:1 = 4][key_id:16][V1 or V2 file]
+//! ```
+//!
+//! `key_id` is random and names the key in the table. A V1 file whose random
+//! nonce happens to start with 4 is told apart by its missing table entry.
+//!
+//! ## Key Table
+//!
+//! ```json
+//! {
+//!   "format_version": 1,
+//!   "master_key_id": "<hex>",
+//!   "keys": { "<key_id hex>": "<hex nonce || wrapped key>" }
+//! }
+//! ```
+//!
+//! Keys are wrapped with XChaCha20-Poly1305 under the [`subkey::FILE_KEYS`]
+//! subkey, with their key ID as AAD. The table is replaced atomically, under
+//! the store's write lock (see [`crate::write_mode`]). Key rotation rewraps it
+//! without touching the files it covers.
+
+use crate::encryptor::Encryptor;
+use crate::error::SecureFsError;
+use crate::key_manager::{derive_subkey_cipher, subkey, KeyManager};
+use crate::secret::{LockedBox, SharedCipher};
+use crate::streaming::StreamEncryptor;
+use crate::util::AtomicFile;
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use zeroize::Zeroizing;
+
+/// Format version byte of files with their own key
+pub const VERSION_V4_FILE_KEY: u8 = 4;
+
+/// Length of the envelope header: version byte and key ID
+pub const HEADER_LEN: usize = 17;
+
+/// Current key table format version
+pub const KEY_TABLE_FORMAT_VERSION: u32 = 1;
+
+const AAD_DOMAIN: &[u8] = b"securefs/file-key/v1\0";
+const NONCE_LEN: usize = 24;
+
+/// ID of a per-file key
+pub(crate) type KeyId = [u8; 16];
+
+/// How [`SecureFileOps::delete_file_with`](crate::storagefile_ops::SecureFileOps::delete_file_with)
+/// removes a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeleteMode {
+    /// Move it to the trash if one is configured, otherwise unlink it
+    #[default]
+    Remove,
+    /// Destroy its keys, then unlink it and its archived versions,
+    /// bypassing the trash. Fails for files without their own key.
+    Shred,
+}
+
+/// A freshly generated per-file key
+pub(crate) struct FileKey {
+    pub id: KeyId,
+    pub key: LockedBox<[u8; 32]>,
+}
+
+impl FileKey {
+    pub fn generate() -> Self {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        let key = LockedBox::from_fn(|key| OsRng.fill_bytes(key));
+        Self { id, key }
+    }
+
+    /// Envelope header of files encrypted under this key
+    pub fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        header[0] = VERSION_V4_FILE_KEY;
+        header[1..].copy_from_slice(&self.id);
+        header
+    }
+}
+
+/// Content ciphers for a per-file key, derived like the master key's
+pub(crate) fn ciphers(key: &LockedBox<[u8; 32]>) -> (Encryptor, StreamEncryptor) {
+    (
+        Encryptor::new(derive_subkey_cipher(key, subkey::BUFFER_CONTENT)),
+        StreamEncryptor::new(derive_subkey_cipher(key, subkey::STREAM_CONTENT)),
+    )
+}
+
+/// Key ID in the envelope header at the start of `data`, if it has one
+pub(crate) fn key_id_of(data: &[u8]) -> Option<KeyId> {
+    match data {
+        [VERSION_V4_FILE_KEY, rest @ ..] if rest.len() >= 16 => rest[..16].try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Key IDs in the headers of the file at `path`, or of every file below it
+pub(crate) async fn ids_under(path: &Path) -> Result<Vec<KeyId>> {
+    let mut ids = Vec::new();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let meta = match fs::symlink_metadata(&path).await {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("inspecting {:?}", &path))),
+        };
+        if meta.is_dir() {
+            let mut entries = fs::read_dir(&path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                pending.push(entry.path());
+            }
+        } else if meta.is_file() {
+            let mut header = Vec::with_capacity(HEADER_LEN);
+            fs::File::open(&path)
+                .await
+                .with_context(|| format!("opening {:?}", &path))?
+                .take(HEADER_LEN as u64)
+                .read_to_end(&mut header)
+                .await?;
+            ids.extend(key_id_of(&header));
+        }
+    }
+    Ok(ids)
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableFile {
+    format_version: u32,
+    master_key_id: String,
+    keys: BTreeMap<String, String>,
+}
+
+/// Per-file keys of a store, wrapped by its master key
+#[derive(Clone)]
+pub struct KeyTable {
+    path: PathBuf,
+    cipher: SharedCipher,
+    master_key_id: String,
+    /// Wrapping key of a rotation's old master key, for reads only
+    previous: Option<(SharedCipher, String)>,
+}
+
+impl KeyTable {
+    /// The key table at `path`, wrapped by `km`; created on first use
+    pub fn new(km: &KeyManager, path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            cipher: km.derive_subkey(subkey::FILE_KEYS),
+            master_key_id: hex::encode(km.key_id()),
+            previous: None,
+        }
+    }
+
+    /// Also reads keys while the table is still wrapped by `previous`'s
+    /// master key, as it is until a key rotation rewraps it (see
+    /// [`crate::rotation`]). Changes still need this table's own key.
+    pub(crate) fn reading_previous(mut self, previous: &KeyTable) -> Self {
+        self.previous = Some((previous.cipher.clone(), previous.master_key_id.clone()));
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds `key`. The caller holds the store's write lock.
+    pub(crate) async fn insert(&self, key: &FileKey) -> Result<()> {
+        let mut table = self.load_own().await?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let wrapped = wrap(&self.cipher, &nonce, &key.id, &key.key[..])?;
+        table.keys.insert(hex::encode(key.id), wrapped);
+        self.save(&table).await
+    }
+
+    /// The key with ID `id`, or `None` if it was never added or was destroyed
+    pub(crate) async fn get(&self, id: &KeyId) -> Result<Option<LockedBox<[u8; 32]>>> {
+        let table = self.load().await?;
+        let cipher = match &self.previous {
+            Some((cipher, master_key_id)) if table.master_key_id == *master_key_id => cipher,
+            _ => {
+                self.check_owner(&table)?;
+                &self.cipher
+            }
+        };
+        let Some(wrapped) = table.keys.get(&hex::encode(id)) else {
+            return Ok(None);
+        };
+        let key = unwrap(cipher, id, wrapped)?;
+        if key.len() != 32 {
+            return Err(SecureFsError::format("per-file key has the wrong length").into());
+        }
+        Ok(Some(LockedBox::from_fn(|locked| locked.copy_from_slice(&key))))
+    }
+
+    /// Destroys the keys in `ids`; returns how many were in the table.
+    /// The caller holds the store's write lock.
+    pub(crate) async fn remove(&self, ids: &[KeyId]) -> Result<usize> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let mut table = self.load_own().await?;
+        let removed = ids
+            .iter()
+            .filter(|id| table.keys.remove(&hex::encode(id)).is_some())
+            .count();
+        if removed > 0 {
+            self.save(&table).await?;
+        }
+        Ok(removed)
+    }
+
+    /// IDs of every key in the table
+    pub(crate) async fn ids(&self) -> Result<HashSet<KeyId>> {
+        let table = self.load().await?;
+        Ok(table
+            .keys
+            .keys()
+            .filter_map(|id| hex::decode(id).ok()?.try_into().ok())
+            .collect())
+    }
+
+    /// Rewraps every key under `new_km`, unless that was already done
+    pub(crate) async fn rekey(&self, new_km: &KeyManager) -> Result<()> {
+        let new = Self::new(new_km, &self.path);
+        let table = self.load().await?;
+        if table.master_key_id == new.master_key_id {
+            return Ok(());
+        }
+        self.check_owner(&table)?;
+        let mut keys = BTreeMap::new();
+        for (id_hex, wrapped) in &table.keys {
+            let id: KeyId = hex::decode(id_hex)
+                .ok()
+                .and_then(|id| id.try_into().ok())
+                .ok_or_else(|| SecureFsError::format(format!("invalid key ID {:?} in key table", id_hex)))?;
+            let key = unwrap(&self.cipher, &id, wrapped)?;
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            keys.insert(id_hex.clone(), wrap(&new.cipher, &nonce, &id, &key)?);
+        }
+        new.save(&TableFile {
+            format_version: KEY_TABLE_FORMAT_VERSION,
+            master_key_id: new.master_key_id.clone(),
+            keys,
+        })
+        .await
+    }
+
+    async fn load(&self) -> Result<TableFile> {
+        let data = match fs::read(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(TableFile {
+                    format_version: KEY_TABLE_FORMAT_VERSION,
+                    master_key_id: self.master_key_id.clone(),
+                    keys: BTreeMap::new(),
+                })
+            }
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("reading key table {:?}", &self.path))),
+        };
+        let table: TableFile = serde_json::from_slice(&data)
+            .map_err(|e| SecureFsError::format(format!("parsing key table {:?}: {}", &self.path, e)))?;
+        if table.format_version > KEY_TABLE_FORMAT_VERSION {
+            return Err(SecureFsError::format(format!(
+                "key table format version {} is newer than this version of securefs supports ({})",
+                table.format_version, KEY_TABLE_FORMAT_VERSION
+            ))
+            .into());
+        }
+        Ok(table)
+    }
+
+    /// Loads the table, which must be wrapped by this table's master key
+    async fn load_own(&self) -> Result<TableFile> {
+        let table = self.load().await?;
+        self.check_owner(&table)?;
+        Ok(table)
+    }
+
+    fn check_owner(&self, table: &TableFile) -> Result<()> {
+        if table.master_key_id != self.master_key_id {
+            return Err(SecureFsError::key(format!(
+                "key table {:?} is wrapped by master key {}, not {}",
+                &self.path, table.master_key_id, self.master_key_id
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn save(&self, table: &TableFile) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("creating directory {:?}", dir))?;
+        }
+        let mut out = AtomicFile::create_private(&self.path).await?;
+        out.file()
+            .write_all(&serde_json::to_vec_pretty(table)?)
+            .await
+            .with_context(|| format!("writing key table {:?}", &self.path))?;
+        out.commit().await
+    }
+}
+
+fn wrap(cipher: &SharedCipher, nonce: &XNonce, id: &KeyId, key: &[u8]) -> Result<String> {
+    let wrapped = cipher
+        .encrypt(
+            nonce,
+            Payload {
+                msg: key,
+                aad: &aad(id),
+            },
+        )
+        .map_err(|_| SecureFsError::encryption("wrapping per-file key failed"))?;
+    Ok(hex::encode([nonce.as_slice(), &wrapped].concat()))
+}
+
+fn unwrap(cipher: &SharedCipher, id: &KeyId, wrapped: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let wrapped = hex::decode(wrapped)
+        .ok()
+        .filter(|w| w.len() > NONCE_LEN)
+        .ok_or_else(|| SecureFsError::format(format!("key {} in key table is malformed", hex::encode(id))))?;
+    let (nonce, ct) = wrapped.split_at(NONCE_LEN);
+    let key = cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ct,
+                aad: &aad(id),
+            },
+        )
+        .map_err(|_| SecureFsError::decryption(format!("key {} in key table failed authentication", hex::encode(id))))?;
+    Ok(Zeroizing::new(key))
+}
+
+fn aad(id: &KeyId) -> Vec<u8> {
+    [AAD_DOMAIN, id].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyfile::KeyFile;
+
+    fn master(byte: u8) -> KeyManager {
+        KeyManager::from_key_file(&KeyFile::new([byte; 32]))
+    }
+
+    #[tokio::test]
+    async fn keys_survive_rekey_and_are_gone_once_removed() -> Result<()> {
+        let tmp = tempfile::TempDir::new()?;
+        let path = tmp.path().join("keys.json");
+        let table = KeyTable::new(&master(0x42), &path);
+        let key = FileKey::generate();
+        table.insert(&key).await?;
+        assert_eq!(table.get(&key.id).await?.as_deref(), Some(&*key.key));
+        assert!(!fs::read_to_string(&path).await?.contains(&hex::encode(&key.key[..])));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).await?.permissions().mode() & 0o777, 0o600);
+        }
+
+        table.rekey(&master(0x24)).await?;
+        assert!(table.get(&key.id).await.is_err());
+        let rekeyed = KeyTable::new(&master(0x24), &path);
+        assert_eq!(rekeyed.get(&key.id).await?.as_deref(), Some(&*key.key));
+        assert_eq!(rekeyed.ids().await?, HashSet::from([key.id]));
+
+        assert_eq!(rekeyed.remove(&[key.id]).await?, 1);
+        assert!(rekeyed.get(&key.id).await?.is_none());
+        assert!(!fs::read_to_string(&path).await?.contains(&hex::encode(key.id)));
+        Ok(())
+    }
+
+    #[test]
+    fn envelope_header_names_the_key() {
+        let key = FileKey::generate();
+        assert_eq!(key_id_of(&key.header()), Some(key.id));
+        assert_eq!(key_id_of(&key.header()[..10]), None);
+        assert_eq!(key_id_of(&[2; HEADER_LEN]), None);
+    }
+}
@@ -0,0 +1,244 @@
+//! Version history of stored files.
+//!
+//! When a store is opened with a [`HistoryPolicy`] (see
+//! [`SecureFileOps::with_history`](crate::storagefile_ops::SecureFileOps::with_history)),
+//! every overwrite first archives the file being replaced, and the policy
+//! then prunes that file's archive. Archived versions are kept encrypted
+//! exactly as they were written, so archiving needs no key.
+//!
+//! ## Layout
+//!
+//! ```text
+//! .securefs-history/<path>/<archived_at>        encrypted file as it was
+//! .securefs-history/<path>/<archived_at>.meta   its sealed metadata sidecar
+//! ```
+//!
+//! `<path>` is the file's path relative to the storage root (encrypted, if
+//! names are), and `<archived_at>` the time it was replaced, in nanoseconds
+//! since the Unix epoch. Entries are hard links to the replaced files where
+//! the filesystem allows, and copies otherwise. Subdirectories of `<path>`
+//! hold the history of files inside a directory of the same name.
+
+use crate::metadata;
+use crate::util;
+use crate::write_mode::FileVersion;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::debug;
+
+/// Directory in the storage root holding archived versions
+pub const HISTORY_DIR: &str = ".securefs-history";
+
+/// Suffix of an entry's metadata sidecar
+const META_SUFFIX: &str = ".meta";
+
+/// How many replaced versions to keep per file.
+/// A version is pruned as soon as it exceeds either limit; with neither
+/// set, every version is kept.
+///
+/// ```json
+/// { "history": { "max_versions": 10, "max_age_days": 30 } }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryPolicy {
+    /// Keep at most this many replaced versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_versions: Option<usize>,
+    /// Drop versions replaced more than this many days ago
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_days: Option<u64>,
+}
+
+impl HistoryPolicy {
+    /// Keeps the last `n` replaced versions
+    pub fn keep_last(n: usize) -> Self {
+        Self {
+            max_versions: Some(n),
+            max_age_days: None,
+        }
+    }
+
+    /// Indices of `archived` (newest first) that the policy drops at `now`
+    fn expired(&self, archived: &[SystemTime], now: SystemTime) -> Vec<usize> {
+        let max_age = self.max_age_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+        archived
+            .iter()
+            .enumerate()
+            .filter(|(i, at)| {
+                self.max_versions.is_some_and(|max| *i >= max)
+                    || max_age.is_some_and(|age| now.duration_since(**at).is_ok_and(|elapsed| elapsed > age))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// One version of a stored file, as listed by
+/// [`SecureFileOps::list_versions`](crate::storagefile_ops::SecureFileOps::list_versions)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub version: FileVersion,
+    /// When this version was replaced; `None` for the current version
+    pub archived_at: Option<SystemTime>,
+    /// Encrypted size in bytes
+    pub size: u64,
+}
+
+/// An archived version of a file
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub path: PathBuf,
+    pub archived_at: SystemTime,
+}
+
+impl Entry {
+    pub fn meta_path(&self) -> PathBuf {
+        meta_path(&self.path)
+    }
+}
+
+/// Directory holding the history of the encrypted file at `path` under `root`
+pub(crate) fn history_dir(root: &Path, path: &Path) -> PathBuf {
+    let relative = path
+        .strip_prefix(root)
+        .expect("BUG: stored files always lie under the storage root");
+    root.join(HISTORY_DIR).join(relative)
+}
+
+/// Sidecar location of the entry at `entry`
+pub(crate) fn meta_path(entry: &Path) -> PathBuf {
+    let mut meta = entry.as_os_str().to_owned();
+    meta.push(META_SUFFIX);
+    PathBuf::from(meta)
+}
+
+/// Archives the current file at `path`, and its sidecar, before it is replaced
+pub(crate) async fn archive(root: &Path, path: &Path) -> Result<()> {
+    let dir = history_dir(root, path);
+    fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("creating history directory {:?}", &dir))?;
+
+    let mut at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    // Writes are serialized, but a coarse clock can repeat a timestamp
+    let entry = loop {
+        let entry = dir.join(format!("{:020}", at));
+        match link_or_copy(path, &entry).await {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => at += 1,
+            result => break result.map(|_| entry).with_context(|| format!("archiving {:?}", path))?,
+        }
+    };
+    let sidecar = metadata::sidecar_path(root, path);
+    match link_or_copy(&sidecar, &meta_path(&entry)).await {
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        result => result.with_context(|| format!("archiving metadata {:?}", &sidecar))?,
+    }
+    util::sync_parent(&entry).await?;
+    debug!(entry = %entry.display(), "archived previous version");
+    Ok(())
+}
+
+/// Hard links `src` to `dest`, copying where links aren't supported
+async fn link_or_copy(src: &Path, dest: &Path) -> std::io::Result<()> {
+    match fs::hard_link(src, dest).await {
+        Err(e) if !matches!(e.kind(), ErrorKind::AlreadyExists | ErrorKind::NotFound) => {
+            fs::copy(src, dest).await.map(|_| ())
+        }
+        result => result,
+    }
+}
+
+/// Archived versions of the file at `path` under `root`, newest first
+pub(crate) async fn entries(root: &Path, path: &Path) -> Result<Vec<Entry>> {
+    entries_in(&history_dir(root, path)).await
+}
+
+/// Archived versions in history directory `dir`, newest first
+pub(crate) async fn entries_in(dir: &Path) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = match fs::read_dir(dir).await {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("reading history {:?}", dir))),
+    };
+    while let Some(item) = read_dir.next_entry().await? {
+        // Subdirectories hold the history of other files
+        if !item.file_type().await?.is_file() {
+            continue;
+        }
+        let Some(nanos) = item.file_name().to_str().and_then(|n| n.parse::<u64>().ok()) else {
+            continue;
+        };
+        entries.push(Entry {
+            path: item.path(),
+            archived_at: UNIX_EPOCH + Duration::from_nanos(nanos),
+        });
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.archived_at));
+    Ok(entries)
+}
+
+/// Removes the archived versions of the file at `path` that `policy` no longer keeps
+pub(crate) async fn prune(root: &Path, path: &Path, policy: &HistoryPolicy) -> Result<usize> {
+    let entries = entries(root, path).await?;
+    let archived: Vec<_> = entries.iter().map(|e| e.archived_at).collect();
+    let expired = policy.expired(&archived, SystemTime::now());
+    for &i in &expired {
+        remove_entry(&entries[i]).await?;
+    }
+    Ok(expired.len())
+}
+
+/// Removes every archived version of the file at `path`, keeping the
+/// history of files in a directory of the same name
+pub(crate) async fn remove_all(root: &Path, path: &Path) -> Result<()> {
+    for entry in entries(root, path).await? {
+        remove_entry(&entry).await?;
+    }
+    // Only succeeds once nothing else is left in it
+    let _ = fs::remove_dir(history_dir(root, path)).await;
+    Ok(())
+}
+
+async fn remove_entry(entry: &Entry) -> Result<()> {
+    fs::remove_file(&entry.path)
+        .await
+        .with_context(|| format!("removing {:?}", &entry.path))?;
+    match fs::remove_file(entry.meta_path()).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_drops_versions_beyond_either_limit() {
+        let now = UNIX_EPOCH + Duration::from_secs(100 * 86400);
+        let days_ago = |d: u64| now - Duration::from_secs(d * 86400);
+        let archived = [days_ago(1), days_ago(5), days_ago(20), days_ago(40)];
+
+        assert!(HistoryPolicy::default().expired(&archived, now).is_empty());
+        assert_eq!(HistoryPolicy::keep_last(2).expired(&archived, now), [2, 3]);
+        let by_age = HistoryPolicy {
+            max_age_days: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(by_age.expired(&archived, now), [2, 3]);
+        let both = HistoryPolicy {
+            max_versions: Some(3),
+            max_age_days: Some(30),
+        };
+        assert_eq!(both.expired(&archived, now), [3]);
+        assert_eq!(HistoryPolicy::keep_last(0).expired(&archived, now), [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,213 @@
+//! External key helper protocol.
+//!
+//! This module provides [`KeyHelperProvider`], selected by the `key_command`
+//! config option, which delegates key storage to an external program in the
+//! style of git credential helpers. Teams use it to bridge secret managers the
+//! library can't link against.
+//!
+//! ## Protocol
+//!
+//! The helper is run through the shell as `<key_command> <action>`, where
+//! `action` is `get`, `store` or `erase`. It receives `name=value` lines on
+//! stdin, terminated by a blank line:
+//!
+//! ```text
+//! version=1
+//! storage_dir=./storage
+//! key_id=<hex>          (store, erase)
+//! key=<hex key file>    (store only)
+//! ```
+//!
+//! For `get` it answers on stdout in the same format; empty output means it
+//! holds no key. A `key_id` line, if present, must match the returned key.
+//!
+//! ```text
+//! key=<hex key file or raw key>
+//! key_id=<hex>
+//! ```
+//!
+//! A non-zero exit status is an error; its stderr is included in the message.
+
+use crate::key_provider::{parse_key_material, KeyProvider};
+use crate::keyfile::KeyFile;
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+use zeroize::Zeroizing;
+
+/// Protocol version sent to helpers
+pub const HELPER_PROTOCOL_VERSION: u32 = 1;
+
+/// Key held by an external helper command
+pub struct KeyHelperProvider {
+    command: String,
+    storage_dir: Option<String>,
+}
+
+impl KeyHelperProvider {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            storage_dir: None,
+        }
+    }
+
+    /// Tell the helper which store the key belongs to
+    pub fn with_storage_dir(mut self, storage_dir: impl Into<String>) -> Self {
+        self.storage_dir = Some(storage_dir.into());
+        self
+    }
+
+    /// Runs `action`, sending `attrs` on stdin, and returns the parsed reply
+    fn run(&self, action: &str, attrs: &[(&str, &str)]) -> Result<Vec<(String, Zeroizing<String>)>> {
+        let mut request = Zeroizing::new(format!("version={}\n", HELPER_PROTOCOL_VERSION));
+        if let Some(dir) = &self.storage_dir {
+            push_attr(&mut request, "storage_dir", dir)?;
+        }
+        for (name, value) in attrs {
+            push_attr(&mut request, name, value)?;
+        }
+        request.push('\n');
+
+        debug!(helper = %self.command, action, "running key helper");
+        let mut child = shell(&format!("{} {}", self.command, action))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("running {} {}", self.describe(), action))?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        match stdin.write_all(request.as_bytes()) {
+            // Helpers that don't need the request may exit without reading it
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+            other => other.with_context(|| format!("writing request to {}", self.describe()))?,
+        }
+        drop(stdin);
+
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("waiting for {} {}", self.describe(), action))?;
+        let stdout = Zeroizing::new(output.stdout);
+        if !output.status.success() {
+            bail!(
+                "{} {} failed ({}): {}",
+                self.describe(),
+                action,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let text = std::str::from_utf8(&stdout)
+            .with_context(|| format!("{} {} returned non-UTF-8 output", self.describe(), action))?;
+        parse_reply(text)
+    }
+}
+
+impl KeyProvider for KeyHelperProvider {
+    fn describe(&self) -> String {
+        format!("key helper '{}'", self.command)
+    }
+
+    fn load(&self) -> Result<Option<KeyFile>> {
+        let reply = self.run("get", &[])?;
+        let Some((_, key)) = reply.iter().find(|(name, _)| name == "key") else {
+            return Ok(None);
+        };
+        let key_file = parse_key_material(key.as_bytes())
+            .with_context(|| format!("parsing key from {}", self.describe()))?;
+
+        if let Some((_, key_id)) = reply.iter().find(|(name, _)| name == "key_id") {
+            if key_id.as_str() != hex::encode(key_file.key_id) {
+                bail!(
+                    "{} returned key {} but claimed key ID {}",
+                    self.describe(),
+                    hex::encode(key_file.key_id),
+                    key_id.as_str()
+                );
+            }
+        }
+        Ok(Some(key_file))
+    }
+
+    fn store(&self, key: &KeyFile) -> Result<()> {
+        let key_hex = Zeroizing::new(hex::encode(&key.to_bytes()[..]));
+        let key_id = hex::encode(key.key_id);
+        self.run("store", &[("key_id", &key_id), ("key", &key_hex)])?;
+        info!(helper = %self.command, key_id = %key_id, "stored key with helper");
+        Ok(())
+    }
+
+    fn erase(&self, key_id: &[u8; 16]) -> Result<()> {
+        let key_id = hex::encode(key_id);
+        self.run("erase", &[("key_id", &key_id)])?;
+        info!(helper = %self.command, key_id = %key_id, "erased key from helper");
+        Ok(())
+    }
+}
+
+fn push_attr(request: &mut String, name: &str, value: &str) -> Result<()> {
+    if value.contains('\n') || value.contains('\0') {
+        bail!("key helper attribute '{}' must not contain newlines or NUL", name);
+    }
+    request.push_str(name);
+    request.push('=');
+    request.push_str(value);
+    request.push('\n');
+    Ok(())
+}
+
+/// Parses `name=value` lines up to the first blank line
+fn parse_reply(text: &str) -> Result<Vec<(String, Zeroizing<String>)>> {
+    let mut attrs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            break;
+        }
+        // The line may hold key material, so it is never echoed
+        let (name, value) = line
+            .split_once('=')
+            .with_context(|| format!("malformed key helper output on line {} (expected name=value)", i + 1))?;
+        attrs.push((name.to_string(), Zeroizing::new(value.to_string())));
+    }
+    Ok(attrs)
+}
+
+#[cfg(unix)]
+fn shell(script: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(script);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(script: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(script);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_reply_until_blank_line() {
+        let reply = parse_reply("key=00ff\r\nkey_id=abcd\n\nignored=1\n").expect("parse");
+        assert_eq!(reply.len(), 2);
+        assert_eq!(reply[0].0, "key");
+        assert_eq!(reply[1].1.as_str(), "abcd");
+
+        assert!(parse_reply("").expect("empty").is_empty());
+        assert!(parse_reply("no equals sign\n").is_err());
+    }
+
+    #[test]
+    fn rejects_multiline_attributes() {
+        let mut request = String::new();
+        assert!(push_attr(&mut request, "storage_dir", "a\nkey=evil").is_err());
+    }
+}
@@ -5,94 +5,460 @@
 //!
 //! ## Security Features
 //!
-//! - Keys are zeroized on drop (via `Zeroize` trait)
+//! - Keys live in locked, non-dumpable memory and are zeroized on drop
+//!   (see [`crate::secret`])
 //! - Unix file permissions set to 0600 (owner read/write only)
 //! - Cryptographically secure random generation via `OsRng`
+//! - Self-describing, checksummed key files (see [`crate::keyfile`])
+//! - Pluggable key sources (see [`crate::key_provider`])
+//! - M-of-N key backup with Shamir secret sharing (see [`crate::shamir`])
+//! - Mnemonic and paper key backups (see [`crate::mnemonic`])
+//! - Purpose-separated subkeys derived with HKDF (see [`KeyManager::derive_subkey`])
+//!
+//! ## Recipients and Identities
+//!
+//! Besides the symmetric master key, files can be encrypted to X25519
+//! [`Recipient`] public keys. Only holders of the matching [`Identity`]
+//! file can decrypt them, which lets producers write into a store without
+//! being able to read it back.
 
+use crate::error::SecureFsError;
+use crate::key_provider::{self, KeyProvider};
+use crate::keyfile::KeyFile;
+use crate::mnemonic;
+use crate::secret::{LockedBox, SharedCipher};
+use crate::shamir::{self, KeyShare};
+use crate::streaming::RecipientStanza;
 use anyhow::{bail, Context, Result};
-use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
 use rand_core::OsRng;
 use rand_core::RngCore;
+use sha2::Sha256;
+use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 use tokio::fs;
 use tracing::{info, warn};
-use zeroize::Zeroize;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Text prefix for encoded identities (private keys)
+pub const IDENTITY_PREFIX: &str = "securefs-identity:";
+
+/// Text prefix for encoded recipients (public keys)
+pub const RECIPIENT_PREFIX: &str = "securefs-recipient:";
+
+/// HKDF info string for wrapping file keys to X25519 recipients
+const X25519_WRAP_INFO: &[u8] = b"securefs/v3/x25519";
+
+/// HKDF info prefix for purpose-bound subkeys; the purpose is appended
+const SUBKEY_INFO_PREFIX: &[u8] = b"securefs/subkey/v1/";
+
+/// Purposes accepted by [`KeyManager::derive_subkey`].
+/// Each purpose yields an independent key; never reuse one for two jobs.
+pub mod subkey {
+    /// What a subkey is for; only the purposes below exist
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Purpose(&'static str);
+
+    impl Purpose {
+        /// The label mixed into the HKDF info string
+        pub fn as_str(&self) -> &'static str {
+            self.0
+        }
+    }
+
+    /// File contents in the V1 buffer format
+    pub const BUFFER_CONTENT: Purpose = Purpose("content/buffer");
+    /// File contents in the V2 stream format
+    pub const STREAM_CONTENT: Purpose = Purpose("content/stream");
+    /// Vault descriptor key check value and MAC (see [`crate::vault`])
+    pub const VAULT: Purpose = Purpose("vault");
+    /// Encrypted storage names (see [`crate::name_cipher`])
+    pub const NAMES: Purpose = Purpose("names/encrypt");
+    /// Synthetic IVs for deterministic name encryption
+    pub const NAME_SIV: Purpose = Purpose("names/siv");
+    /// Sealed metadata sidecars (see [`crate::metadata`])
+    pub const METADATA: Purpose = Purpose("metadata");
+    /// Wrapping of per-file keys in a key table (see [`crate::file_keys`])
+    pub const FILE_KEYS: Purpose = Purpose("file-keys");
+}
 
 /// Handles key generation and persistence.
 /// In production: prefer a hardware key store or OS keyring.
 pub struct KeyManager {
-    key_bytes: [u8; 32],
+    key: LockedBox<[u8; 32]>,
+    cipher: SharedCipher,
+    key_id: [u8; 16],
 }
 
-impl Drop for KeyManager {
-    fn drop(&mut self) {
-        self.key_bytes.zeroize();
+impl KeyManager {
+    /// Opens the existing key from the source selected in `cfg` (see [`crate::key_provider`]).
+    /// Fails with [`SecureFsError::KeyNotFound`] if the source holds no key.
+    pub async fn open(cfg: &crate::config::Config) -> Result<Self> {
+        Self::open_provider(key_provider::from_config(cfg)).await
+    }
+
+    /// Generates a new key and stores it in the source selected in `cfg`.
+    /// Fails if the source already holds a key.
+    pub async fn create(cfg: &crate::config::Config) -> Result<Self> {
+        Self::create_with_provider(key_provider::from_config(cfg)).await
     }
-}
 
-impl KeyManager {
-    pub async fn new(cfg: &crate::config::Config) -> Result<Self> {
-        let path = Path::new(&cfg.key_path);
-
-        // Check if file exists using tokio::fs
-        let key_bytes = if fs::try_exists(path).await
-            .with_context(|| format!("checking existence of {}", path.display()))?
-        {
-            // Read existing key
-            info!(path = %path.display(), "loading existing encryption key");
-            let data = fs::read(path).await
-                .with_context(|| format!("reading key from {}", path.display()))?;
-            if data.len() != 32 {
-                warn!(path = %path.display(), found_bytes = data.len(), "invalid key size");
-                bail!(
-                    "expected 32-byte key at {} but found {} bytes",
-                    path.display(),
-                    data.len()
-                );
-            }
-            let mut arr = [0u8; 32];
-            arr.copy_from_slice(&data);
-            arr
-        } else {
-            // Generate new key
-            info!(path = %path.display(), "generating new encryption key");
-            let mut key = [0u8; 32];
-            OsRng.fill_bytes(&mut key);
-
-            // Write with restrictive permissions using spawn_blocking for Unix
-            #[cfg(unix)]
-            {
-                let path_buf = path.to_path_buf();
-                let key_clone = key;
-                tokio::task::spawn_blocking(move || {
-                    use std::fs::OpenOptions;
-                    use std::io::Write;
-                    use std::os::unix::fs::OpenOptionsExt;
-
-                    let mut f = OpenOptions::new()
-                        .write(true)
-                        .create_new(true)
-                        .mode(0o600)
-                        .open(&path_buf)?;
-                    f.write_all(&key_clone)?;
-                    Ok::<(), anyhow::Error>(())
-                }).await??;
-            }
-            #[cfg(not(unix))]
-            {
-                fs::write(path, &key).await?;
+    /// Opens the existing key from `provider`; fails if it holds none
+    pub async fn open_provider(provider: Arc<dyn KeyProvider>) -> Result<Self> {
+        let key_file = tokio::task::spawn_blocking(move || -> Result<KeyFile> {
+            load_existing(provider.as_ref())?
+                .ok_or_else(|| SecureFsError::KeyNotFound(provider.describe()).into())
+        })
+        .await??;
+
+        Ok(Self::from_key_file(&key_file))
+    }
+
+    /// Generates a new key and stores it in `provider`; fails if it already holds one
+    pub async fn create_with_provider(provider: Arc<dyn KeyProvider>) -> Result<Self> {
+        let key_file = tokio::task::spawn_blocking(move || {
+            if load_existing(provider.as_ref())?.is_some() {
+                bail!("{} already holds a key; refusing to overwrite it", provider.describe());
             }
+            generate_into(provider.as_ref())
+        })
+        .await??;
+
+        Ok(Self::from_key_file(&key_file))
+    }
 
-            key
-        };
+    /// Loads the key from the source selected in `cfg`,
+    /// generating and storing a new one if the source holds none.
+    #[deprecated(
+        since = "0.4.0",
+        note = "silently creates a key when the path is wrong; use `KeyManager::open` or `KeyManager::create`"
+    )]
+    pub async fn new(cfg: &crate::config::Config) -> Result<Self> {
+        #[allow(deprecated)]
+        Self::from_provider(key_provider::from_config(cfg)).await
+    }
+
+    /// Loads the key from `provider`, generating and storing a new one if it holds none
+    #[deprecated(
+        since = "0.4.0",
+        note = "use `KeyManager::open_provider` or `KeyManager::create_with_provider`"
+    )]
+    pub async fn from_provider(provider: Arc<dyn KeyProvider>) -> Result<Self> {
+        let key_file = tokio::task::spawn_blocking(move || match load_existing(provider.as_ref())? {
+            Some(key_file) => Ok(key_file),
+            None => generate_into(provider.as_ref()),
+        })
+        .await??;
+
+        Ok(Self::from_key_file(&key_file))
+    }
+
+    /// Uses an already loaded key file, e.g. one restored from a backup
+    pub fn from_key_file(key_file: &KeyFile) -> Self {
+        let key = LockedBox::from_slice(key_file.key());
+        let cipher = Arc::new(LockedBox::new(
+            XChaCha20Poly1305::new_from_slice(&key[..])
+                .expect("BUG: key is always 32 bytes, this should never fail"),
+        ));
+
+        Self {
+            key,
+            cipher,
+            key_id: key_file.key_id,
+        }
+    }
+
+    /// Public identifier of the loaded key, safe to log and display
+    pub fn key_id(&self) -> [u8; 16] {
+        self.key_id
+    }
+
+    /// Splits the master key into `shares` Shamir shares, any `threshold` of which recover it
+    pub fn split(&self, shares: u8, threshold: u8) -> Result<Vec<KeyShare>> {
+        Ok(shamir::split(&self.key, shares, threshold)?)
+    }
+
+    /// Recovers a key file from shares produced by [`KeyManager::split`].
+    /// Fails if the shares are too few, inconsistent, or don't match their key fingerprint.
+    pub fn combine(shares: &[KeyShare]) -> Result<KeyFile> {
+        let secret = shamir::combine(shares)?;
+        Ok(KeyFile::from_locked(LockedBox::from_slice(&secret)))
+    }
+
+    /// Renders the master key as a 24-word mnemonic for paper backup
+    pub fn export_mnemonic(&self) -> Zeroizing<String> {
+        mnemonic::to_mnemonic(&self.key)
+    }
+
+    /// Renders the master key as a checksummed base32 paper key
+    pub fn export_paper_key(&self) -> Zeroizing<String> {
+        mnemonic::to_paper_key(&self.key)
+    }
+
+    /// Rebuilds a key file from a mnemonic or paper key.
+    /// Fails if the backup is malformed or its checksum does not match.
+    pub fn import_backup(text: &str) -> Result<KeyFile> {
+        let secret = mnemonic::parse_backup(text)?;
+        Ok(KeyFile::from_locked(LockedBox::from_slice(&secret)))
+    }
+
+    /// Generates a new X25519 identity and writes it to `path` (mode 0600).
+    /// Fails if the file already exists.
+    pub async fn generate_identity(path: impl AsRef<Path>) -> Result<Identity> {
+        let path = path.as_ref();
+        info!(path = %path.display(), "generating new recipient identity");
+        let identity = Identity::generate();
+        let contents = Zeroizing::new(format!(
+            "# recipient: {}\n{}\n",
+            identity.recipient(),
+            identity.encode().as_str()
+        ));
+        let path_buf = path.to_path_buf();
+        tokio::task::spawn_blocking(move || write_secret_file(&path_buf, contents.as_bytes()))
+            .await??;
+        Ok(identity)
+    }
+
+    /// Loads an identity file written by [`KeyManager::generate_identity`].
+    /// Blank lines and `#` comments are ignored.
+    pub async fn load_identity(path: impl AsRef<Path>) -> Result<Identity> {
+        let path = path.as_ref();
+        info!(path = %path.display(), "loading recipient identity");
+        let contents = Zeroizing::new(
+            fs::read_to_string(path).await
+                .with_context(|| format!("reading identity from {}", path.display()))?,
+        );
+        let line = contents
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .ok_or_else(|| anyhow::anyhow!("no identity found in {}", path.display()))?;
+        Identity::parse(line).with_context(|| format!("parsing identity in {}", path.display()))
+    }
 
-        Ok(Self { key_bytes })
+    /// Shared cipher over the raw master key.
+    /// Every clone borrows the same locked copy; no key bytes are duplicated.
+    /// New data should use [`KeyManager::derive_subkey`]; this remains for
+    /// reading files written before subkeys existed.
+    pub fn cipher(&self) -> SharedCipher {
+        debug_assert_eq!(self.key.len(), 32);
+        Arc::clone(&self.cipher)
     }
 
-    pub fn cipher(&self) -> XChaCha20Poly1305 {
-        // This is safe because key_bytes is always exactly 32 bytes
-        debug_assert_eq!(self.key_bytes.len(), 32);
-        XChaCha20Poly1305::new_from_slice(&self.key_bytes)
-            .expect("BUG: key_bytes is always 32 bytes, this should never fail")
+    /// Derives an independent cipher for `purpose` (see [`subkey`]) with
+    /// HKDF-SHA256, so no two parts of the system share key material.
+    pub fn derive_subkey(&self, purpose: subkey::Purpose) -> SharedCipher {
+        derive_subkey_cipher(&self.key, purpose)
+    }
+
+    /// Raw 32-byte subkey for `purpose`, for uses other than XChaCha20-Poly1305
+    pub fn derive_subkey_bytes(&self, purpose: subkey::Purpose) -> LockedBox<[u8; 32]> {
+        derive_subkey_from(&self.key, purpose)
+    }
+}
+
+/// [`KeyManager::derive_subkey`] for a key other than the master key, such
+/// as a per-file key, read straight from its locked memory
+pub(crate) fn derive_subkey_cipher(key: &LockedBox<[u8; 32]>, purpose: subkey::Purpose) -> SharedCipher {
+    let key = derive_subkey_from(key, purpose);
+    Arc::new(LockedBox::new(
+        XChaCha20Poly1305::new_from_slice(&key[..])
+            .expect("BUG: subkey is always 32 bytes, this should never fail"),
+    ))
+}
+
+fn derive_subkey_from(key: &LockedBox<[u8; 32]>, purpose: subkey::Purpose) -> LockedBox<[u8; 32]> {
+    let hk = Hkdf::<Sha256>::new(None, &key[..]);
+    let purpose = purpose.as_str().as_bytes();
+    let mut info = Vec::with_capacity(SUBKEY_INFO_PREFIX.len() + purpose.len());
+    info.extend_from_slice(SUBKEY_INFO_PREFIX);
+    info.extend_from_slice(purpose);
+    LockedBox::from_fn(|okm| {
+        hk.expand(&info, okm)
+            .expect("BUG: 32 bytes is a valid HKDF-SHA256 output length")
+    })
+}
+
+/// Loads the key from `provider`, logging the outcome
+fn load_existing(provider: &dyn KeyProvider) -> Result<Option<KeyFile>> {
+    let source = provider.describe();
+    match provider.load() {
+        Ok(Some(key_file)) => {
+            info!(source = %source, legacy = key_file.legacy, "loaded existing encryption key");
+            Ok(Some(key_file))
+        }
+        Ok(None) => Ok(None),
+        Err(e) => {
+            warn!(source = %source, error = %e, "failed to load encryption key");
+            Err(e)
+        }
+    }
+}
+
+/// Generates a fresh key and stores it in `provider`
+fn generate_into(provider: &dyn KeyProvider) -> Result<KeyFile> {
+    info!(source = %provider.describe(), "generating new encryption key");
+    let key_file = KeyFile::from_locked(LockedBox::from_fn(|key| OsRng.fill_bytes(key)));
+    provider.store(&key_file)?;
+    Ok(key_file)
+}
+
+/// X25519 private key that can decrypt files sealed to its [`Recipient`].
+/// The secret scalar is zeroized on drop.
+pub struct Identity {
+    secret: LockedBox<StaticSecret>,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            secret: LockedBox::new(StaticSecret::random_from_rng(OsRng)),
+        }
+    }
+
+    /// Parses a `securefs-identity:<hex>` string
+    pub fn parse(s: &str) -> Result<Self> {
+        let encoded = s
+            .trim()
+            .strip_prefix(IDENTITY_PREFIX)
+            .ok_or_else(|| anyhow::anyhow!("identity must start with '{}'", IDENTITY_PREFIX))?;
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        hex::decode_to_slice(encoded, bytes.as_mut())
+            .map_err(|_| anyhow::anyhow!("identity must be 64 hex characters"))?;
+        Ok(Self {
+            secret: LockedBox::new(StaticSecret::from(*bytes)),
+        })
+    }
+
+    /// Encodes the identity as `securefs-identity:<hex>`
+    pub fn encode(&self) -> Zeroizing<String> {
+        let bytes = Zeroizing::new(self.secret.to_bytes());
+        Zeroizing::new(format!("{}{}", IDENTITY_PREFIX, hex::encode(bytes.as_ref())))
+    }
+
+    /// Public recipient matching this identity
+    pub fn recipient(&self) -> Recipient {
+        Recipient {
+            public: PublicKey::from(&*self.secret),
+        }
+    }
+
+    /// Attempts to recover the file key from a stanza addressed to this identity.
+    /// Returns `None` if the stanza was wrapped for a different recipient.
+    pub(crate) fn unwrap_file_key(&self, stanza: &RecipientStanza) -> Option<Zeroizing<[u8; 32]>> {
+        let ephemeral = PublicKey::from(stanza.ephemeral_public);
+        let shared = self.secret.diffie_hellman(&ephemeral);
+        if !shared.was_contributory() {
+            return None;
+        }
+        let wrap_key = derive_wrap_key(shared.as_bytes(), &ephemeral, &self.recipient().public);
+        let cipher = XChaCha20Poly1305::new_from_slice(wrap_key.as_ref()).ok()?;
+        let unwrapped = Zeroizing::new(
+            cipher
+                .decrypt(&XNonce::default(), Payload { msg: &stanza.wrapped_key, aad: &stanza.ephemeral_public })
+                .ok()?,
+        );
+        let mut key = Zeroizing::new([0u8; 32]);
+        key.copy_from_slice(unwrapped.get(..32)?);
+        Some(key)
+    }
+}
+
+/// X25519 public key that files can be encrypted to.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Recipient {
+    public: PublicKey,
+}
+
+impl Recipient {
+    /// Wraps `file_key` for this recipient using a fresh ephemeral key pair
+    pub(crate) fn wrap_file_key(&self, file_key: &[u8; 32]) -> Result<RecipientStanza> {
+        let ephemeral_secret = StaticSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&ephemeral_secret);
+        let shared = ephemeral_secret.diffie_hellman(&self.public);
+        if !shared.was_contributory() {
+            bail!("recipient public key is a low-order point");
+        }
+        let wrap_key = derive_wrap_key(shared.as_bytes(), &ephemeral, &self.public);
+        let cipher = XChaCha20Poly1305::new_from_slice(wrap_key.as_ref())
+            .expect("wrap key is always 32 bytes");
+
+        // The wrap key is single-use, so a fixed nonce is safe here
+        let wrapped = cipher
+            .encrypt(&XNonce::default(), Payload { msg: file_key, aad: ephemeral.as_bytes() })
+            .map_err(|e| anyhow::anyhow!("wrapping file key failed: {}", e))?;
+
+        let mut wrapped_key = [0u8; 48];
+        wrapped_key.copy_from_slice(&wrapped);
+        Ok(RecipientStanza {
+            ephemeral_public: ephemeral.to_bytes(),
+            wrapped_key,
+        })
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = anyhow::Error;
+
+    /// Parses a `securefs-recipient:<hex>` string
+    fn from_str(s: &str) -> Result<Self> {
+        let encoded = s
+            .trim()
+            .strip_prefix(RECIPIENT_PREFIX)
+            .ok_or_else(|| anyhow::anyhow!("recipient must start with '{}'", RECIPIENT_PREFIX))?;
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(encoded, &mut bytes)
+            .map_err(|_| anyhow::anyhow!("recipient must be 64 hex characters"))?;
+        Ok(Self {
+            public: PublicKey::from(bytes),
+        })
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", RECIPIENT_PREFIX, hex::encode(self.public.as_bytes()))
+    }
+}
+
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recipient({})", self)
+    }
+}
+
+/// Derives the key that wraps a file key for one recipient.
+/// Both public keys are bound in the salt so a stanza can't be replayed to another recipient.
+fn derive_wrap_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> Zeroizing<[u8; 32]> {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared);
+    let mut okm = Zeroizing::new([0u8; 32]);
+    hk.expand(X25519_WRAP_INFO, okm.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+/// Writes secret material to a new file, with mode 0600 on Unix, and
+/// fsyncs it. Fails if the file already exists. Blocking.
+pub fn write_secret_file(path: &Path, contents: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
     }
+    let mut f = options.open(path)?;
+    f.write_all(contents)?;
+    f.sync_all()?;
+    Ok(())
 }
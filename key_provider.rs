@@ -0,0 +1,545 @@
+//! Pluggable key sources.
+//!
+//! This module defines the [`KeyProvider`] trait used by
+//! [`KeyManager`](crate::key_manager::KeyManager) to obtain the master key,
+//! plus implementations for the common ways keys are supplied:
+//!
+//! - [`FileKeyProvider`]: key file on disk (default, supports generation)
+//! - [`EnvKeyProvider`]: hex-encoded key in an environment variable (CI)
+//! - [`StdinKeyProvider`]: key piped on standard input
+//! - [`FdKeyProvider`]: key read from an inherited file descriptor (containers, systemd)
+//! - [`KeyringKeyProvider`]: key in a Linux kernel keyring (see [`crate::keyring`])
+//! - [`CommandKeyProvider`]: key printed by an external helper command (operators)
+//! - [`KeyHelperProvider`]: get/store/erase through a helper protocol (see [`crate::key_helper`])
+//!
+//! Non-file sources accept either a key file (structured or legacy raw) or its
+//! hex encoding. Providers are blocking; `KeyManager` runs them on the blocking pool.
+
+use crate::config::{Config, KeyPermissions, KeySource};
+use crate::error::SecureFsError;
+use crate::key_helper::KeyHelperProvider;
+use crate::keyfile::{KeyFile, KEY_FILE_MAGIC, LEGACY_KEY_LEN};
+use crate::keyring::KeyringKeyProvider;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+/// A source the master key can be loaded from (and optionally stored to)
+pub trait KeyProvider: Send + Sync {
+    /// Human-readable description for logs and error messages
+    fn describe(&self) -> String;
+
+    /// Loads the key. Returns `Ok(None)` if the source holds no key yet.
+    fn load(&self) -> Result<Option<KeyFile>>;
+
+    /// Persists a newly generated key. Read-only sources return an error.
+    fn store(&self, _key: &KeyFile) -> Result<()> {
+        bail!("{} is read-only; cannot store a new key", self.describe())
+    }
+
+    /// Removes the key with `key_id` from the source, if the source supports it
+    fn erase(&self, _key_id: &[u8; 16]) -> Result<()> {
+        bail!("{} does not support erasing keys", self.describe())
+    }
+}
+
+/// Builds the provider selected by `cfg.key_command` or `cfg.key_source`,
+/// defaulting to `cfg.key_path`
+pub fn from_config(cfg: &Config) -> Arc<dyn KeyProvider> {
+    if let Some(command) = &cfg.key_command {
+        return Arc::new(KeyHelperProvider::new(command).with_storage_dir(&cfg.storage_dir));
+    }
+    match &cfg.key_source {
+        None => Arc::new(FileKeyProvider::new(&cfg.key_path).with_permissions(cfg.key_permissions)),
+        Some(KeySource::File { path }) => {
+            Arc::new(FileKeyProvider::new(path).with_permissions(cfg.key_permissions))
+        }
+        Some(KeySource::Env { var }) => Arc::new(EnvKeyProvider::new(var)),
+        Some(KeySource::Stdin) => Arc::new(StdinKeyProvider),
+        Some(KeySource::Fd { fd }) => Arc::new(FdKeyProvider::new(*fd)),
+        Some(KeySource::Keyring { description, keyring, timeout_secs }) => Arc::new(
+            KeyringKeyProvider::new(description)
+                .with_keyring(*keyring)
+                .with_timeout(*timeout_secs),
+        ),
+        Some(KeySource::Command { command, args }) => {
+            Arc::new(CommandKeyProvider::new(command, args.clone()))
+        }
+    }
+}
+
+/// Parses key material from a non-file source.
+/// Accepts a binary key file (structured or legacy raw) or its hex encoding.
+pub fn parse_key_material(data: &[u8]) -> Result<KeyFile> {
+    if data.starts_with(&KEY_FILE_MAGIC) || data.len() == LEGACY_KEY_LEN {
+        return Ok(KeyFile::parse(data)?);
+    }
+
+    let text = std::str::from_utf8(data)
+        .map_err(|_| anyhow::anyhow!("key material is neither a key file nor hex text"))?;
+    let decoded = Zeroizing::new(
+        hex::decode(text.trim())
+            .map_err(|_| anyhow::anyhow!("key material is neither a key file nor hex text"))?,
+    );
+    Ok(KeyFile::parse(&decoded)?)
+}
+
+/// Key file on disk. Creates the file (mode 0600) when storing a new key.
+///
+/// Before loading, the file must be owned by the current user (or root), not be
+/// accessible by group or others, and not be a symlink pointing outside its
+/// own directory; that directory must not be world-writable without the
+/// sticky bit. [`KeyPermissions::Warn`] downgrades violations to warnings.
+pub struct FileKeyProvider {
+    path: PathBuf,
+    permissions: KeyPermissions,
+}
+
+impl FileKeyProvider {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            permissions: KeyPermissions::Strict,
+        }
+    }
+
+    pub fn with_permissions(mut self, permissions: KeyPermissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    fn check_security(&self, reason: Option<String>) -> Result<()> {
+        let Some(reason) = reason else {
+            return Ok(());
+        };
+        match self.permissions {
+            KeyPermissions::Strict => {
+                Err(SecureFsError::insecure_key_file(self.path.display().to_string(), reason).into())
+            }
+            KeyPermissions::Warn => {
+                warn!(path = %self.path.display(), reason = %reason, "loading insecure key file");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Opens the key file at `path`, returning the handle and why it is unsafe
+/// to trust, if it is. `Ok(None)` means there is no key file.
+///
+/// The directory is opened once and the file is opened relative to it with
+/// `O_NOFOLLOW`, so every check runs on the handles that are then read and a
+/// path swapped in between is never followed. A symlink is only resolved
+/// within that directory, and the directory itself must not let anyone
+/// replace its entries.
+#[cfg(unix)]
+fn open_key_file(path: &Path) -> Result<Option<(File, Option<String>)>> {
+    use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} does not name a file", path.display()))?;
+    let dir_path = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let dir = match std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY)
+        .open(dir_path)
+    {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("opening {}", dir_path.display()));
+        }
+    };
+
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let euid = unsafe { libc::geteuid() };
+    let mut reason = None;
+    let dir_meta = dir.metadata().with_context(|| format!("inspecting {}", dir_path.display()))?;
+    if dir_meta.mode() & 0o002 != 0 && dir_meta.mode() & 0o1000 == 0 {
+        reason = Some(format!(
+            "directory {} is world-writable without the sticky bit",
+            dir_path.display()
+        ));
+    }
+
+    let file = match open_at(&dir, name, true) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+            let target = read_link_at(&dir, name)
+                .with_context(|| format!("resolving symlink {}", path.display()))?;
+            // The target names a sibling if its directory is the one already
+            // open; it is then opened through that handle, never by path
+            let target_path = Path::new(&target);
+            let sibling = target_path.file_name().filter(|_| {
+                let parent = dir_path.join(target_path.parent().unwrap_or(Path::new("")));
+                std::fs::metadata(parent)
+                    .is_ok_and(|m| m.dev() == dir_meta.dev() && m.ino() == dir_meta.ino())
+            });
+            let opened = match sibling {
+                Some(sibling) => open_at(&dir, sibling, true),
+                None => {
+                    reason.get_or_insert_with(|| {
+                        format!(
+                            "symlink points to {} outside {}",
+                            target_path.display(),
+                            dir_path.display()
+                        )
+                    });
+                    open_at(&dir, name, false)
+                }
+            };
+            match opened {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+                    reason.get_or_insert_with(|| {
+                        format!("symlink points to another symlink {}", target_path.display())
+                    });
+                    open_at(&dir, name, false)
+                        .with_context(|| format!("opening {}", path.display()))?
+                }
+                Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+            }
+        }
+        Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+    };
+
+    let meta = file.metadata().with_context(|| format!("inspecting {}", path.display()))?;
+    if !meta.is_file() {
+        return Ok(Some((file, Some("not a regular file".to_string()))));
+    }
+    if reason.is_none() && meta.uid() != euid && meta.uid() != 0 {
+        reason = Some(format!(
+            "owned by uid {}, not the current user (uid {})",
+            meta.uid(),
+            euid
+        ));
+    }
+    let mode = meta.mode() & 0o777;
+    if reason.is_none() && mode & 0o077 != 0 {
+        reason = Some(format!(
+            "mode {:04o} allows access by group or others (expected 0600; run `chmod 600 {}`)",
+            mode,
+            path.display()
+        ));
+    }
+    Ok(Some((file, reason)))
+}
+
+/// Opens `name` relative to `dir` for reading, refusing a final symlink
+/// unless `nofollow` is false
+#[cfg(unix)]
+fn open_at(dir: &File, name: &std::ffi::OsStr, nofollow: bool) -> std::io::Result<File> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = std::ffi::CString::new(name.as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // O_NONBLOCK keeps a FIFO planted at the path from blocking the open;
+    // it has no effect on the regular-file reads that follow
+    let mut flags = libc::O_RDONLY | libc::O_CLOEXEC | libc::O_NOCTTY | libc::O_NONBLOCK;
+    if nofollow {
+        flags |= libc::O_NOFOLLOW;
+    }
+    // SAFETY: `dir` is an open descriptor and `name` is NUL-terminated
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and is owned by nothing else
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Reads the target of the symlink `name` in `dir`
+#[cfg(unix)]
+fn read_link_at(dir: &File, name: &std::ffi::OsStr) -> std::io::Result<std::ffi::OsString> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let name = std::ffi::CString::new(name.as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    // SAFETY: `dir` is an open descriptor, `name` is NUL-terminated and `buf`
+    // is writable for its full length
+    let len = unsafe {
+        libc::readlinkat(dir.as_raw_fd(), name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    };
+    if len < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    buf.truncate(len as usize);
+    Ok(std::ffi::OsString::from_vec(buf))
+}
+
+/// Permission checks rely on Unix ownership and modes
+#[cfg(not(unix))]
+fn open_key_file(path: &Path) -> Result<Option<(File, Option<String>)>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some((file, None))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("opening {}", path.display())),
+    }
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn describe(&self) -> String {
+        format!("key file {}", self.path.display())
+    }
+
+    fn load(&self) -> Result<Option<KeyFile>> {
+        let Some((mut file, reason)) = open_key_file(&self.path)? else {
+            return Ok(None);
+        };
+        self.check_security(reason)?;
+        let mut data = Zeroizing::new(Vec::new());
+        file.read_to_end(&mut data)
+            .with_context(|| format!("reading key from {}", self.path.display()))?;
+        let key_file = KeyFile::parse(&data)
+            .with_context(|| format!("loading key from {}", self.path.display()))?;
+        Ok(Some(key_file))
+    }
+
+    fn store(&self, key: &KeyFile) -> Result<()> {
+        crate::key_manager::write_secret_file(&self.path, &key.to_bytes())
+            .with_context(|| format!("writing key to {}", self.path.display()))
+    }
+}
+
+/// Hex-encoded key in an environment variable
+pub struct EnvKeyProvider {
+    var: String,
+}
+
+impl EnvKeyProvider {
+    pub fn new(var: impl Into<String>) -> Self {
+        Self { var: var.into() }
+    }
+}
+
+impl KeyProvider for EnvKeyProvider {
+    fn describe(&self) -> String {
+        format!("environment variable {}", self.var)
+    }
+
+    fn load(&self) -> Result<Option<KeyFile>> {
+        match std::env::var(&self.var) {
+            Ok(value) => {
+                let value = Zeroizing::new(value);
+                parse_key_material(value.as_bytes())
+                    .with_context(|| format!("parsing key from {}", self.describe()))
+                    .map(Some)
+            }
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(e) => bail!("reading {}: {}", self.describe(), e),
+        }
+    }
+}
+
+/// Key piped on standard input. Can only be read once per process.
+pub struct StdinKeyProvider;
+
+impl KeyProvider for StdinKeyProvider {
+    fn describe(&self) -> String {
+        "standard input".to_string()
+    }
+
+    fn load(&self) -> Result<Option<KeyFile>> {
+        let mut data = Zeroizing::new(Vec::new());
+        std::io::stdin()
+            .lock()
+            .read_to_end(&mut data)
+            .context("reading key from standard input")?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        parse_key_material(&data)
+            .context("parsing key from standard input")
+            .map(Some)
+    }
+}
+
+/// Key read from an inherited file descriptor, which is consumed and closed
+pub struct FdKeyProvider {
+    fd: i32,
+}
+
+impl FdKeyProvider {
+    pub fn new(fd: i32) -> Self {
+        Self { fd }
+    }
+}
+
+impl KeyProvider for FdKeyProvider {
+    fn describe(&self) -> String {
+        format!("file descriptor {}", self.fd)
+    }
+
+    #[cfg(unix)]
+    fn load(&self) -> Result<Option<KeyFile>> {
+        use std::os::unix::io::FromRawFd;
+
+        if self.fd < 0 {
+            bail!("invalid file descriptor {}", self.fd);
+        }
+        // SAFETY: the fd is handed to us by the parent process for this purpose;
+        // we take ownership and close it after reading.
+        let mut file = unsafe { std::fs::File::from_raw_fd(self.fd) };
+        let mut data = Zeroizing::new(Vec::new());
+        file.read_to_end(&mut data)
+            .with_context(|| format!("reading key from {}", self.describe()))?;
+        if data.is_empty() {
+            return Ok(None);
+        }
+        parse_key_material(&data)
+            .with_context(|| format!("parsing key from {}", self.describe()))
+            .map(Some)
+    }
+
+    #[cfg(not(unix))]
+    fn load(&self) -> Result<Option<KeyFile>> {
+        bail!("{} is only supported on Unix", self.describe())
+    }
+}
+
+/// Key printed on stdout by an external helper command
+pub struct CommandKeyProvider {
+    command: String,
+    args: Vec<String>,
+}
+
+impl CommandKeyProvider {
+    pub fn new(command: impl Into<String>, args: Vec<String>) -> Self {
+        Self {
+            command: command.into(),
+            args,
+        }
+    }
+}
+
+impl KeyProvider for CommandKeyProvider {
+    fn describe(&self) -> String {
+        format!("key command '{}'", self.command)
+    }
+
+    fn load(&self) -> Result<Option<KeyFile>> {
+        let output = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .with_context(|| format!("running {}", self.describe()))?;
+        let stdout = Zeroizing::new(output.stdout);
+
+        if !output.status.success() {
+            bail!(
+                "{} failed ({}): {}",
+                self.describe(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        if stdout.iter().all(u8::is_ascii_whitespace) {
+            return Ok(None);
+        }
+        parse_key_material(&stdout)
+            .with_context(|| format!("parsing key from {}", self.describe()))
+            .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_binary_and_hex_material() {
+        let key = [0x42u8; 32];
+        let structured = KeyFile::new(key).to_bytes();
+
+        assert_eq!(parse_key_material(&key).expect("raw").key(), &key);
+        assert_eq!(parse_key_material(&structured).expect("structured").key(), &key);
+
+        let hex_raw = format!("{}\n", hex::encode(key));
+        assert_eq!(parse_key_material(hex_raw.as_bytes()).expect("hex raw").key(), &key);
+
+        let hex_structured = hex::encode(&structured[..]);
+        assert_eq!(parse_key_material(hex_structured.as_bytes()).expect("hex structured").key(), &key);
+
+        assert!(parse_key_material(b"not a key").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_provider_enforces_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let path = dir.path().join("key.bin");
+        FileKeyProvider::new(&path).store(&KeyFile::new([0x42u8; 32])).expect("store");
+        assert!(FileKeyProvider::new(&path).load().expect("0600 loads").is_some());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).expect("chmod");
+        let err = FileKeyProvider::new(&path).load().err().expect("0644 must be refused");
+        assert!(matches!(
+            err.downcast_ref::<SecureFsError>(),
+            Some(SecureFsError::InsecureKeyFile { .. })
+        ));
+        let lenient = FileKeyProvider::new(&path).with_permissions(KeyPermissions::Warn);
+        assert!(lenient.load().expect("warn policy loads").is_some());
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).expect("chmod");
+
+        // Symlinks within the key's directory are fine; elsewhere they are not
+        let sibling = dir.path().join("current.key");
+        std::os::unix::fs::symlink(&path, &sibling).expect("symlink");
+        assert!(FileKeyProvider::new(&sibling).load().expect("sibling link").is_some());
+
+        let other = tempfile::TempDir::new().expect("tempdir");
+        let remote = other.path().join("key.bin");
+        std::os::unix::fs::symlink(&path, &remote).expect("symlink");
+        let err = FileKeyProvider::new(&remote).load().err().expect("remote link must be refused");
+        assert!(err.to_string().contains("symlink"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_provider_checks_the_key_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let path = dir.path().join("key.bin");
+        FileKeyProvider::new(&path).store(&KeyFile::new([0x42u8; 32])).expect("store");
+
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o777)).expect("chmod");
+        let err = FileKeyProvider::new(&path).load().err().expect("shared directory must be refused");
+        assert!(err.to_string().contains("world-writable"));
+        // Group-writable (umask 002) and sticky directories are fine
+        for mode in [0o775, 0o1777] {
+            std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(mode)).expect("chmod");
+            assert!(FileKeyProvider::new(&path).load().expect("load").is_some());
+        }
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o700)).expect("chmod");
+
+        // Links are resolved one level, within the directory
+        std::os::unix::fs::symlink("key.bin", dir.path().join("a.key")).expect("symlink");
+        std::os::unix::fs::symlink("a.key", dir.path().join("b.key")).expect("symlink");
+        assert!(FileKeyProvider::new(dir.path().join("a.key")).load().expect("link").is_some());
+        let err = FileKeyProvider::new(dir.path().join("b.key")).load().err().expect("chain");
+        assert!(err.to_string().contains("symlink"));
+
+        std::os::unix::fs::symlink("missing.key", dir.path().join("dangling.key")).expect("symlink");
+        assert!(FileKeyProvider::new(dir.path().join("dangling.key")).load().expect("load").is_none());
+    }
+
+    #[test]
+    fn file_provider_reports_missing_key() {
+        let provider = FileKeyProvider::new("/nonexistent/securefs-test.key");
+        assert!(provider.load().expect("load").is_none());
+    }
+}
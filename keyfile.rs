@@ -0,0 +1,249 @@
+//! Versioned, self-describing master key file format.
+//!
+//! This module provides [`KeyFile`] for encoding and validating master key
+//! files, so that a corrupted or wrong-type file is rejected with a clear
+//! error instead of being misused as key material.
+//!
+//! ## Format (V1, 100 bytes)
+//!
+//! ```text
+//! [magic:8][version:1][algorithm:1][reserved:2][key_id:16][created_at:8][key:32][checksum:32]
+//! ```
+//!
+//! - `magic` is `SFS-KEY\0`
+//! - `key_id` is derived from the key (see [`key_id_for`])
+//! - `created_at` is Unix seconds, big-endian
+//! - `checksum` is SHA-256 over all preceding bytes
+//!
+//! Legacy key files (exactly 32 raw bytes) are still accepted.
+
+use crate::error::SecureFsError;
+use crate::secret::LockedBox;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+/// Magic bytes at the start of every structured key file
+pub const KEY_FILE_MAGIC: [u8; 8] = *b"SFS-KEY\0";
+
+/// Current key file format version
+pub const KEY_FILE_VERSION: u8 = 1;
+
+/// Size of a V1 key file in bytes
+pub const KEY_FILE_LEN: usize = 100;
+
+/// Size of a legacy raw key file in bytes
+pub const LEGACY_KEY_LEN: usize = 32;
+
+const CHECKSUM_OFFSET: usize = KEY_FILE_LEN - 32;
+
+/// Domain separation prefix for key IDs
+const KEY_ID_CONTEXT: &[u8] = b"securefs/key-id/v1";
+
+/// Algorithm the key is intended for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    XChaCha20Poly1305,
+}
+
+impl KeyAlgorithm {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::XChaCha20Poly1305 => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed master key file
+pub struct KeyFile {
+    pub key_id: [u8; 16],
+    pub created_at: u64,
+    pub algorithm: KeyAlgorithm,
+    /// True if loaded from a legacy 32-byte raw key file
+    pub legacy: bool,
+    key: LockedBox<[u8; 32]>,
+}
+
+impl KeyFile {
+    /// Wraps a freshly generated key, stamped with the current time.
+    /// The caller's copy of `key` is left as is; prefer [`KeyFile::from_locked`].
+    pub fn new(key: [u8; 32]) -> Self {
+        Self::from_locked(LockedBox::from_slice(&key))
+    }
+
+    /// Like [`KeyFile::new`], for a key already in locked memory
+    pub fn from_locked(key: LockedBox<[u8; 32]>) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            key_id: key_id_for(&key),
+            created_at,
+            algorithm: KeyAlgorithm::XChaCha20Poly1305,
+            legacy: false,
+            key,
+        }
+    }
+
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    /// Parses a structured or legacy key file
+    pub fn parse(data: &[u8]) -> Result<Self, SecureFsError> {
+        if !data.starts_with(&KEY_FILE_MAGIC) {
+            if data.len() == LEGACY_KEY_LEN {
+                let key = LockedBox::from_fn(|key| key.copy_from_slice(data));
+                return Ok(Self {
+                    key_id: key_id_for(&key),
+                    created_at: 0,
+                    algorithm: KeyAlgorithm::XChaCha20Poly1305,
+                    legacy: true,
+                    key,
+                });
+            }
+            return Err(SecureFsError::key(describe_foreign(data)));
+        }
+
+        if data.len() < KEY_FILE_MAGIC.len() + 1 {
+            return Err(SecureFsError::key("key file is truncated"));
+        }
+        let version = data[8];
+        if version != KEY_FILE_VERSION {
+            return Err(SecureFsError::key(format!(
+                "unsupported key file version {} (expected {})",
+                version, KEY_FILE_VERSION
+            )));
+        }
+        if data.len() != KEY_FILE_LEN {
+            return Err(SecureFsError::key(format!(
+                "key file is corrupted: expected {} bytes but found {}",
+                KEY_FILE_LEN,
+                data.len()
+            )));
+        }
+
+        let checksum = Sha256::digest(&data[..CHECKSUM_OFFSET]);
+        if checksum.as_slice() != &data[CHECKSUM_OFFSET..] {
+            return Err(SecureFsError::key("key file is corrupted: checksum mismatch"));
+        }
+
+        let algorithm = KeyAlgorithm::from_byte(data[9]).ok_or_else(|| {
+            SecureFsError::key(format!("key file uses unknown algorithm id {}", data[9]))
+        })?;
+
+        let mut key_id = [0u8; 16];
+        key_id.copy_from_slice(&data[12..28]);
+        let mut created = [0u8; 8];
+        created.copy_from_slice(&data[28..36]);
+        let key = LockedBox::from_fn(|key| key.copy_from_slice(&data[36..68]));
+
+        if key_id != key_id_for(&key) {
+            return Err(SecureFsError::key("key file is corrupted: key ID does not match key"));
+        }
+
+        Ok(Self {
+            key_id,
+            created_at: u64::from_be_bytes(created),
+            algorithm,
+            legacy: false,
+            key,
+        })
+    }
+
+    /// Serializes to the current (V1) key file format
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(Vec::with_capacity(KEY_FILE_LEN));
+        out.extend_from_slice(&KEY_FILE_MAGIC);
+        out.push(KEY_FILE_VERSION);
+        out.push(self.algorithm.to_byte());
+        out.extend_from_slice(&[0u8; 2]);
+        out.extend_from_slice(&self.key_id);
+        out.extend_from_slice(&self.created_at.to_be_bytes());
+        out.extend_from_slice(&self.key[..]);
+        let checksum = Sha256::digest(&out[..]);
+        out.extend_from_slice(&checksum);
+        out
+    }
+}
+
+/// Derives the public key ID for a key.
+/// Deterministic, so legacy raw keys get the same ID once upgraded.
+pub fn key_id_for(key: &[u8; 32]) -> [u8; 16] {
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_ID_CONTEXT);
+    hasher.update(key);
+    let digest = hasher.finalize();
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&digest[..16]);
+    id
+}
+
+/// Explains what a non-key file looks like, for clearer errors
+fn describe_foreign(data: &[u8]) -> String {
+    let text = std::str::from_utf8(data).unwrap_or("");
+    if text.contains(crate::key_manager::IDENTITY_PREFIX) {
+        "file is a recipient identity, not a master key".to_string()
+    } else if data.is_empty() {
+        "key file is empty".to_string()
+    } else {
+        format!(
+            "not a SecureFS key file ({} bytes, no key file header and not a 32-byte legacy key)",
+            data.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let kf = KeyFile::new([0x42u8; 32]);
+        let bytes = kf.to_bytes();
+        assert_eq!(bytes.len(), KEY_FILE_LEN);
+
+        let parsed = KeyFile::parse(&bytes).expect("parse");
+        assert_eq!(parsed.key(), &[0x42u8; 32]);
+        assert_eq!(parsed.key_id, kf.key_id);
+        assert_eq!(parsed.created_at, kf.created_at);
+        assert!(!parsed.legacy);
+    }
+
+    #[test]
+    fn legacy_raw_key_loads() {
+        let parsed = KeyFile::parse(&[0x42u8; 32]).expect("parse legacy");
+        assert!(parsed.legacy);
+        assert_eq!(parsed.key_id, key_id_for(&[0x42u8; 32]));
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let kf = KeyFile::new([0x42u8; 32]);
+        let mut bytes = kf.to_bytes().to_vec();
+        bytes[40] ^= 0xff;
+        let err = KeyFile::parse(&bytes).err().expect("must fail");
+        assert!(err.to_string().contains("checksum mismatch"));
+
+        let err = KeyFile::parse(&bytes[..50]).err().expect("must fail");
+        assert!(err.to_string().contains("corrupted"));
+    }
+
+    #[test]
+    fn rejects_wrong_type() {
+        let err = KeyFile::parse(b"securefs-identity:abcd\n").err().expect("must fail");
+        assert!(err.to_string().contains("recipient identity"));
+
+        let err = KeyFile::parse(b"hello").err().expect("must fail");
+        assert!(err.to_string().contains("not a SecureFS key file"));
+    }
+}
@@ -0,0 +1,300 @@
+//! Linux kernel keyring key source.
+//!
+//! This module provides [`KeyringKeyProvider`], which keeps the master key in
+//! the kernel keyring (as a `user` type key) instead of a file on disk.
+//!
+//! ## Keyrings
+//!
+//! - `user`: shared by all sessions of the current user (default)
+//! - `session`: scoped to the current login session
+//! - `persistent`: per-user keyring that outlives sessions (`keyctl get_persistent`)
+//!
+//! Keys are stored in the structured key file format (see [`crate::keyfile`])
+//! and can be given a timeout after which the kernel expires them.
+//! On non-Linux platforms every operation fails with a clear error.
+
+use crate::key_provider::KeyProvider;
+use crate::keyfile::KeyFile;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::{debug, info};
+
+/// Default key description used by the CLI
+pub const DEFAULT_DESCRIPTION: &str = "securefs:master";
+
+/// Which kernel keyring holds the key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyringKind {
+    #[default]
+    User,
+    Session,
+    Persistent,
+}
+
+impl fmt::Display for KeyringKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::User => "user",
+            Self::Session => "session",
+            Self::Persistent => "persistent",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for KeyringKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "user" => Ok(Self::User),
+            "session" => Ok(Self::Session),
+            "persistent" => Ok(Self::Persistent),
+            other => anyhow::bail!("unknown keyring '{}' (expected user, session or persistent)", other),
+        }
+    }
+}
+
+/// Master key held in a Linux kernel keyring, looked up by description
+pub struct KeyringKeyProvider {
+    description: String,
+    keyring: KeyringKind,
+    timeout_secs: Option<u32>,
+}
+
+impl KeyringKeyProvider {
+    pub fn new(description: impl Into<String>) -> Self {
+        Self {
+            description: description.into(),
+            keyring: KeyringKind::default(),
+            timeout_secs: None,
+        }
+    }
+
+    pub fn with_keyring(mut self, keyring: KeyringKind) -> Self {
+        self.keyring = keyring;
+        self
+    }
+
+    /// Expire stored keys after `secs` seconds
+    pub fn with_timeout(mut self, secs: Option<u32>) -> Self {
+        self.timeout_secs = secs;
+        self
+    }
+}
+
+impl KeyProvider for KeyringKeyProvider {
+    fn describe(&self) -> String {
+        format!("{} keyring key '{}'", self.keyring, self.description)
+    }
+
+    fn load(&self) -> Result<Option<KeyFile>> {
+        let ring = sys::keyring_id(self.keyring)
+            .with_context(|| format!("opening {} keyring", self.keyring))?;
+        let Some(key) = sys::search(ring, &self.description)
+            .with_context(|| format!("searching {}", self.describe()))?
+        else {
+            debug!(source = %self.describe(), "no key in keyring");
+            return Ok(None);
+        };
+        let payload = sys::read(key).with_context(|| format!("reading {}", self.describe()))?;
+        let key_file = KeyFile::parse(&payload)
+            .with_context(|| format!("parsing {}", self.describe()))?;
+        Ok(Some(key_file))
+    }
+
+    /// Adds the key, replacing any existing key with the same description
+    fn store(&self, key: &KeyFile) -> Result<()> {
+        let ring = sys::keyring_id(self.keyring)
+            .with_context(|| format!("opening {} keyring", self.keyring))?;
+        let serial = sys::add(ring, &self.description, &key.to_bytes())
+            .with_context(|| format!("storing {}", self.describe()))?;
+        if let Some(secs) = self.timeout_secs {
+            sys::set_timeout(serial, secs)
+                .with_context(|| format!("setting timeout on {}", self.describe()))?;
+        }
+        info!(source = %self.describe(), serial, timeout_secs = ?self.timeout_secs, "stored key in kernel keyring");
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::KeyringKind;
+    use std::ffi::CString;
+    use std::io;
+    use zeroize::Zeroizing;
+
+    const KEY_TYPE: &str = "user";
+
+    fn cstring(s: &str) -> io::Result<CString> {
+        CString::new(s).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "key description contains NUL"))
+    }
+
+    fn check(ret: libc::c_long) -> io::Result<libc::c_long> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    pub fn keyring_id(kind: KeyringKind) -> io::Result<i32> {
+        match kind {
+            KeyringKind::User => Ok(libc::KEY_SPEC_USER_KEYRING),
+            KeyringKind::Session => Ok(libc::KEY_SPEC_SESSION_KEYRING),
+            KeyringKind::Persistent => {
+                // SAFETY: KEYCTL_GET_PERSISTENT takes plain integer arguments
+                let ret = unsafe {
+                    libc::syscall(
+                        libc::SYS_keyctl,
+                        libc::KEYCTL_GET_PERSISTENT as libc::c_long,
+                        -1 as libc::c_long,
+                        libc::KEY_SPEC_PROCESS_KEYRING as libc::c_long,
+                    )
+                };
+                check(ret).map(|id| id as i32)
+            }
+        }
+    }
+
+    /// Returns `None` if no usable key with this description is present
+    pub fn search(ring: i32, description: &str) -> io::Result<Option<i32>> {
+        let ty = cstring(KEY_TYPE)?;
+        let desc = cstring(description)?;
+        // SAFETY: both strings are valid NUL-terminated C strings for the call's duration
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                libc::KEYCTL_SEARCH as libc::c_long,
+                ring as libc::c_long,
+                ty.as_ptr(),
+                desc.as_ptr(),
+                0 as libc::c_long,
+            )
+        };
+        match check(ret) {
+            Ok(id) => Ok(Some(id as i32)),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOKEY | libc::EKEYEXPIRED | libc::EKEYREVOKED)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn read(key: i32) -> io::Result<Zeroizing<Vec<u8>>> {
+        let mut buf = Zeroizing::new(vec![0u8; 256]);
+        loop {
+            // SAFETY: buf is valid for writes of buf.len() bytes
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_keyctl,
+                    libc::KEYCTL_READ as libc::c_long,
+                    key as libc::c_long,
+                    buf.as_mut_ptr(),
+                    buf.len() as libc::c_long,
+                )
+            };
+            let len = check(ret)? as usize;
+            if len <= buf.len() {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            buf = Zeroizing::new(vec![0u8; len]);
+        }
+    }
+
+    pub fn add(ring: i32, description: &str, payload: &[u8]) -> io::Result<i32> {
+        let ty = cstring(KEY_TYPE)?;
+        let desc = cstring(description)?;
+        // SAFETY: strings are valid C strings and payload is valid for payload.len() bytes
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_add_key,
+                ty.as_ptr(),
+                desc.as_ptr(),
+                payload.as_ptr(),
+                payload.len(),
+                ring as libc::c_long,
+            )
+        };
+        check(ret).map(|id| id as i32)
+    }
+
+    pub fn set_timeout(key: i32, secs: u32) -> io::Result<()> {
+        // SAFETY: KEYCTL_SET_TIMEOUT takes plain integer arguments
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                libc::KEYCTL_SET_TIMEOUT as libc::c_long,
+                key as libc::c_long,
+                secs as libc::c_long,
+            )
+        };
+        check(ret).map(|_| ())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::KeyringKind;
+    use std::io;
+    use zeroize::Zeroizing;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "kernel keyrings are only available on Linux")
+    }
+
+    pub fn keyring_id(_kind: KeyringKind) -> io::Result<i32> {
+        Err(unsupported())
+    }
+
+    pub fn search(_ring: i32, _description: &str) -> io::Result<Option<i32>> {
+        Err(unsupported())
+    }
+
+    pub fn read(_key: i32) -> io::Result<Zeroizing<Vec<u8>>> {
+        Err(unsupported())
+    }
+
+    pub fn add(_ring: i32, _description: &str, _payload: &[u8]) -> io::Result<i32> {
+        Err(unsupported())
+    }
+
+    pub fn set_timeout(_key: i32, _secs: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_and_load_round_trip() {
+        let description = format!("securefs:test:{}", std::process::id());
+        let provider = KeyringKeyProvider::new(&description)
+            .with_keyring(KeyringKind::Session)
+            .with_timeout(Some(60));
+
+        let key_file = KeyFile::new([0x42u8; 32]);
+        if let Err(e) = provider.store(&key_file) {
+            // Containers and CI sandboxes often block keyctl entirely
+            eprintln!("skipping keyring test: {:#}", e);
+            return;
+        }
+
+        let loaded = provider.load().expect("load").expect("key present");
+        assert_eq!(loaded.key(), &[0x42u8; 32]);
+
+        let missing = KeyringKeyProvider::new(format!("{}:missing", description))
+            .with_keyring(KeyringKind::Session);
+        assert!(missing.load().expect("search").is_none());
+    }
+
+    #[test]
+    fn parses_keyring_kind() {
+        assert_eq!("persistent".parse::<KeyringKind>().unwrap(), KeyringKind::Persistent);
+        assert!("nope".parse::<KeyringKind>().is_err());
+    }
+}
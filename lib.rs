@@ -8,7 +8,7 @@
 //! - **XChaCha20-Poly1305**: Extended-nonce authenticated encryption
 //! - **Streaming API**: Process large files without loading into memory
 //! - **Compression**: Optional gzip compression before encryption
-//! - **Secure Key Management**: Automatic zeroization and Unix permissions
+//! - **Secure Key Management**: Locked, zeroized key memory and Unix permissions
 //! - **Format Detection**: Auto-detect V1 (buffer) and V2 (streaming) formats
 //!
 //! ## Quick Start
@@ -19,8 +19,8 @@
 //! #[tokio::main]
 //! async fn main() -> anyhow::Result<()> {
 //!     let cfg = Config::new("./key.bin", "./storage");
-//!     let km = KeyManager::new(&cfg).await?;
-//!     let ops = SecureFileOps::new(km, &cfg.storage_dir);
+//!     let km = KeyManager::open(&cfg).await?;
+//!     let ops = SecureFileOps::new(km, &cfg.storage_dir).await?;
 //!
 //!     // Encrypt data
 //!     ops.write_encrypted("secret.txt", b"sensitive data").await?;
@@ -39,11 +39,26 @@
 pub mod config;
 pub mod encryptor;
 pub mod error;
+pub mod file_keys;
+pub mod history;
+pub mod key_helper;
 pub mod key_manager;
+pub mod key_provider;
+pub mod keyfile;
+pub mod keyring;
 pub mod metadata;
+pub mod mnemonic;
+pub mod name_cipher;
+pub mod rotation;
+pub mod secret;
+pub mod shamir;
+pub mod storage_name;
 pub mod storagefile_ops;
 pub mod streaming;
+pub mod trash;
 pub mod util;
+pub mod vault;
+pub mod write_mode;
 
 // Re-export common types for convenience
 pub use error::SecureFsError;
@@ -1,26 +1,478 @@
-use anyhow::Result;
+//! Encrypted, authenticated file metadata.
+//!
+//! Every file written with the master key gets a metadata sidecar, sealed
+//! with [`MetadataKey`] so it can be neither read nor edited without the key.
+//! Files written by keyless producers (see
+//! [`SecureFileOps::keyless`](crate::storagefile_ops::SecureFileOps::keyless))
+//! have no sidecar.
+//!
+//! ## Format
+//!
+//! Sidecars live in the [`METADATA_DIR`] directory of the storage root, at
+//! the same relative path as their encrypted file (`docs/report.pdf` has
+//! `.securefs-meta/docs/report.pdf`), so every file has its own:
+//!
+//! ```json
+//! {
+//!   "format_version": 1,
+//!   "nonce": "<hex>",
+//!   "sealed": "<hex>"
+//! }
+//! ```
+//!
+//! `sealed` is the XChaCha20-Poly1305 encryption, under the
+//! [`subkey::METADATA`] subkey, of:
+//!
+//! ```json
+//! {
+//!   "filename": "docs/report.pdf",
+//!   "size": 1234,
+//!   "created": { "secs_since_epoch": 1792300000, "nanos_since_epoch": 0 },
+//!   "modified": { "secs_since_epoch": 1792310000, "nanos_since_epoch": 0 },
+//!   "source_path": "/home/me/report.pdf",
+//!   "content_type": "application/pdf",
+//!   "sha256": "<hex>",
+//!   "stored_size": 1301,
+//!   "tags": { "project": "apollo" },
+//!   "content": "<hex>"
+//! }
+//! ```
+//!
+//! Every field after `size` is optional; sidecars written before they
+//! existed open with them unset. Tags and the source path can be changed
+//! without rewriting the file (see
+//! [`SecureFileOps::update_tags`](crate::storagefile_ops::SecureFileOps::update_tags)).
+//!
+//! - The storage name is the AAD, so a sidecar can't be moved to another file.
+//! - `content` is the SHA-256 of the first [`BINDING_LEN`] bytes of the
+//!   encrypted file, which start with its random nonce. A sidecar therefore
+//!   only matches the write it was created for, not an older or newer
+//!   version of the same file.
+//!
+//! Sidecars are replaced atomically, after their file. A crash in between
+//! leaves the previous sidecar, which then fails the binding check rather
+//! than describing the wrong contents.
+//!
+//! Earlier versions wrote plain JSON to `<file>.meta.json` next to each file,
+//! where `report.txt` and `report.pdf` shared one sidecar. Those files are
+//! no longer trusted. Listings recognize them by their contents and skip them,
+//! so a stored file whose own name ends in `.meta.json` is still listed, and
+//! [`SecureFileOps::migrate_legacy_metadata`](crate::storagefile_ops::SecureFileOps::migrate_legacy_metadata)
+//! seals them into this format and deletes them.
+
+use crate::error::SecureFsError;
+use crate::key_manager::{subkey, KeyManager};
+use crate::secret::SharedCipher;
+use crate::util::AtomicFile;
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::SystemTime;
 use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use zeroize::Zeroizing;
+
+/// Directory in the storage root holding the sidecars
+pub const METADATA_DIR: &str = ".securefs-meta";
+
+/// Current sidecar format version
+pub const METADATA_FORMAT_VERSION: u32 = 1;
+
+/// Number of leading bytes of the encrypted file a sidecar is bound to
+pub const BINDING_LEN: usize = 64;
+
+const AAD_DOMAIN: &[u8] = b"securefs/metadata/v1\0";
+
+/// Leading plaintext bytes inspected to detect the content type
+const SNIFF_LEN: usize = 8192;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Suffix of the plaintext sidecars earlier versions wrote next to each file
+pub const LEGACY_SIDECAR_SUFFIX: &str = ".meta.json";
+
+/// Largest file read as a possible legacy sidecar; they hold a name and a size
+const LEGACY_SIDECAR_MAX_LEN: u64 = 64 * 1024;
+
+/// What SecureFS records about a stored file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileMetadata {
+    /// Storage name of the file
     pub filename: String,
+    /// Plaintext size in bytes
     pub size: u64,
+    /// When the file was first written under this name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<SystemTime>,
+    /// When the current version was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<SystemTime>,
+    /// Path the file was stored from, if recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_path: Option<String>,
+    /// MIME type detected from the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Hex SHA-256 of the plaintext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Size in bytes of the encrypted file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_size: Option<u64>,
+    /// User key/value tags
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
 }
 
 impl FileMetadata {
-    pub async fn record(path: &Path, size: u64) -> Result<()> {
-        let filename = path
-            .file_name()
-            .ok_or_else(|| anyhow::anyhow!("path has no filename: {}", path.display()))?
-            .to_string_lossy()
-            .into_owned();
-
-        let meta = Self { filename, size };
-        let json = serde_json::to_string_pretty(&meta)?;
-        let meta_path = path.with_extension("meta.json");
-        fs::write(meta_path, json).await?;
-        Ok(())
+    /// Stored size over plaintext size, encryption overhead included;
+    /// below 1 when compression paid off
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match (self.stored_size, self.size) {
+            (Some(stored), size) if size > 0 => Some(stored as f64 / size as f64),
+            _ => None,
+        }
+    }
+}
+
+/// Size, digest and content type of plaintext, gathered as it is written
+#[derive(Default)]
+pub(crate) struct ContentDigest {
+    size: u64,
+    hasher: Sha256,
+    head: Vec<u8>,
+}
+
+/// What a write recorded about its plaintext (see [`ContentDigest`])
+pub(crate) struct Digested {
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: String,
+}
+
+impl ContentDigest {
+    pub fn of(data: &[u8]) -> Digested {
+        let mut digest = Self::default();
+        digest.update(data);
+        digest.finish()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        self.hasher.update(data);
+        let take = SNIFF_LEN.saturating_sub(self.head.len()).min(data.len());
+        self.head.extend_from_slice(&data[..take]);
+    }
+
+    pub fn finish(self) -> Digested {
+        Digested {
+            size: self.size,
+            sha256: hex::encode(self.hasher.finalize()),
+            content_type: content_type(&self.head).to_string(),
+        }
+    }
+}
+
+/// MIME type of content starting with `head`: known magic numbers first,
+/// then UTF-8 text, then opaque bytes
+fn content_type(head: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type();
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => "text/plain",
+        // `head` can end part way through a character
+        Err(e) if e.error_len().is_none() && head.len() == SNIFF_LEN => "text/plain",
+        Err(_) => "application/octet-stream",
+    }
+}
+
+/// Reader feeding everything read through it to a [`ContentDigest`]
+pub(crate) struct DigestReader<'a, R> {
+    inner: &'a mut R,
+    digest: ContentDigest,
+}
+
+impl<'a, R> DigestReader<'a, R> {
+    pub fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            digest: ContentDigest::default(),
+        }
+    }
+
+    pub fn finish(self) -> Digested {
+        self.digest.finish()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DigestReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let this = &mut *self;
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, buf))?;
+        this.digest.update(&buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    format_version: u32,
+    nonce: String,
+    sealed: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    #[serde(flatten)]
+    metadata: FileMetadata,
+    content: String,
+}
+
+/// Seals and opens metadata sidecars
+#[derive(Clone)]
+pub struct MetadataKey {
+    cipher: SharedCipher,
+}
+
+impl MetadataKey {
+    pub fn new(km: &KeyManager) -> Self {
+        Self {
+            cipher: km.derive_subkey(subkey::METADATA),
+        }
+    }
+
+    /// Seals `metadata` for the file `name` whose encrypted contents start with `header`
+    pub fn seal(&self, name: &str, metadata: &FileMetadata, header: &[u8]) -> Result<Vec<u8>> {
+        let sealed = Sealed {
+            metadata: metadata.clone(),
+            content: content_binding(header),
+        };
+        let plaintext = Zeroizing::new(serde_json::to_vec(&sealed)?);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad(name),
+                },
+            )
+            .map_err(|_| SecureFsError::encryption("sealing metadata failed"))?;
+        let sidecar = Sidecar {
+            format_version: METADATA_FORMAT_VERSION,
+            nonce: hex::encode(nonce),
+            sealed: hex::encode(ciphertext),
+        };
+        Ok(serde_json::to_vec_pretty(&sidecar)?)
+    }
+
+    /// Opens a sidecar for `name`, checking it belongs to the file starting with `header`
+    pub fn open(&self, name: &str, sidecar: &[u8], header: &[u8]) -> Result<FileMetadata, SecureFsError> {
+        let sealed = self.unseal(name, sidecar)?;
+        if sealed.content != content_binding(header) {
+            return Err(SecureFsError::decryption(format!(
+                "metadata for {:?} belongs to a different version of the file",
+                name
+            )));
+        }
+        Ok(sealed.metadata)
+    }
+
+    /// Opens a sidecar for `name` without checking which version of the file
+    /// it was written for. Used to carry metadata over when a file is
+    /// re-encrypted (see [`crate::rotation`]).
+    pub fn open_unbound(&self, name: &str, sidecar: &[u8]) -> Result<FileMetadata, SecureFsError> {
+        Ok(self.unseal(name, sidecar)?.metadata)
+    }
+
+    /// Seals `metadata` for the encrypted file at `path` under `root` and writes its sidecar
+    pub async fn write(&self, root: &Path, path: &Path, name: &str, metadata: &FileMetadata) -> Result<()> {
+        let header = content_header(path).await?;
+        let sidecar = self.seal(name, metadata, &header)?;
+        let meta_path = sidecar_path(root, path);
+        if let Some(dir) = meta_path.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("creating metadata directory {:?}", dir))?;
+        }
+        let mut file = AtomicFile::create(&meta_path).await?;
+        file.file()
+            .write_all(&sidecar)
+            .await
+            .with_context(|| format!("writing metadata {:?}", &meta_path))?;
+        file.commit().await
+    }
+
+    /// Reads and opens the sidecar of the encrypted file at `path` under `root`
+    pub async fn read(&self, root: &Path, path: &Path, name: &str) -> Result<FileMetadata> {
+        let meta_path = sidecar_path(root, path);
+        let sidecar = fs::read(&meta_path)
+            .await
+            .with_context(|| format!("reading metadata from {:?}", &meta_path))?;
+        let header = content_header(path).await?;
+        Ok(self.open(name, &sidecar, &header)?)
+    }
+
+    fn unseal(&self, name: &str, sidecar: &[u8]) -> Result<Sealed, SecureFsError> {
+        let sidecar: Sidecar = match serde_json::from_slice(sidecar) {
+            Ok(sidecar) => sidecar,
+            Err(_) if serde_json::from_slice::<FileMetadata>(sidecar).is_ok() => {
+                return Err(SecureFsError::format(format!(
+                    "metadata for {:?} is unauthenticated plaintext from an older version; \
+                     migrate it with `securefs migrate-metadata` or write the file again",
+                    name
+                )))
+            }
+            Err(e) => return Err(SecureFsError::format(format!("parsing metadata for {:?}: {}", name, e))),
+        };
+        if sidecar.format_version > METADATA_FORMAT_VERSION {
+            return Err(SecureFsError::format(format!(
+                "metadata format version {} is newer than this version of securefs supports ({})",
+                sidecar.format_version, METADATA_FORMAT_VERSION
+            )));
+        }
+        let nonce = hex::decode(&sidecar.nonce)
+            .ok()
+            .filter(|n| n.len() == 24)
+            .ok_or_else(|| SecureFsError::format(format!("metadata nonce for {:?} is invalid", name)))?;
+        let ciphertext = hex::decode(&sidecar.sealed)
+            .map_err(|_| SecureFsError::format(format!("metadata for {:?} is not valid hex", name)))?;
+        let plaintext = Zeroizing::new(
+            self.cipher
+                .decrypt(
+                    XNonce::from_slice(&nonce),
+                    Payload {
+                        msg: &ciphertext,
+                        aad: &aad(name),
+                    },
+                )
+                .map_err(|_| {
+                    SecureFsError::decryption(format!(
+                        "metadata for {:?} failed authentication (modified, moved or wrong key)",
+                        name
+                    ))
+                })?,
+        );
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| SecureFsError::format(format!("parsing sealed metadata for {:?}: {}", name, e)))
+    }
+}
+
+/// A plaintext sidecar written next to its file by an earlier version
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LegacySidecar {
+    /// File name (not path) of the file it describes
+    pub filename: String,
+    pub size: u64,
+}
+
+/// Reads the file at `path` as a legacy sidecar, or `None` if it isn't one.
+/// A stored file that merely ends in `.meta.json` is encrypted, so it never
+/// parses as one.
+pub(crate) async fn read_legacy(path: &Path) -> Option<LegacySidecar> {
+    let name = path.file_name()?.to_str()?;
+    if !name.ends_with(LEGACY_SIDECAR_SUFFIX) {
+        return None;
+    }
+    let meta = fs::symlink_metadata(path).await.ok()?;
+    if !meta.is_file() || meta.len() > LEGACY_SIDECAR_MAX_LEN {
+        return None;
+    }
+    serde_json::from_slice(&fs::read(path).await.ok()?).ok()
+}
+
+/// Location of the sidecar for the encrypted file at `path` under `root`
+pub fn sidecar_path(root: &Path, path: &Path) -> PathBuf {
+    let relative = path
+        .strip_prefix(root)
+        .expect("BUG: stored files always lie under the storage root");
+    root.join(METADATA_DIR).join(relative)
+}
+
+/// The leading bytes of the encrypted file at `path` that a sidecar is bound to
+pub async fn content_header(path: &Path) -> Result<Vec<u8>> {
+    let file = fs::File::open(path)
+        .await
+        .with_context(|| format!("opening {:?}", path))?;
+    let mut header = Vec::with_capacity(BINDING_LEN);
+    file.take(BINDING_LEN as u64)
+        .read_to_end(&mut header)
+        .await
+        .with_context(|| format!("reading {:?}", path))?;
+    Ok(header)
+}
+
+fn content_binding(header: &[u8]) -> String {
+    hex::encode(Sha256::digest(&header[..header.len().min(BINDING_LEN)]))
+}
+
+fn aad(name: &str) -> Vec<u8> {
+    [AAD_DOMAIN, name.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyfile::KeyFile;
+
+    fn key(byte: u8) -> MetadataKey {
+        MetadataKey::new(&KeyManager::from_key_file(&KeyFile::new([byte; 32])))
+    }
+
+    #[test]
+    fn sealed_metadata_is_bound_to_name_and_contents() {
+        let key = key(0x42);
+        let metadata = FileMetadata {
+            filename: "docs/report.pdf".into(),
+            size: 1234,
+            tags: BTreeMap::from([("project".into(), "apollo".into())]),
+            ..Default::default()
+        };
+        let sidecar = key.seal("docs/report.pdf", &metadata, b"header one").expect("seal");
+        let text = String::from_utf8_lossy(&sidecar);
+        assert!(!text.contains("report") && !text.contains("1234"));
+
+        assert_eq!(key.open("docs/report.pdf", &sidecar, b"header one").expect("open"), metadata);
+        assert!(key.open("docs/other.pdf", &sidecar, b"header one").is_err());
+        assert!(key.open("docs/report.pdf", &sidecar, b"header two").is_err());
+        assert_eq!(key.open_unbound("docs/report.pdf", &sidecar).expect("open"), metadata);
+        assert!(self::key(0x24).open("docs/report.pdf", &sidecar, b"header one").is_err());
+
+        let mut tampered = sidecar.clone();
+        let at = text.rfind('"').expect("quoted") - 1;
+        tampered[at] = if tampered[at] == b'0' { b'1' } else { b'0' };
+        assert!(key.open("docs/report.pdf", &tampered, b"header one").is_err());
+    }
+
+    #[test]
+    fn digest_detects_content_type() {
+        let png = ContentDigest::of(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR");
+        assert_eq!(png.content_type, "image/png");
+        assert_eq!(png.size, 16);
+        assert_eq!(ContentDigest::of("plain text, ünïcode".as_bytes()).content_type, "text/plain");
+        assert_eq!(ContentDigest::of(&[0xff, 0xfe, 0x00, 0x81]).content_type, "application/octet-stream");
+        assert_eq!(
+            ContentDigest::of(b"abc").sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn legacy_plaintext_sidecars_are_not_trusted() {
+        let legacy = br#"{ "filename": "a.txt", "size": 3 }"#;
+        let err = key(0x42).open("a.txt", legacy, b"").expect_err("plaintext must be rejected");
+        assert!(err.to_string().contains("unauthenticated"));
     }
 }
@@ -0,0 +1,167 @@
+//! Paper backups of the master key.
+//!
+//! This module renders a 32-byte key in two hand-copyable forms, used by
+//! [`KeyManager::export_mnemonic`](crate::key_manager::KeyManager::export_mnemonic),
+//! [`KeyManager::export_paper_key`](crate::key_manager::KeyManager::export_paper_key)
+//! and [`KeyManager::import_backup`](crate::key_manager::KeyManager::import_backup):
+//!
+//! - **Mnemonic**: 24 words from the BIP39 English word list (the last word
+//!   carries an 8-bit checksum)
+//! - **Paper key**: base32 text in dash-separated groups of five characters
+//!
+//! ## Paper Key Format
+//!
+//! ```text
+//! [version:1][key:32][checksum:4]
+//! ```
+//!
+//! - `checksum` is the first 4 bytes of SHA-256 over the preceding bytes
+//! - Encoded as unpadded RFC 4648 base32 (60 characters); parsing ignores case,
+//!   spaces and dashes
+//!
+//! Neither form carries the key ID. Callers print it on export so the restored
+//! key can be compared by eye, and check it against the store on import.
+
+use crate::error::SecureFsError;
+use bip39::Mnemonic;
+use data_encoding::BASE32_NOPAD;
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Current paper key format version
+pub const PAPER_KEY_VERSION: u8 = 1;
+
+/// Number of words in a key mnemonic
+pub const MNEMONIC_WORDS: usize = 24;
+
+const PAPER_KEY_LEN: usize = 1 + 32 + 4;
+const PAPER_KEY_GROUP: usize = 5;
+
+/// Renders `key` as a 24-word mnemonic
+pub fn to_mnemonic(key: &[u8; 32]) -> Zeroizing<String> {
+    let mnemonic = Mnemonic::from_entropy(key).expect("BUG: 32 bytes is a valid entropy length");
+    Zeroizing::new(mnemonic.to_string())
+}
+
+/// Renders `key` as a checksummed base32 paper key
+pub fn to_paper_key(key: &[u8; 32]) -> Zeroizing<String> {
+    let mut bytes = Zeroizing::new(Vec::with_capacity(PAPER_KEY_LEN));
+    bytes.push(PAPER_KEY_VERSION);
+    bytes.extend_from_slice(key);
+    let checksum = Sha256::digest(&bytes[..]);
+    bytes.extend_from_slice(&checksum[..4]);
+
+    let encoded = Zeroizing::new(BASE32_NOPAD.encode(&bytes));
+    let groups: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(PAPER_KEY_GROUP)
+        .map(|g| std::str::from_utf8(g).expect("base32 is ASCII"))
+        .collect();
+    Zeroizing::new(groups.join("-"))
+}
+
+/// Parses either backup form, telling them apart by word count
+pub fn parse_backup(text: &str) -> Result<Zeroizing<[u8; 32]>, SecureFsError> {
+    if text.split_whitespace().count() == MNEMONIC_WORDS {
+        parse_mnemonic(text)
+    } else {
+        parse_paper_key(text)
+    }
+}
+
+/// Parses a 24-word mnemonic, verifying its checksum word
+pub fn parse_mnemonic(text: &str) -> Result<Zeroizing<[u8; 32]>, SecureFsError> {
+    let normalized = Zeroizing::new(text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase());
+    let mnemonic = Mnemonic::parse_normalized(&normalized).map_err(|e| match e {
+        bip39::Error::InvalidChecksum => {
+            SecureFsError::key("mnemonic checksum mismatch (a word is mistyped or out of order)")
+        }
+        bip39::Error::UnknownWord(i) => {
+            SecureFsError::key(format!("mnemonic word {} is not in the word list", i + 1))
+        }
+        other => SecureFsError::key(format!("invalid mnemonic: {}", other)),
+    })?;
+
+    let (mut entropy, len) = mnemonic.to_entropy_array();
+    if len != 32 {
+        entropy.zeroize();
+        return Err(SecureFsError::key(format!(
+            "mnemonic encodes {} bytes, expected a 32-byte key",
+            len
+        )));
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&entropy[..32]);
+    entropy.zeroize();
+    Ok(key)
+}
+
+/// Parses a paper key, verifying its checksum
+pub fn parse_paper_key(text: &str) -> Result<Zeroizing<[u8; 32]>, SecureFsError> {
+    let cleaned = Zeroizing::new(
+        text.chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect::<String>()
+            .to_ascii_uppercase(),
+    );
+    let bytes = Zeroizing::new(
+        BASE32_NOPAD
+            .decode(cleaned.as_bytes())
+            .map_err(|_| SecureFsError::key("paper key is not valid base32"))?,
+    );
+    if bytes.len() != PAPER_KEY_LEN {
+        return Err(SecureFsError::key(format!(
+            "paper key has wrong length: expected {} bytes but found {}",
+            PAPER_KEY_LEN,
+            bytes.len()
+        )));
+    }
+    let checksum = Sha256::digest(&bytes[..PAPER_KEY_LEN - 4]);
+    if checksum[..4] != bytes[PAPER_KEY_LEN - 4..] {
+        return Err(SecureFsError::key("paper key checksum mismatch (mistyped or corrupted key)"));
+    }
+    if bytes[0] != PAPER_KEY_VERSION {
+        return Err(SecureFsError::key(format!("unsupported paper key version {}", bytes[0])));
+    }
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&bytes[1..33]);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [0x5au8; 32];
+
+    #[test]
+    fn mnemonic_round_trip_and_checksum() {
+        let words = to_mnemonic(&KEY);
+        assert_eq!(words.split(' ').count(), MNEMONIC_WORDS);
+        assert_eq!(*parse_backup(&words).expect("parse"), KEY);
+
+        // Case and extra whitespace don't matter
+        let messy = format!("  {}\n", words.to_uppercase().replace(' ', "\n  "));
+        assert_eq!(*parse_backup(&messy).expect("parse messy"), KEY);
+
+        // Swapping two words breaks the checksum
+        let mut list: Vec<&str> = words.split(' ').collect();
+        list.swap(0, 1);
+        assert!(parse_mnemonic(&list.join(" ")).is_err());
+    }
+
+    #[test]
+    fn paper_key_round_trip_and_checksum() {
+        let paper = to_paper_key(&KEY);
+        assert_eq!(paper.split('-').count(), 12);
+        assert_eq!(*parse_backup(&paper).expect("parse"), KEY);
+        assert_eq!(*parse_backup(&paper.to_lowercase().replace('-', " ")).expect("parse"), KEY);
+
+        let mut typo = paper.to_string();
+        let flipped = if typo.starts_with('A') { "B" } else { "A" };
+        typo.replace_range(0..1, flipped);
+        let err = parse_backup(&typo).expect_err("typo must fail");
+        assert!(err.to_string().contains("checksum"));
+    }
+}
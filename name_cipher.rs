@@ -0,0 +1,174 @@
+//! Deterministic encryption of storage names.
+//!
+//! This module provides [`NameCipher`], used by
+//! [`SecureFileOps`](crate::storagefile_ops::SecureFileOps) when a vault is
+//! created with encrypted names (see [`crate::vault::VaultOptions`]). Every
+//! component of a [`StorageName`] is encrypted separately, so directories
+//! stay directories on disk while revealing nothing about their names.
+//!
+//! ## Format
+//!
+//! Each on-disk component is the unpadded lowercase base32 encoding of:
+//!
+//! ```text
+//! [siv:16][ciphertext][tag:16]
+//! ```
+//!
+//! - `siv` is HMAC-SHA256 over the parent name and the component, truncated
+//!   to 16 bytes, under the [`subkey::NAME_SIV`] subkey. It makes the
+//!   encryption deterministic, so lookups don't need a directory scan.
+//! - The component is sealed with XChaCha20-Poly1305 under the
+//!   [`subkey::NAMES`] subkey, using `siv` zero-padded to 24 bytes as the
+//!   nonce and the plaintext parent name as AAD, so an encrypted name can't
+//!   be moved to another directory.
+//!
+//! Base32 expands names by 8/5, limiting components to
+//! [`MAX_ENCRYPTED_COMPONENT_LEN`] bytes.
+
+use crate::error::SecureFsError;
+use crate::key_manager::{subkey, KeyManager};
+use crate::secret::{LockedBox, SharedCipher};
+use crate::storage_name::StorageName;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::XNonce;
+use data_encoding::BASE32_DNSSEC;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+
+/// Longest plaintext component that still fits a 255-byte file name once encrypted
+pub const MAX_ENCRYPTED_COMPONENT_LEN: usize = 127;
+
+const SIV_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Encrypts and decrypts the components of storage names
+#[derive(Clone)]
+pub struct NameCipher {
+    cipher: SharedCipher,
+    siv_key: Arc<LockedBox<[u8; 32]>>,
+}
+
+impl NameCipher {
+    pub fn new(km: &KeyManager) -> Self {
+        Self {
+            cipher: km.derive_subkey(subkey::NAMES),
+            siv_key: Arc::new(km.derive_subkey_bytes(subkey::NAME_SIV)),
+        }
+    }
+
+    /// On-disk components for `name`, outermost directory first
+    pub fn encrypt_name(&self, name: &StorageName) -> Result<Vec<String>, SecureFsError> {
+        let mut parent = String::new();
+        let mut encrypted = Vec::new();
+        for component in name.components() {
+            if component.len() > MAX_ENCRYPTED_COMPONENT_LEN {
+                return Err(SecureFsError::invalid_name(
+                    name.as_str(),
+                    "with encrypted names, each component is limited to 127 bytes",
+                ));
+            }
+            encrypted.push(self.encrypt_component(&parent, component));
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push_str(component);
+        }
+        Ok(encrypted)
+    }
+
+    /// Encrypts one component of a name inside directory `parent` ("" for the root)
+    pub fn encrypt_component(&self, parent: &str, component: &str) -> String {
+        let siv = self.siv(parent, component);
+        let ct = self
+            .cipher
+            .encrypt(
+                &nonce(&siv),
+                Payload {
+                    msg: component.as_bytes(),
+                    aad: parent.as_bytes(),
+                },
+            )
+            .expect("BUG: XChaCha20-Poly1305 encryption of a short name cannot fail");
+        let mut raw = siv.to_vec();
+        raw.extend_from_slice(&ct);
+        BASE32_DNSSEC.encode(&raw)
+    }
+
+    /// Decrypts an on-disk component found in directory `parent`.
+    /// Returns `None` if it isn't a name encrypted with this key.
+    pub fn decrypt_component(&self, parent: &str, encoded: &str) -> Option<String> {
+        let raw = BASE32_DNSSEC.decode(encoded.as_bytes()).ok()?;
+        if raw.len() < SIV_LEN + TAG_LEN {
+            return None;
+        }
+        let (siv, ct) = raw.split_at(SIV_LEN);
+        let siv: [u8; SIV_LEN] = siv.try_into().ok()?;
+        let plaintext = self
+            .cipher
+            .decrypt(
+                &nonce(&siv),
+                Payload {
+                    msg: ct,
+                    aad: parent.as_bytes(),
+                },
+            )
+            .ok()?;
+        let component = String::from_utf8(plaintext).ok()?;
+        // Reject names that decrypt but aren't the canonical encryption
+        (self.siv(parent, &component) == siv).then_some(component)
+    }
+
+    fn siv(&self, parent: &str, component: &str) -> [u8; SIV_LEN] {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.siv_key[..])
+            .expect("HMAC accepts any key length");
+        mac.update(&(parent.len() as u64).to_le_bytes());
+        mac.update(parent.as_bytes());
+        mac.update(component.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let mut siv = [0u8; SIV_LEN];
+        siv.copy_from_slice(&digest[..SIV_LEN]);
+        siv
+    }
+}
+
+fn nonce(siv: &[u8; SIV_LEN]) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..SIV_LEN].copy_from_slice(siv);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyfile::KeyFile;
+
+    fn cipher(byte: u8) -> NameCipher {
+        NameCipher::new(&KeyManager::from_key_file(&KeyFile::new([byte; 32])))
+    }
+
+    #[test]
+    fn names_are_deterministic_and_bound_to_their_directory() {
+        let names = cipher(0x42);
+        let name = StorageName::new("docs/payroll-2026.xlsx").expect("valid");
+        let encrypted = names.encrypt_name(&name).expect("encrypt");
+        assert_eq!(encrypted, names.encrypt_name(&name).expect("encrypt"));
+        assert!(encrypted.iter().all(|c| !c.contains("payroll") && c.len() <= 255));
+
+        assert_eq!(names.decrypt_component("docs", &encrypted[1]).as_deref(), Some("payroll-2026.xlsx"));
+        assert_eq!(names.decrypt_component("", &encrypted[1]), None);
+        assert_eq!(cipher(0x24).decrypt_component("docs", &encrypted[1]), None);
+        assert_eq!(names.decrypt_component("", "plain.txt"), None);
+    }
+
+    #[test]
+    fn longest_component_fits_a_file_name() {
+        let names = cipher(0x42);
+        let longest = "x".repeat(MAX_ENCRYPTED_COMPONENT_LEN);
+        assert!(names.encrypt_component("", &longest).len() <= 255);
+        let name = StorageName::new("x".repeat(MAX_ENCRYPTED_COMPONENT_LEN + 1)).expect("valid");
+        assert!(names.encrypt_name(&name).is_err());
+    }
+}
@@ -0,0 +1,672 @@
+//! Re-encryption of a whole store under a new master key.
+//!
+//! This module implements
+//! [`SecureFileOps::reencrypt_all`](crate::storagefile_ops::SecureFileOps::reencrypt_all),
+//! used to retire a master key after a suspected compromise.
+//!
+//! ## Protocol
+//!
+//! 1. The store's write lock (see [`crate::write_mode`]) is taken for the
+//!    whole run. A progress journal is created in the storage root, or an
+//!    existing one for the same pair of keys is resumed.
+//! 2. Each file is decrypted with the old key and re-encrypted with the new
+//!    one into a temporary file, which is fsynced and renamed over the
+//!    original (see [`crate::util::AtomicFile`]). Its name is then appended
+//!    to the journal. Several files are processed at once.
+//! 3. Once every file is done the vault descriptor is rebound to the new key
+//!    and the journal is removed. A journal left behind once the descriptor
+//!    names its new key is stale and ignored.
+//!
+//! Until the descriptor flips, the old key keeps opening the store. Files
+//! the journal marks as done are read with the new key when the store is
+//! given it (see
+//! [`SecureFileOps::with_rotation_key`](crate::storagefile_ops::SecureFileOps::with_rotation_key)).
+//! Writes wait for the lock and then fail while a rotation is pending, as
+//! do writes with the old key once the descriptor has flipped, so nothing
+//! new lands under the retired key.
+//!
+//! A file renamed into place but not yet journaled when the job was
+//! interrupted is recognized on resume because it authenticates under the
+//! new key. V3 recipient files don't use the master key and are left as is,
+//! and so are files with keys of their own (see [`crate::file_keys`]): the
+//! key table is rewrapped under the new key once every file is done.
+//! V2 streams written before file IDs get one on the way, so they can be
+//! renamed without re-encryption from then on (see [`crate::streaming`]).
+//!
+//! When the store encrypts names (see [`crate::name_cipher`]), names are
+//! keyed by the master key too: each file is written under its new
+//! encrypted name and the old one is removed, and directories left empty
+//! are pruned at the end.
+//!
+//! Archived versions (see [`crate::history`]) are re-encrypted, and moved
+//! along with their file, before the file is journaled. Files in the trash
+//! (see [`crate::trash`]) are re-encrypted in place, journaled as
+//! `.securefs-trash/<id>/<file name>`.
+//!
+//! ## Journal Format
+//!
+//! `.securefs-rotation.jsonl` in the storage root, one JSON value per line:
+//!
+//! ```text
+//! {"old_key_id":"<hex>","new_key_id":"<hex>"}
+//! "<file name>"
+//! ...
+//! ```
+
+use crate::encryptor::Encryptor;
+use crate::file_keys::{self, KeyId, KeyTable};
+use crate::history;
+use crate::key_manager::KeyManager;
+use crate::metadata::{self, MetadataKey};
+use crate::name_cipher::NameCipher;
+use crate::storage_name::StorageName;
+use crate::storagefile_ops::{content_aad, resolve_in};
+use crate::streaming::{
+    self, FormatFlags, StreamEncryptor, FILE_ID_LEN, FLAG_FILE_ID, MAX_RECIPIENTS, STANZA_X25519,
+    VERSION_V2_STREAM, VERSION_V3_RECIPIENTS,
+};
+use crate::util::{self, AtomicFile, FileLock, RESERVED_PREFIX};
+use crate::vault::VaultDescriptor;
+use crate::write_mode::WRITE_LOCK_FILE;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+use zeroize::Zeroizing;
+
+/// Name of the progress journal in the storage root
+pub const JOURNAL_FILE: &str = ".securefs-rotation.jsonl";
+
+/// Buffer between the decrypting and re-encrypting halves of a stream
+pub(crate) const PIPE_SIZE: usize = 64 * 1024;
+
+/// Outcome of a completed [`reencrypt_all`](crate::storagefile_ops::SecureFileOps::reencrypt_all)
+#[derive(Debug, Clone, Default)]
+pub struct RotationReport {
+    /// Files re-encrypted by this run
+    pub rotated: usize,
+    /// Files with keys of their own, left as they are; the key table is
+    /// rewrapped instead (see [`crate::file_keys`])
+    pub own_key: usize,
+    /// Files already under the new key, e.g. from an interrupted run
+    pub resumed: usize,
+    /// Recipient (V3) files, which don't use the master key
+    pub skipped: Vec<String>,
+}
+
+/// Content ciphers for one master key
+#[derive(Clone)]
+pub(crate) struct ContentKeys {
+    pub encryptor: Encryptor,
+    pub stream: StreamEncryptor,
+    /// Set when the store encrypts names, which then change with the key
+    pub names: Option<NameCipher>,
+    pub metadata: MetadataKey,
+    pub key_id: [u8; 16],
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct JournalHeader {
+    old_key_id: String,
+    new_key_id: String,
+}
+
+enum Outcome {
+    Rotated,
+    AlreadyRotated,
+    Skipped,
+    /// Encrypted under a key of its own, which is rewrapped instead
+    OwnKey,
+}
+
+struct Job {
+    old: ContentKeys,
+    new: ContentKeys,
+    /// Keys in the store's key table (see [`crate::file_keys`])
+    file_keys: HashSet<KeyId>,
+}
+
+/// An unfinished rotation, as recorded in its journal
+pub(crate) struct Pending {
+    pub new_key_id: String,
+    /// Journal entries of the files already under the new key
+    pub done: HashSet<String>,
+}
+
+/// Whether an unfinished rotation is recorded in `root`
+pub async fn in_progress(root: &Path) -> bool {
+    matches!(pending(root).await, Ok(Some(_)))
+}
+
+/// The unfinished rotation recorded in `root`, if any; stale journals,
+/// whose new key the vault descriptor already names, don't count
+pub(crate) async fn pending(root: &Path) -> Result<Option<Pending>> {
+    let path = root.join(JOURNAL_FILE);
+    let text = match fs::read_to_string(&path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("reading rotation journal {}", path.display()))),
+    };
+    let (header, done) = parse_journal(&text).with_context(|| format!("parsing rotation journal {}", path.display()))?;
+    let vault = VaultDescriptor::load(root).await?;
+    if vault.is_some_and(|vault| vault.settings.key_id == header.new_key_id) {
+        return Ok(None);
+    }
+    Ok(Some(Pending {
+        new_key_id: header.new_key_id,
+        done,
+    }))
+}
+
+/// Re-encrypts `files`, given as the root of the store holding each (the
+/// storage root or a trash entry, see [`crate::trash`]) and its name
+pub(crate) async fn reencrypt_all(
+    root: &Path,
+    files: Vec<(PathBuf, String)>,
+    old: ContentKeys,
+    new_km: &KeyManager,
+    vault: Option<&VaultDescriptor>,
+    key_table: Option<&KeyTable>,
+    jobs: usize,
+) -> Result<RotationReport> {
+    if old.key_id == new_km.key_id() {
+        bail!("the new key is the key the store already uses");
+    }
+    let _lock = FileLock::acquire(&root.join(WRITE_LOCK_FILE)).await?;
+    if pending(root).await?.is_none() && fs::try_exists(root.join(JOURNAL_FILE)).await? {
+        debug!("removing the journal of a finished rotation");
+        fs::remove_file(root.join(JOURNAL_FILE)).await?;
+    }
+    let header = JournalHeader {
+        old_key_id: hex::encode(old.key_id),
+        new_key_id: hex::encode(new_km.key_id()),
+    };
+    let mut journal = Journal::open(root, &header).await?;
+
+    let mut report = RotationReport {
+        resumed: journal.done.len(),
+        ..Default::default()
+    };
+    let pending: Vec<_> = files
+        .into_iter()
+        .map(|(store, name)| (journal_key(root, &store, &name), store, name))
+        .filter(|(key, _, _)| !journal.done.contains(key))
+        .collect();
+    let stores: BTreeSet<PathBuf> = pending.iter().map(|(_, store, _)| store.clone()).collect();
+    info!(
+        pending = pending.len(),
+        resumed = report.resumed,
+        jobs,
+        new_key_id = %header.new_key_id,
+        "re-encrypting store"
+    );
+
+    let mut new = crate::storagefile_ops::content_keys(new_km);
+    if old.names.is_some() {
+        new.names = Some(NameCipher::new(new_km));
+    }
+    let renames = new.names.is_some();
+    let file_keys = match key_table {
+        Some(table) => table.ids().await?,
+        None => HashSet::new(),
+    };
+    let job = Arc::new(Job { old, new, file_keys });
+    let mut pending = pending.into_iter();
+    let mut tasks = JoinSet::new();
+    let mut failed = Vec::new();
+    loop {
+        while tasks.len() < jobs.max(1) {
+            let Some((key, store, name)) = pending.next() else { break };
+            let job = Arc::clone(&job);
+            tasks.spawn(async move {
+                let result = rotate_file(&job, &store, &name).await;
+                (key, result)
+            });
+        }
+        let Some(joined) = tasks.join_next().await else { break };
+        let (name, result) = joined.context("re-encryption task panicked")?;
+        match result {
+            Ok(outcome) => {
+                match outcome {
+                    Outcome::Rotated => report.rotated += 1,
+                    Outcome::OwnKey => report.own_key += 1,
+                    Outcome::AlreadyRotated => report.resumed += 1,
+                    Outcome::Skipped => report.skipped.push(name.clone()),
+                }
+                journal.record(&name).await?;
+            }
+            Err(e) => {
+                error!(file = %name, error = %e, "re-encryption failed");
+                failed.push(name);
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        failed.sort();
+        bail!(
+            "{} file(s) could not be re-encrypted and still need the old key: {}; \
+             fix or remove them and run the rotation again to resume",
+            failed.len(),
+            failed.join(", ")
+        );
+    }
+
+    if renames {
+        for store in stores.iter().map(PathBuf::as_path).chain([root]) {
+            prune_empty_dirs(store).await?;
+            for mirror in [metadata::METADATA_DIR, history::HISTORY_DIR] {
+                let mirror = store.join(mirror);
+                if fs::try_exists(&mirror).await? {
+                    prune_empty_dirs(&mirror).await?;
+                }
+            }
+        }
+    }
+
+    if let Some(table) = key_table {
+        table.rekey(new_km).await?;
+    }
+
+    // Flip the vault before dropping the journal, so there is no point at
+    // which the old key opens the store without knowing which files moved on
+    if let Some(vault) = vault {
+        vault.rekey(new_km).replace(root).await?;
+    }
+    fs::remove_file(&journal.path)
+        .await
+        .with_context(|| format!("removing {}", journal.path.display()))?;
+    report.skipped.sort();
+    info!(
+        rotated = report.rotated,
+        own_key = report.own_key,
+        resumed = report.resumed,
+        skipped = report.skipped.len(),
+        "store re-encrypted"
+    );
+    Ok(report)
+}
+
+/// Re-encrypts one file, or recognizes it as already done. With encrypted
+/// names the file moves to its name under the new key; otherwise it is
+/// replaced in place.
+async fn rotate_file(job: &Job, root: &Path, name: &str) -> Result<Outcome> {
+    let storage_name = StorageName::new(name)?;
+    let path = resolve_in(root, job.old.names.as_ref(), &storage_name, false).await?;
+    let dest = resolve_in(root, job.new.names.as_ref(), &storage_name, true).await?;
+    let outcome = rotate_content(
+        job,
+        name,
+        &path,
+        &dest,
+        &metadata::sidecar_path(root, &path),
+        &metadata::sidecar_path(root, &dest),
+    )
+    .await?;
+    rotate_history(job, root, name, &path, &dest).await?;
+    Ok(outcome)
+}
+
+/// Journal entry for `name` in the store at `store`: the name itself in the
+/// storage root, prefixed with the store's relative path otherwise
+fn journal_key(root: &Path, store: &Path, name: &str) -> String {
+    match store.strip_prefix(root) {
+        Ok(relative) if !relative.as_os_str().is_empty() => format!("{}/{}", relative.display(), name),
+        _ => name.to_string(),
+    }
+}
+
+/// Re-encrypts the archived versions of `name` (see [`crate::history`]),
+/// moving them to the history directory of `dest` if needed
+async fn rotate_history(job: &Job, root: &Path, name: &str, path: &Path, dest: &Path) -> Result<()> {
+    let src_dir = history::history_dir(root, path);
+    let dest_dir = history::history_dir(root, dest);
+    // An interrupted run may already have moved some entries
+    let mut entries = BTreeSet::new();
+    for dir in [&src_dir, &dest_dir] {
+        for entry in history::entries_in(dir).await? {
+            if let Some(file_name) = entry.path.file_name() {
+                entries.insert(file_name.to_owned());
+            }
+        }
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+    fs::create_dir_all(&dest_dir)
+        .await
+        .with_context(|| format!("creating history directory {:?}", &dest_dir))?;
+    for file_name in entries {
+        let entry = src_dir.join(&file_name);
+        let entry_dest = dest_dir.join(&file_name);
+        rotate_content(
+            job,
+            name,
+            &entry,
+            &entry_dest,
+            &history::meta_path(&entry),
+            &history::meta_path(&entry_dest),
+        )
+        .await
+        .with_context(|| format!("re-encrypting archived version {:?}", &entry))?;
+    }
+    Ok(())
+}
+
+/// Re-encrypts the encrypted file at `path` into `dest` and reseals its
+/// sidecar from `src_meta` into `dest_meta`
+async fn rotate_content(
+    job: &Job,
+    name: &str,
+    path: &Path,
+    dest: &Path,
+    src_meta: &Path,
+    dest_meta: &Path,
+) -> Result<Outcome> {
+    let outcome = if path != dest && !fs::try_exists(path).await? && fs::try_exists(dest).await? {
+        Outcome::AlreadyRotated
+    } else {
+        let outcome = reencrypt_file(job, name, path, dest).await?;
+        if path != dest {
+            if matches!(outcome, Outcome::Skipped | Outcome::OwnKey) {
+                fs::rename(path, dest)
+                    .await
+                    .with_context(|| format!("moving {:?} to {:?}", path, dest))?;
+            } else {
+                fs::remove_file(path)
+                    .await
+                    .with_context(|| format!("removing {:?}", path))?;
+            }
+        }
+        outcome
+    };
+
+    reseal_metadata(job, name, dest, src_meta, dest_meta).await?;
+    if path != dest {
+        util::sync_parent(dest).await?;
+    }
+    Ok(outcome)
+}
+
+/// Seals the metadata sidecar of `name` under the new key, bound to the
+/// re-encrypted file at `dest`, moving it from `src_meta` to `dest_meta` if
+/// needed. Sidecars already sealed under the new key are left alone, so this
+/// is safe to repeat on resume.
+async fn reseal_metadata(job: &Job, name: &str, dest: &Path, src_meta: &Path, dest_meta: &Path) -> Result<()> {
+    let src_meta = if fs::try_exists(src_meta).await? { src_meta } else { dest_meta };
+    let sidecar = match fs::read(&src_meta).await {
+        Ok(sidecar) => sidecar,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("reading metadata {:?}", &src_meta))),
+    };
+
+    let header = metadata::content_header(dest).await?;
+    if job.new.metadata.open(name, &sidecar, &header).is_err() {
+        match job.old.metadata.open_unbound(name, &sidecar) {
+            Ok(mut meta) => {
+                if meta.stored_size.is_some() {
+                    meta.stored_size = Some(fs::metadata(dest).await?.len());
+                }
+                let sealed = job.new.metadata.seal(name, &meta, &header)?;
+                util::create_parent(dest_meta).await?;
+                let mut out = AtomicFile::create(dest_meta).await?;
+                out.file().write_all(&sealed).await?;
+                out.commit().await?;
+                if src_meta != dest_meta {
+                    fs::remove_file(src_meta).await?;
+                }
+                return Ok(());
+            }
+            Err(e) => warn!(file = name, error = %e, "metadata can't be opened with the old key; moving it unchanged"),
+        }
+    }
+    if src_meta != dest_meta {
+        util::create_parent(dest_meta).await?;
+        fs::rename(src_meta, dest_meta)
+            .await
+            .with_context(|| format!("moving metadata {:?}", &src_meta))?;
+    }
+    Ok(())
+}
+
+/// Re-encrypts the file at `path` into `dest`, which may be the same path.
+/// V1 files start with a random nonce, so a leading version byte alone
+/// doesn't prove a V2 or V3 file.
+async fn reencrypt_file(job: &Job, name: &str, path: &Path, dest: &Path) -> Result<Outcome> {
+    let mut header = Vec::with_capacity(file_keys::HEADER_LEN);
+    fs::File::open(&path)
+        .await
+        .with_context(|| format!("opening {:?}", &path))?
+        .take(file_keys::HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .await
+        .with_context(|| format!("reading header of {:?}", &path))?;
+
+    if file_keys::key_id_of(&header).is_some_and(|id| job.file_keys.contains(&id)) {
+        debug!(file = name, "file has its own key");
+        return Ok(Outcome::OwnKey);
+    }
+    match header.first() {
+        Some(&VERSION_V3_RECIPIENTS) if is_recipient_header(&header) => {
+            debug!(file = name, "skipping recipient file");
+            Ok(Outcome::Skipped)
+        }
+        Some(&VERSION_V2_STREAM) => match reencrypt_stream(job, name, path, dest).await {
+            Ok(outcome) => Ok(outcome),
+            Err(e) => reencrypt_buffer(job, path, dest).await.map_err(|_| e),
+        },
+        _ => reencrypt_buffer(job, path, dest).await,
+    }
+}
+
+/// Whether `header` starts a plausible V3 header: known flags, a stanza
+/// count within bounds and an X25519 first stanza
+pub(crate) fn is_recipient_header(header: &[u8]) -> bool {
+    matches!(header, [VERSION_V3_RECIPIENTS, flags, count, STANZA_X25519, ..]
+        if flags & !(0x03 | FLAG_FILE_ID) == 0 && (1..=MAX_RECIPIENTS).contains(&(*count as usize)))
+}
+
+/// Re-encrypts a V2 stream through a pipe, without holding it in memory.
+/// Streams bound to their name get a file ID (see [`crate::streaming`]).
+async fn reencrypt_stream(job: &Job, name: &str, path: &Path, dest: &Path) -> Result<Outcome> {
+    let mut src = fs::File::open(&path)
+        .await
+        .with_context(|| format!("opening {:?}", &path))?;
+    let mut header = Vec::with_capacity(2 + FILE_ID_LEN);
+    (&mut src).take(2 + FILE_ID_LEN as u64).read_to_end(&mut header).await?;
+    src.rewind().await?;
+    let mut flags = FormatFlags::from_v2_header(&header);
+    flags.file_id.get_or_insert_with(streaming::generate_file_id);
+    let aad = content_aad(&header, name);
+
+    let mut out = AtomicFile::create(dest).await?;
+    let (tx, mut rx) = tokio::io::duplex(PIPE_SIZE);
+    let decrypt = async {
+        let mut tx = tx;
+        let result = job.old.stream.decrypt_stream(&mut src, &mut tx, aad).await;
+        // Closing the pipe ends the encrypting side
+        drop(tx);
+        result
+    };
+    let encrypt = job.new.stream.encrypt_stream(&mut rx, out.file(), flags, None);
+    match tokio::try_join!(decrypt, encrypt) {
+        Ok(_) => {
+            out.commit().await?;
+            Ok(Outcome::Rotated)
+        }
+        Err(e) => {
+            drop(out);
+            let mut src = fs::File::open(path).await?;
+            let done = job
+                .new
+                .stream
+                .decrypt_stream(&mut src, &mut tokio::io::sink(), aad)
+                .await
+                .is_ok();
+            if done {
+                Ok(Outcome::AlreadyRotated)
+            } else {
+                Err(e.context(format!("decrypting {:?} with the old key", &path)))
+            }
+        }
+    }
+}
+
+/// Re-encrypts a V1 buffer
+async fn reencrypt_buffer(job: &Job, path: &Path, dest: &Path) -> Result<Outcome> {
+    let data = fs::read(path)
+        .await
+        .with_context(|| format!("reading {:?}", &path))?;
+    // Re-encrypt the payload as is, so compressed buffers stay compressed
+    let payload = match job.old.encryptor.decrypt(&data, None) {
+        Ok(payload) => Zeroizing::new(payload),
+        Err(_) if job.new.encryptor.decrypt(&data, None).is_ok() => return Ok(Outcome::AlreadyRotated),
+        Err(e) => return Err(e.context(format!("decrypting {:?} with the old key", &path))),
+    };
+    let enc = job.new.encryptor.encrypt(&payload, None)?;
+    let mut out = AtomicFile::create(dest).await?;
+    out.file().write_all(&enc).await?;
+    out.commit().await?;
+    Ok(Outcome::Rotated)
+}
+
+/// Removes directories left empty after files moved to their new encrypted names
+async fn prune_empty_dirs(root: &Path) -> Result<()> {
+    let mut dirs = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let reserved = entry.file_name().to_string_lossy().starts_with(RESERVED_PREFIX);
+            if entry.file_type().await?.is_dir() && !reserved {
+                pending.push(entry.path());
+                dirs.push(entry.path());
+            }
+        }
+    }
+    // Deepest first, so parents are empty by the time they are reached
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    for dir in dirs {
+        if fs::remove_dir(&dir).await.is_ok() {
+            debug!(dir = %dir.display(), "removed empty directory");
+        }
+    }
+    Ok(())
+}
+
+/// Append-only record of finished files
+struct Journal {
+    path: PathBuf,
+    file: fs::File,
+    done: HashSet<String>,
+}
+
+impl Journal {
+    /// Resumes the journal in `root` if it matches `header`, or starts a new one
+    async fn open(root: &Path, header: &JournalHeader) -> Result<Self> {
+        let path = root.join(JOURNAL_FILE);
+        let mut done = HashSet::new();
+
+        let file = if fs::try_exists(&path).await.unwrap_or(false) {
+            let text = fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("reading rotation journal {}", path.display()))?;
+            let (found, names) = parse_journal(&text)
+                .with_context(|| format!("parsing rotation journal {}", path.display()))?;
+            if found != *header {
+                bail!(
+                    "a rotation from key {} to key {} is already in progress; \
+                     finish it with those keys before starting another",
+                    found.old_key_id,
+                    found.new_key_id
+                );
+            }
+            done = names;
+            info!(path = %path.display(), done = done.len(), "resuming interrupted rotation");
+
+            let mut file = fs::OpenOptions::new().append(true).open(&path).await?;
+            // An interrupted append may have left a partial line
+            if !text.ends_with('\n') {
+                file.write_all(b"\n").await?;
+            }
+            file
+        } else {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+                .with_context(|| format!("creating rotation journal {}", path.display()))?;
+            file.write_all(format!("{}\n", serde_json::to_string(header)?).as_bytes())
+                .await?;
+            file.sync_all().await?;
+            util::sync_parent(&path).await?;
+            file
+        };
+
+        Ok(Self { path, file, done })
+    }
+
+    /// Durably marks `name` as finished
+    async fn record(&mut self, name: &str) -> Result<()> {
+        let line = format!("{}\n", serde_json::to_string(name)?);
+        self.file.write_all(line.as_bytes()).await?;
+        self.file
+            .sync_data()
+            .await
+            .with_context(|| format!("syncing {}", self.path.display()))?;
+        self.done.insert(name.to_string());
+        Ok(())
+    }
+}
+
+/// Parses a journal, ignoring a truncated final line
+fn parse_journal(text: &str) -> Result<(JournalHeader, HashSet<String>)> {
+    let mut lines = text.lines();
+    let header: JournalHeader = serde_json::from_str(lines.next().context("journal is empty")?)
+        .context("invalid journal header")?;
+    let mut names = HashSet::new();
+    let lines: Vec<&str> = lines.filter(|l| !l.is_empty()).collect();
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str::<String>(line) {
+            Ok(name) => {
+                names.insert(name);
+            }
+            Err(_) if i + 1 == lines.len() => warn!("ignoring truncated last journal entry"),
+            Err(e) => return Err(e).context(format!("invalid journal entry {}", i + 1)),
+        }
+    }
+    Ok((header, names))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_tolerates_truncated_tail() {
+        let text = "{\"old_key_id\":\"aa\",\"new_key_id\":\"bb\"}\n\"a.txt\"\n\"b.t";
+        let (header, names) = parse_journal(text).expect("parse");
+        assert_eq!(header.new_key_id, "bb");
+        assert_eq!(names.len(), 1);
+        assert!(names.contains("a.txt"));
+
+        let corrupt = "{\"old_key_id\":\"aa\",\"new_key_id\":\"bb\"}\n\"a.t\n\"b.txt\"\n";
+        assert!(parse_journal(corrupt).is_err());
+        assert!(parse_journal("").is_err());
+    }
+
+    #[test]
+    fn v1_nonce_starting_with_version_byte_is_not_a_recipient_file() {
+        assert!(is_recipient_header(&[VERSION_V3_RECIPIENTS, 0x01, 2, STANZA_X25519]));
+        assert!(!is_recipient_header(&[VERSION_V3_RECIPIENTS, 0x9c, 2, STANZA_X25519]));
+        assert!(!is_recipient_header(&[VERSION_V3_RECIPIENTS, 0x00, 0, STANZA_X25519]));
+        assert!(!is_recipient_header(&[VERSION_V3_RECIPIENTS, 0x00, 1, 0x7f]));
+        assert!(!is_recipient_header(&[VERSION_V3_RECIPIENTS]));
+    }
+}
@@ -0,0 +1,229 @@
+//! Locked, non-swappable memory for key material.
+//!
+//! This module provides [`LockedBox`], a heap container that places its value
+//! in dedicated pages which are:
+//!
+//! - locked in RAM with `mlock` so they are never written to swap
+//! - excluded from core dumps with `madvise(MADV_DONTDUMP)` (Linux)
+//! - zeroized before being unmapped on drop
+//!
+//! Locking can fail when `RLIMIT_MEMLOCK` is exhausted; in that case a warning
+//! is logged once and the value is still stored and wiped, just not pinned.
+//! Cipher instances share one locked copy through [`SharedCipher`] instead of
+//! each holding their own key.
+
+use chacha20poly1305::XChaCha20Poly1305;
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+use zeroize::Zeroize;
+
+/// A cipher living in locked memory, shared by every encryptor that uses it
+pub type SharedCipher = Arc<LockedBox<XChaCha20Poly1305>>;
+
+static LOCK_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Owns a `T` stored in its own locked, non-dumpable pages
+pub struct LockedBox<T> {
+    ptr: NonNull<T>,
+    len: usize,
+    locked: bool,
+}
+
+// SAFETY: LockedBox uniquely owns its allocation, like Box<T>
+unsafe impl<T: Send> Send for LockedBox<T> {}
+unsafe impl<T: Sync> Sync for LockedBox<T> {}
+
+impl<T> LockedBox<T> {
+    /// Moves `value` into locked memory.
+    /// The caller's copy is moved out, so no drop (and no zeroization) runs on it;
+    /// prefer [`LockedBox::from_fn`] for raw key bytes.
+    pub fn new(value: T) -> Self {
+        let (ptr, len, locked) = allocate::<T>();
+        // SAFETY: ptr is valid, aligned and large enough for T
+        unsafe { ptr.as_ptr().write(value) };
+        Self { ptr, len, locked }
+    }
+
+    /// Whether the pages were successfully locked in RAM
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+impl<const N: usize> LockedBox<[u8; N]> {
+    /// Allocates zeroed locked bytes and lets `fill` write the secret in place,
+    /// so it never exists outside locked memory
+    pub fn from_fn(fill: impl FnOnce(&mut [u8; N])) -> Self {
+        let (ptr, len, locked) = allocate::<[u8; N]>();
+        // SAFETY: fresh mappings are zero-filled, which is a valid [u8; N]
+        let mut boxed = Self { ptr, len, locked };
+        fill(unsafe { boxed.ptr.as_mut() });
+        boxed
+    }
+
+    /// Copies `bytes` into locked memory
+    pub fn from_slice(bytes: &[u8; N]) -> Self {
+        Self::from_fn(|buf| buf.copy_from_slice(bytes))
+    }
+}
+
+impl<T> Deref for LockedBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: ptr holds an initialized T for the lifetime of self
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Drop for LockedBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: the value is initialized and dropped exactly once; the mapping
+        // is then wiped and released, and never touched again
+        unsafe {
+            std::ptr::drop_in_place(self.ptr.as_ptr());
+            let bytes = std::slice::from_raw_parts_mut(self.ptr.as_ptr().cast::<u8>(), self.len);
+            bytes.zeroize();
+            sys::release(self.ptr.as_ptr().cast(), self.len, self.locked);
+        }
+    }
+}
+
+impl<T> fmt::Debug for LockedBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LockedBox(<redacted>)")
+    }
+}
+
+/// Maps zeroed pages for a `T` and tries to lock them
+fn allocate<T>() -> (NonNull<T>, usize, bool) {
+    let page = sys::page_size();
+    assert!(mem::align_of::<T>() <= page, "LockedBox does not support over-aligned types");
+    let size = mem::size_of::<T>().max(1);
+    let len = size.div_ceil(page) * page;
+
+    let raw = sys::map(len);
+    let ptr = NonNull::new(raw.cast::<T>()).expect("mapping returned null");
+
+    let locked = sys::protect(raw, len);
+    if !locked && !LOCK_WARNED.swap(true, Ordering::Relaxed) {
+        warn!(
+            bytes = len,
+            "could not lock key memory (mlock failed, check RLIMIT_MEMLOCK); secrets may be swapped to disk"
+        );
+    }
+    (ptr, len, locked)
+}
+
+#[cfg(unix)]
+mod sys {
+    pub fn page_size() -> usize {
+        // SAFETY: sysconf has no preconditions
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if size > 0 {
+            size as usize
+        } else {
+            4096
+        }
+    }
+
+    pub fn map(len: usize) -> *mut u8 {
+        // SAFETY: anonymous private mapping with no address hint
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            std::alloc::handle_alloc_error(std::alloc::Layout::from_size_align(len, 1).expect("valid layout"));
+        }
+        ptr.cast()
+    }
+
+    /// Excludes the region from core dumps and locks it in RAM.
+    /// Returns whether the lock succeeded.
+    pub fn protect(ptr: *mut u8, len: usize) -> bool {
+        // SAFETY: ptr/len describe a mapping we own
+        unsafe {
+            #[cfg(target_os = "linux")]
+            libc::madvise(ptr.cast(), len, libc::MADV_DONTDUMP);
+            libc::mlock(ptr.cast(), len) == 0
+        }
+    }
+
+    /// # Safety
+    /// `ptr`/`len` must describe a live mapping returned by [`map`]
+    pub unsafe fn release(ptr: *mut u8, len: usize, locked: bool) {
+        if locked {
+            libc::munlock(ptr.cast(), len);
+        }
+        libc::munmap(ptr.cast(), len);
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+
+    pub fn page_size() -> usize {
+        4096
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, page_size()).expect("valid layout")
+    }
+
+    pub fn map(len: usize) -> *mut u8 {
+        // SAFETY: layout has non-zero size
+        let ptr = unsafe { alloc_zeroed(layout(len)) };
+        if ptr.is_null() {
+            handle_alloc_error(layout(len));
+        }
+        ptr
+    }
+
+    /// Memory locking is not implemented on this platform
+    pub fn protect(_ptr: *mut u8, _len: usize) -> bool {
+        false
+    }
+
+    /// # Safety
+    /// `ptr`/`len` must describe a live allocation returned by [`map`]
+    pub unsafe fn release(ptr: *mut u8, len: usize, _locked: bool) {
+        dealloc(ptr, layout(len));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_and_reads_back() {
+        let key = LockedBox::<[u8; 32]>::from_fn(|k| k.fill(0x42));
+        assert_eq!(*key, [0x42u8; 32]);
+
+        let boxed = LockedBox::new(String::from("secret"));
+        assert_eq!(boxed.as_str(), "secret");
+        assert_eq!(format!("{:?}", boxed), "LockedBox(<redacted>)");
+    }
+
+    #[test]
+    fn drops_inner_value() {
+        let tracker = Arc::new(());
+        let boxed = LockedBox::new(tracker.clone());
+        assert_eq!(Arc::strong_count(&tracker), 2);
+        drop(boxed);
+        assert_eq!(Arc::strong_count(&tracker), 1);
+    }
+}
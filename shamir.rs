@@ -0,0 +1,303 @@
+//! Shamir secret sharing for master key backup.
+//!
+//! This module provides [`KeyShare`] and the [`split`] / [`combine`] functions
+//! used by [`KeyManager::split`](crate::key_manager::KeyManager::split) to
+//! divide a 32-byte key into N shares, any M of which recover it.
+//!
+//! Sharing is done byte-wise over GF(2^8) with random polynomials of degree M-1.
+//!
+//! ## Share Format
+//!
+//! Shares are text: `securefs-share:` followed by the hex encoding of
+//!
+//! ```text
+//! [version:1][threshold:1][index:1][key_id:16][value:32][checksum:4]
+//! ```
+//!
+//! - `key_id` identifies the key the share belongs to (see [`crate::keyfile::key_id_for`])
+//! - `checksum` is the first 4 bytes of SHA-256 over all preceding bytes
+//!
+//! The checksum catches typos in a single share; the key ID catches shares
+//! from different keys or a wrong combination.
+
+use crate::error::SecureFsError;
+use crate::keyfile::key_id_for;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Text prefix for encoded shares
+pub const SHARE_PREFIX: &str = "securefs-share:";
+
+/// Current share format version
+pub const SHARE_VERSION: u8 = 1;
+
+const SHARE_LEN: usize = 1 + 1 + 1 + 16 + 32 + 4;
+
+/// One share of a split master key
+#[derive(Clone)]
+pub struct KeyShare {
+    pub threshold: u8,
+    pub index: u8,
+    pub key_id: [u8; 16],
+    value: Zeroizing<[u8; 32]>,
+}
+
+impl KeyShare {
+    /// Encodes as `securefs-share:<hex>`
+    pub fn encode(&self) -> Zeroizing<String> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(SHARE_LEN));
+        bytes.push(SHARE_VERSION);
+        bytes.push(self.threshold);
+        bytes.push(self.index);
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(self.value.as_ref());
+        let checksum = Sha256::digest(&bytes[..]);
+        bytes.extend_from_slice(&checksum[..4]);
+        Zeroizing::new(format!("{}{}", SHARE_PREFIX, hex::encode(&bytes[..])))
+    }
+
+    /// Parses and checksums an encoded share
+    pub fn parse(s: &str) -> Result<Self, SecureFsError> {
+        let encoded = s
+            .trim()
+            .strip_prefix(SHARE_PREFIX)
+            .ok_or_else(|| SecureFsError::key(format!("share must start with '{}'", SHARE_PREFIX)))?;
+        let bytes = Zeroizing::new(
+            hex::decode(encoded).map_err(|_| SecureFsError::key("share is not valid hex"))?,
+        );
+        if bytes.len() != SHARE_LEN {
+            return Err(SecureFsError::key(format!(
+                "share has wrong length: expected {} bytes but found {}",
+                SHARE_LEN,
+                bytes.len()
+            )));
+        }
+        let checksum = Sha256::digest(&bytes[..SHARE_LEN - 4]);
+        if checksum[..4] != bytes[SHARE_LEN - 4..] {
+            return Err(SecureFsError::key("share checksum mismatch (mistyped or corrupted share)"));
+        }
+        if bytes[0] != SHARE_VERSION {
+            return Err(SecureFsError::key(format!("unsupported share version {}", bytes[0])));
+        }
+
+        let mut key_id = [0u8; 16];
+        key_id.copy_from_slice(&bytes[3..19]);
+        let mut value = Zeroizing::new([0u8; 32]);
+        value.copy_from_slice(&bytes[19..51]);
+        let share = Self {
+            threshold: bytes[1],
+            index: bytes[2],
+            key_id,
+            value,
+        };
+        if share.index == 0 || share.threshold == 0 {
+            return Err(SecureFsError::key("share has invalid index or threshold"));
+        }
+        Ok(share)
+    }
+}
+
+impl fmt::Debug for KeyShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyShare")
+            .field("threshold", &self.threshold)
+            .field("index", &self.index)
+            .field("key_id", &hex::encode(self.key_id))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Splits `secret` into `shares` shares, any `threshold` of which recover it
+pub fn split(secret: &[u8; 32], shares: u8, threshold: u8) -> Result<Vec<KeyShare>, SecureFsError> {
+    if threshold < 2 {
+        return Err(SecureFsError::key("threshold must be at least 2"));
+    }
+    if shares < threshold {
+        return Err(SecureFsError::key(format!(
+            "number of shares ({}) must be at least the threshold ({})",
+            shares, threshold
+        )));
+    }
+
+    let key_id = key_id_for(secret);
+    let mut out: Vec<KeyShare> = (1..=shares)
+        .map(|index| KeyShare {
+            threshold,
+            index,
+            key_id,
+            value: Zeroizing::new([0u8; 32]),
+        })
+        .collect();
+
+    // One random polynomial per byte; coefficient 0 is the secret byte
+    let mut coefficients = Zeroizing::new(vec![0u8; threshold as usize]);
+    for (byte_idx, &secret_byte) in secret.iter().enumerate() {
+        coefficients[0] = secret_byte;
+        OsRng.fill_bytes(&mut coefficients[1..]);
+        for share in out.iter_mut() {
+            share.value[byte_idx] = eval(&coefficients, share.index);
+        }
+    }
+    coefficients.zeroize();
+
+    Ok(out)
+}
+
+/// Recovers the secret from at least `threshold` matching shares.
+/// Verifies the result against the key ID carried by the shares.
+pub fn combine(shares: &[KeyShare]) -> Result<Zeroizing<[u8; 32]>, SecureFsError> {
+    let first = shares
+        .first()
+        .ok_or_else(|| SecureFsError::key("no shares provided"))?;
+
+    for share in shares {
+        if share.key_id != first.key_id {
+            return Err(SecureFsError::key(format!(
+                "share {} belongs to a different key ({} vs {})",
+                share.index,
+                hex::encode(share.key_id),
+                hex::encode(first.key_id)
+            )));
+        }
+        if share.threshold != first.threshold {
+            return Err(SecureFsError::key("shares disagree on the threshold"));
+        }
+    }
+    let mut indices: Vec<u8> = shares.iter().map(|s| s.index).collect();
+    indices.sort_unstable();
+    indices.dedup();
+    if indices.len() != shares.len() {
+        return Err(SecureFsError::key("duplicate share provided"));
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(SecureFsError::key(format!(
+            "need {} shares to recover the key but only {} provided",
+            first.threshold,
+            shares.len()
+        )));
+    }
+
+    // Lagrange interpolation at x = 0 using exactly `threshold` shares
+    let used = &shares[..first.threshold as usize];
+    let mut secret = Zeroizing::new([0u8; 32]);
+    for (i, share_i) in used.iter().enumerate() {
+        let mut basis = 1u8;
+        for (j, share_j) in used.iter().enumerate() {
+            if i != j {
+                // l_i(0) = prod x_j / (x_j - x_i); subtraction is XOR in GF(2^8)
+                basis = gf_mul(basis, gf_div(share_j.index, share_j.index ^ share_i.index));
+            }
+        }
+        for (out, &y) in secret.iter_mut().zip(share_i.value.iter()) {
+            *out ^= gf_mul(basis, y);
+        }
+    }
+
+    if key_id_for(&secret) != first.key_id {
+        return Err(SecureFsError::key(
+            "recovered key does not match the share fingerprint (wrong or corrupted share)",
+        ));
+    }
+    Ok(secret)
+}
+
+/// Evaluates a polynomial at `x` using Horner's rule
+fn eval(coefficients: &[u8], x: u8) -> u8 {
+    coefficients
+        .iter()
+        .rev()
+        .fold(0u8, |acc, &c| gf_mul(acc, x) ^ c)
+}
+
+/// Multiplication in GF(2^8) with the AES polynomial x^8 + x^4 + x^3 + x + 1.
+/// Branch-free so timing does not depend on secret bytes.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// Inverse via a^254 (Fermat), so it is also branch-free
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    gf_mul(a, gf_inv(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret() -> [u8; 32] {
+        let mut s = [0u8; 32];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = (i as u8).wrapping_mul(37).wrapping_add(11);
+        }
+        s
+    }
+
+    #[test]
+    fn gf_inverse_is_correct() {
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1, "inverse of {}", a);
+        }
+    }
+
+    #[test]
+    fn any_threshold_subset_recovers() {
+        let shares = split(&secret(), 5, 3).expect("split");
+        assert_eq!(shares.len(), 5);
+
+        for combo in [[0, 1, 2], [0, 2, 4], [1, 3, 4], [4, 2, 0]] {
+            let subset: Vec<KeyShare> = combo.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(*combine(&subset).expect("combine"), secret());
+        }
+    }
+
+    #[test]
+    fn too_few_or_foreign_shares_fail() {
+        let shares = split(&secret(), 5, 3).expect("split");
+        assert!(combine(&shares[..2]).is_err());
+
+        let other = split(&[0x42u8; 32], 5, 3).expect("split");
+        let mixed = vec![shares[0].clone(), shares[1].clone(), other[2].clone()];
+        let err = combine(&mixed).expect_err("mixed keys must fail");
+        assert!(err.to_string().contains("different key"));
+    }
+
+    #[test]
+    fn encoding_detects_typos() {
+        let share = split(&secret(), 3, 2).expect("split").remove(0);
+        let encoded = share.encode();
+        let parsed = KeyShare::parse(&encoded).expect("parse");
+        assert_eq!(parsed.index, share.index);
+        assert_eq!(*parsed.value, *share.value);
+
+        // Flip one hex digit in the share value
+        let mut typo = encoded.to_string();
+        let pos = SHARE_PREFIX.len() + 50;
+        let flipped = if &typo[pos..pos + 1] == "0" { "1" } else { "0" };
+        typo.replace_range(pos..pos + 1, flipped);
+        let err = KeyShare::parse(&typo).expect_err("typo must fail");
+        assert!(err.to_string().contains("checksum"));
+    }
+}
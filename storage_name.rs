@@ -0,0 +1,206 @@
+//! Validated names for files in the store.
+//!
+//! This module provides [`StorageName`], which every
+//! [`SecureFileOps`](crate::storagefile_ops::SecureFileOps) operation parses
+//! its `name` argument into before touching the filesystem, so a name can
+//! never resolve outside the storage root.
+//!
+//! ## Rules
+//!
+//! A storage name is one or more components separated by `/`, such as
+//! `docs/2026/report.pdf`; each component names a directory below the
+//! storage root except the last. Each component must be non-empty and at
+//! most [`MAX_NAME_LEN`] bytes, and the whole name at most
+//! [`MAX_PATH_LEN`] bytes. A name is rejected if:
+//!
+//! - it is absolute, or a component contains `\`
+//! - a component is `.` or `..`
+//! - it contains a NUL byte
+//! - a component starts with the prefix reserved for SecureFS's own files (`.securefs`)
+
+use crate::error::SecureFsError;
+use crate::util::RESERVED_PREFIX;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Longest accepted component in bytes; the common filesystem limit
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Longest accepted name in bytes, separators included
+pub const MAX_PATH_LEN: usize = 4096;
+
+/// A file name checked to stay inside the storage root
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StorageName(String);
+
+impl StorageName {
+    /// Validates `name`, failing with [`SecureFsError::InvalidName`]
+    pub fn new(name: impl Into<String>) -> Result<Self, SecureFsError> {
+        let name = name.into();
+        match invalid_reason(&name) {
+            Some(reason) => Err(SecureFsError::invalid_name(name, reason)),
+            None => Ok(Self(name)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The `/`-separated components, outermost directory first
+    pub fn components(&self) -> impl Iterator<Item = &str> {
+        self.0.split('/')
+    }
+
+    /// Last component
+    pub fn file_name(&self) -> &str {
+        self.0.rsplit('/').next().expect("BUG: split always yields a component")
+    }
+
+    /// Enclosing directory, or `None` for names directly in the root
+    pub fn parent(&self) -> Option<StorageName> {
+        self.0.rsplit_once('/').map(|(parent, _)| Self(parent.to_string()))
+    }
+
+    /// Whether this name lies inside directory `dir`
+    pub fn starts_with(&self, dir: &StorageName) -> bool {
+        self.0
+            .strip_prefix(&dir.0)
+            .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// Location of the file under `root`
+    pub fn path_in(&self, root: &Path) -> PathBuf {
+        let mut path = root.to_path_buf();
+        path.extend(self.components());
+        path
+    }
+}
+
+fn invalid_reason(name: &str) -> Option<&'static str> {
+    if name.is_empty() {
+        return Some("name is empty");
+    }
+    if name.len() > MAX_PATH_LEN {
+        return Some("name is longer than 4096 bytes");
+    }
+    if name.contains('\0') {
+        return Some("name contains a NUL byte");
+    }
+    if name.starts_with('/') || Path::new(name).is_absolute() || Path::new(name).has_root() {
+        return Some("name must be relative to the storage root");
+    }
+    for component in name.split('/') {
+        if let Some(reason) = invalid_component(component) {
+            return Some(reason);
+        }
+    }
+    None
+}
+
+fn invalid_component(component: &str) -> Option<&'static str> {
+    if component.is_empty() {
+        return Some("name has an empty component (leading, trailing or doubled '/')");
+    }
+    if component.len() > MAX_NAME_LEN {
+        return Some("name has a component longer than 255 bytes");
+    }
+    if component == "." || component == ".." {
+        return Some("name must not contain '.' or '..' components");
+    }
+    if component.contains('\\') {
+        return Some("name must use '/' as its only separator");
+    }
+    // Drive-relative Windows paths such as `C:x` would escape the root
+    if cfg!(windows) && component.contains(':') {
+        return Some("name must not contain ':'");
+    }
+    if component.starts_with(RESERVED_PREFIX) {
+        return Some("names starting with '.securefs' are reserved");
+    }
+    None
+}
+
+impl fmt::Display for StorageName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for StorageName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for StorageName {
+    type Err = SecureFsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<&str> for StorageName {
+    type Error = SecureFsError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for StorageName {
+    type Error = SecureFsError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::new(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_names() {
+        for name in ["report.pdf", ".hidden", "a..b", "with space", "ünïcode.txt", "...", "docs/2026/report.pdf"] {
+            assert_eq!(StorageName::new(name).expect(name).as_str(), name);
+        }
+    }
+
+    #[test]
+    fn splits_hierarchical_names() {
+        let name = StorageName::new("docs/2026/report.pdf").expect("valid");
+        assert_eq!(name.file_name(), "report.pdf");
+        let parent = name.parent().expect("has parent");
+        assert_eq!(parent.as_str(), "docs/2026");
+        assert!(name.starts_with(&parent));
+        assert!(!name.starts_with(&StorageName::new("doc").expect("valid")));
+        assert!(StorageName::new("top.txt").expect("valid").parent().is_none());
+    }
+
+    #[test]
+    fn rejects_escaping_and_reserved_names() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "../../etc/cron.d/x",
+            "/tmp/x",
+            "a//b",
+            "a/",
+            "a/../../b",
+            "a/./b",
+            "a\\b",
+            "nul\0byte",
+            ".securefs-vault.json",
+            ".securefs-tmp-0-x",
+            "docs/.securefs/x",
+        ] {
+            let err = StorageName::new(name).err().unwrap_or_else(|| panic!("{:?} accepted", name));
+            assert!(matches!(err, SecureFsError::InvalidName { .. }));
+        }
+        assert!(StorageName::new("x".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+}
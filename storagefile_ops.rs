@@ -8,68 +8,413 @@
 //! - Buffer and streaming encryption modes
 //! - Optional compression
 //! - Auto-format detection for reading files
-//! - File metadata tracking
+//! - Sealed, authenticated file metadata (see [`crate::metadata`])
+//! - Vault descriptor that rejects the wrong key up front (see [`crate::vault`])
 //! - Concurrent operation support
+//! - Recipient (public-key) encryption for write-only producers
+//! - Content encrypted with purpose-bound subkeys; files written with the
+//!   raw master key by earlier versions remain readable
+//! - Names validated so no operation can escape the storage root
+//!   (see [`crate::storage_name`])
+//! - Subdirectories: `/`-separated names, directory listing and removal
+//! - Optional encrypted names on disk (see [`crate::name_cipher`])
+//! - Resumable re-encryption under a new master key (see [`crate::rotation`])
+//! - Atomic writes, with create-only and compare-and-swap modes (see [`crate::write_mode`])
+//! - Rename and copy; streams are bound to a file ID rather than their name
+//!   (see [`crate::streaming`]), so renames don't re-encrypt
 
 use crate::encryptor::Encryptor;
-use crate::key_manager::KeyManager;
-use crate::metadata::FileMetadata;
-use crate::streaming::{FormatFlags, StreamEncryptor, VERSION_V2_STREAM};
+use crate::error::SecureFsError;
+use crate::key_manager::{subkey, Identity, KeyManager, Recipient};
+use crate::file_keys::{self, DeleteMode, FileKey, KeyId, KeyTable};
+use crate::history::{self, HistoryPolicy, VersionInfo};
+use crate::metadata::{self, ContentDigest, DigestReader, Digested, FileMetadata, MetadataKey};
+use crate::name_cipher::NameCipher;
+use crate::rotation::{self, ContentKeys, RotationReport, PIPE_SIZE};
+use crate::secret::LockedBox;
+use crate::storage_name::StorageName;
+use crate::streaming::{
+    self, FormatFlags, StreamEncryptor, FILE_ID_LEN, FLAG_FILE_ID, VERSION_V2_STREAM, VERSION_V3_RECIPIENTS,
+};
+use crate::trash::{self, TrashEntry, TrashPolicy};
+use crate::util::{self, AtomicFile, FileLock, RESERVED_PREFIX};
+use crate::vault::{VaultDescriptor, VaultOptions};
+use crate::write_mode::{self, FileVersion, WriteMode, WRITE_LOCK_FILE};
 use anyhow::{Context, Result};
+use std::collections::BTreeMap;
 use std::io::Cursor;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use tokio::fs;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tracing::{debug, error, info, warn};
+use zeroize::Zeroizing;
 
 pub struct SecureFileOps {
-    encryptor: Encryptor,
-    stream_encryptor: StreamEncryptor,
+    encryptor: Option<Encryptor>,
+    stream_encryptor: Option<StreamEncryptor>,
+    recipients: Vec<Recipient>,
+    identities: Vec<Identity>,
+    vault: Option<VaultDescriptor>,
+    key_id: Option<[u8; 16]>,
+    names: Option<NameCipher>,
+    metadata: Option<MetadataKey>,
+    history: Option<HistoryPolicy>,
+    trash: Option<TrashPolicy>,
+    key_table: Option<KeyTable>,
+    rotation: Option<RotationKey>,
     root: PathBuf,
     compress: bool,
 }
 
+/// New key of an interrupted rotation (see [`SecureFileOps::with_rotation_key`])
+struct RotationKey {
+    km: KeyManager,
+    /// The store as opened with the new key
+    ops: Box<SecureFileOps>,
+}
+
 impl SecureFileOps {
-    pub fn new(km: KeyManager, root: impl Into<PathBuf>) -> Self {
+    /// Opens the store with the master key.
+    /// If the store has a vault descriptor (see [`crate::vault`]), the key is
+    /// checked against it and a mismatch fails with [`SecureFsError::KeyMismatch`];
+    /// compression defaults to the vault's recorded setting, and names are
+    /// encrypted if the vault was created with encrypted names.
+    pub async fn new(km: KeyManager, root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        let vault = VaultDescriptor::load(&root).await?;
+        match &vault {
+            Some(vault) => vault.verify(&km)?,
+            None => debug!(root = %root.display(), "no vault descriptor; skipping key check"),
+        }
+        if rotation::in_progress(&root).await {
+            warn!(root = %root.display(), "key rotation in progress; files already rotated need the new key (see with_rotation_key)");
+        }
+        let mut ops = Self::with_key(&km, root);
+        ops.compress = vault.as_ref().is_some_and(|v| v.settings.compression);
+        if vault.as_ref().is_some_and(|v| v.settings.encrypted_names) {
+            ops.names = Some(NameCipher::new(&km));
+        }
+        ops.vault = vault;
+        Ok(ops)
+    }
+
+    /// Creates a vault: writes the descriptor binding `root` to `km`.
+    /// Fails if a descriptor already exists, or if `root` already holds files
+    /// encrypted with a different key.
+    pub async fn init(km: KeyManager, root: impl Into<PathBuf>, compression: bool) -> Result<Self> {
+        Self::init_with_options(
+            km,
+            root,
+            VaultOptions {
+                compression,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Creates a vault with `options` (see [`Self::init`]).
+    /// Encrypted names can only be chosen for a directory without files.
+    pub async fn init_with_options(km: KeyManager, root: impl Into<PathBuf>, options: VaultOptions) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .await
+            .with_context(|| format!("creating storage directory {:?}", &root))?;
+        let mut ops = Self::with_key(&km, root);
+        if ops.verify_key().await? == Some(false) {
+            return Err(SecureFsError::key(format!(
+                "{:?} already holds files encrypted with a different key",
+                &ops.root
+            ))
+            .into());
+        }
+        if options.encrypted_names && !ops.list_files().await?.is_empty() {
+            return Err(SecureFsError::storage(format!(
+                "{:?} already holds files with plaintext names; encrypted names need an empty directory",
+                &ops.root
+            ))
+            .into());
+        }
+
+        let vault = VaultDescriptor::with_options(&km, options);
+        vault.write(&ops.root).await?;
+        ops.compress = options.compression;
+        if options.encrypted_names {
+            ops.names = Some(NameCipher::new(&km));
+        }
+        ops.vault = Some(vault);
+        Ok(ops)
+    }
+
+    fn with_key(km: &KeyManager, root: PathBuf) -> Self {
+        let keys = content_keys(km);
         Self {
-            encryptor: Encryptor::new(km.cipher()),
-            stream_encryptor: StreamEncryptor::new(km.cipher()),
-            root: root.into(),
+            encryptor: Some(keys.encryptor),
+            stream_encryptor: Some(keys.stream),
+            recipients: Vec::new(),
+            identities: Vec::new(),
+            vault: None,
+            key_id: Some(km.key_id()),
+            names: None,
+            metadata: Some(keys.metadata),
+            history: None,
+            trash: None,
+            key_table: None,
+            rotation: None,
+            root,
             compress: false,
         }
     }
 
+    /// Opens the store without a master key.
+    /// Only recipient writes (see [`Self::with_recipients`]) and identity reads
+    /// (see [`Self::with_identity`]) are available; master-key operations fail.
+    /// Compression defaults to the vault's recorded setting. Stores with
+    /// encrypted names are refused, as names need the master key: a file
+    /// written here would be stored under its plaintext name.
+    pub async fn keyless(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        let vault = VaultDescriptor::load(&root).await?;
+        if vault.as_ref().is_some_and(|v| v.settings.encrypted_names) {
+            return Err(SecureFsError::storage(format!(
+                "{:?} encrypts file names, which needs the master key; it can't be opened without one",
+                &root
+            ))
+            .into());
+        }
+        Ok(Self {
+            encryptor: None,
+            stream_encryptor: None,
+            recipients: Vec::new(),
+            identities: Vec::new(),
+            key_id: None,
+            names: None,
+            metadata: None,
+            history: None,
+            trash: None,
+            key_table: None,
+            rotation: None,
+            compress: vault.as_ref().is_some_and(|v| v.settings.compression),
+            vault,
+            root,
+        })
+    }
+
     pub fn with_compression(mut self, compress: bool) -> Self {
         self.compress = compress;
         self
     }
 
-    pub async fn write_encrypted(&self, name: &str, data: &[u8]) -> Result<()> {
-        debug!(file = name, size = data.len(), compress = self.compress, "encrypting file (buffer mode)");
-        fs::create_dir_all(&self.root).await?;
-        let path = self.root.join(name);
-        let enc = if self.compress {
-            self.encryptor.encrypt_compressed(data, None)?
-        } else {
-            self.encryptor.encrypt(data, None)?
+    /// Keep replaced versions of files, pruned by `policy` (see [`crate::history`])
+    pub fn with_history(mut self, policy: HistoryPolicy) -> Self {
+        self.history = Some(policy);
+        self
+    }
+
+    /// Move deleted files to the trash, purged by `policy` (see [`crate::trash`])
+    pub fn with_trash(mut self, policy: TrashPolicy) -> Self {
+        self.trash = Some(policy);
+        self
+    }
+
+    /// Encrypt new files under keys of their own, kept in `table`, so they
+    /// can be shredded (see [`crate::file_keys`])
+    pub fn with_key_table(mut self, table: KeyTable) -> Self {
+        if let Some(rotation) = &mut self.rotation {
+            rotation.ops.key_table = Some(KeyTable::new(&rotation.km, table.path()).reading_previous(&table));
+        }
+        self.key_table = Some(table);
+        self
+    }
+
+    /// Reads the files that an interrupted [`Self::reencrypt_all`] to
+    /// `new_key` has already re-encrypted, as recorded in its journal, with
+    /// `new_key`; the rest are read with this store's key. Writes fail until
+    /// the rotation is finished (see [`crate::rotation`]).
+    pub fn with_rotation_key(mut self, new_key: KeyManager) -> Self {
+        let mut ops = Self::with_key(&new_key, self.root.clone());
+        ops.names = self.names.as_ref().map(|_| NameCipher::new(&new_key));
+        ops.key_table = self
+            .key_table
+            .as_ref()
+            .map(|table| KeyTable::new(&new_key, table.path()).reading_previous(table));
+        ops.compress = self.compress;
+        ops.vault = self.vault.clone();
+        self.rotation = Some(RotationKey {
+            km: new_key,
+            ops: Box::new(ops),
+        });
+        self
+    }
+
+    /// Whether new files are compressed
+    pub fn compression(&self) -> bool {
+        self.compress
+    }
+
+    /// The vault descriptor, if the store has one
+    pub fn vault(&self) -> Option<&VaultDescriptor> {
+        self.vault.as_ref()
+    }
+
+    /// Encrypt all new files to these recipients (V3 format) instead of the master key
+    pub fn with_recipients(mut self, recipients: Vec<Recipient>) -> Self {
+        self.recipients = recipients;
+        self
+    }
+
+    /// Add an identity used to decrypt V3 recipient files
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.identities.push(identity);
+        self
+    }
+
+    fn encryptor(&self) -> Result<&Encryptor> {
+        self.encryptor
+            .as_ref()
+            .ok_or_else(|| SecureFsError::key("store was opened without a master key").into())
+    }
+
+    fn stream_encryptor(&self) -> Result<&StreamEncryptor> {
+        self.stream_encryptor
+            .as_ref()
+            .ok_or_else(|| SecureFsError::key("store was opened without a master key").into())
+    }
+
+    /// Encrypts a stream with `ciphers` (V2) or to the configured recipients
+    /// (V3), bound to a new file ID rather than its name
+    async fn encrypt_to<R, W>(&self, reader: &mut R, writer: &mut W, ciphers: &FileCiphers) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let flags = FormatFlags {
+            compressed: self.compress,
+            file_id: Some(streaming::generate_file_id()),
         };
-        fs::write(&path, &enc).await?;
-        FileMetadata::record(&path, data.len() as u64).await?;
-        info!(file = name, original_size = data.len(), encrypted_size = enc.len(), "file encrypted successfully");
+        if self.recipients.is_empty() {
+            ciphers.stream()?.encrypt_stream(reader, writer, flags, None).await
+        } else {
+            StreamEncryptor::encrypt_stream_to_recipients(reader, writer, &self.recipients, flags, None).await
+        }
+    }
+
+    /// Decrypts a versioned (V2 or V3) stream, dispatching on the version byte
+    async fn decrypt_versioned<R, W>(
+        &self,
+        version: u8,
+        reader: &mut R,
+        writer: &mut W,
+        aad: Option<&[u8]>,
+        ciphers: &FileCiphers,
+    ) -> Result<(u64, FormatFlags)>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if version == VERSION_V3_RECIPIENTS {
+            if self.identities.is_empty() {
+                return Err(SecureFsError::key("file is encrypted to recipients but no identity was provided").into());
+            }
+            StreamEncryptor::decrypt_stream_with_identities(reader, writer, &self.identities, aad).await
+        } else {
+            ciphers.stream()?.decrypt_stream(reader, writer, aad).await
+        }
+    }
+
+    /// Ciphers for a new file: under a fresh key of its own if the store has
+    /// a key table and the file isn't for recipients, otherwise the master key's
+    fn new_file_ciphers(&self) -> (Option<FileKey>, FileCiphers) {
+        match &self.key_table {
+            Some(_) if self.recipients.is_empty() => {
+                let key = FileKey::generate();
+                let ciphers = FileCiphers::own(&key.key);
+                (Some(key), ciphers)
+            }
+            _ => (None, self.master_ciphers()),
+        }
+    }
+
+    /// Ciphers for the file starting with `header`: its own key's if it has
+    /// one, otherwise the master key's. Fails with
+    /// [`SecureFsError::FileKeyMissing`] if its key isn't in the key table,
+    /// unless `header` is a whole V1 file that starts like one by chance.
+    async fn file_ciphers(&self, header: &[u8]) -> Result<FileCiphers> {
+        if let (Some(table), Some(id)) = (&self.key_table, file_keys::key_id_of(header)) {
+            if let Some(key) = table.get(&id).await? {
+                return Ok(FileCiphers::own(&key));
+            }
+            let lookalike = self
+                .encryptor
+                .as_ref()
+                .is_some_and(|encryptor| encryptor.decrypt(header, None).is_ok());
+            if !lookalike {
+                return Err(SecureFsError::FileKeyMissing(hex::encode(id)).into());
+            }
+        }
+        Ok(self.master_ciphers())
+    }
+
+    fn master_ciphers(&self) -> FileCiphers {
+        FileCiphers {
+            encryptor: self.encryptor.clone(),
+            stream: self.stream_encryptor.clone(),
+            offset: 0,
+        }
+    }
+
+    /// Validates `name` and maps it to its path under the root (see [`resolve_in`])
+    async fn resolve(&self, name: &str, create_parents: bool) -> Result<PathBuf> {
+        resolve_in(&self.root, self.names.as_ref(), &StorageName::new(name)?, create_parents).await
+    }
+
+    /// Encrypts `data` into `name`. The file and its metadata are each
+    /// replaced atomically (see [`AtomicFile`]), so a crash leaves either the
+    /// previous version or the new one.
+    pub async fn write_encrypted(&self, name: &str, data: &[u8]) -> Result<()> {
+        self.write_encrypted_with(name, data, WriteMode::Overwrite).await?;
         Ok(())
     }
 
+    /// Like [`Self::write_encrypted`], but only replaces an existing file as
+    /// `mode` allows (see [`crate::write_mode`]). Returns the new version.
+    pub async fn write_encrypted_with(&self, name: &str, data: &[u8], mode: WriteMode) -> Result<FileVersion> {
+        debug!(file = name, size = data.len(), compress = self.compress, ?mode, "encrypting file (buffer mode)");
+        let path = self.resolve(name, true).await?;
+        write_mode::check(mode, name, &path).await?;
+        let (file_key, ciphers) = self.new_file_ciphers();
+        let mut enc = file_key.as_ref().map(|key| key.header().to_vec()).unwrap_or_default();
+        if !self.recipients.is_empty() {
+            self.encrypt_to(&mut Cursor::new(data), &mut enc, &ciphers).await?;
+        } else if self.compress {
+            enc.extend(ciphers.encryptor()?.encrypt_compressed(data, None)?);
+        } else {
+            enc.extend(ciphers.encryptor()?.encrypt(data, None)?);
+        }
+        let mut out = AtomicFile::create(&path).await?;
+        out.file()
+            .write_all(&enc)
+            .await
+            .with_context(|| format!("writing {:?}", &path))?;
+        let version = self
+            .commit_write(out, name, &path, mode, ContentDigest::of(data), file_key.as_ref())
+            .await?;
+        info!(file = name, original_size = data.len(), encrypted_size = enc.len(), %version, "file encrypted successfully");
+        Ok(version)
+    }
+
     pub async fn read_encrypted(&self, name: &str) -> Result<Vec<u8>> {
+        if let Some(rotated) = self.rotated(name).await? {
+            return Box::pin(rotated.read_encrypted(name)).await;
+        }
         debug!(file = name, "decrypting file (buffer mode)");
-        let path = self.root.join(name);
+        let path = self.resolve(name, false).await?;
         let data = fs::read(&path)
             .await
             .with_context(|| format!("reading {:?}", &path))?;
-        let result = if self.compress {
-            self.encryptor.decrypt_compressed(&data, None)
-        } else {
-            self.encryptor.decrypt(&data, None)
-        };
+        let ciphers = self.file_ciphers(&data).await?;
+        let result = self.decrypt_v1(&data[ciphers.offset..], &ciphers);
         match &result {
             Ok(plaintext) => info!(file = name, encrypted_size = data.len(), decrypted_size = plaintext.len(), "file decrypted successfully"),
             Err(e) => error!(file = name, error = %e, "decryption failed"),
@@ -80,6 +425,8 @@ impl SecureFileOps {
     /// Write encrypted data from a stream source (for large files)
     /// Uses chunked encryption to avoid loading entire file into memory
     /// Recommended for files > 10MB
+    /// Replaces any previous version atomically, like [`Self::write_encrypted`];
+    /// if `reader` fails part way, the previous version is kept.
     pub async fn write_encrypted_stream<R>(
         &self,
         name: &str,
@@ -88,29 +435,42 @@ impl SecureFileOps {
     where
         R: AsyncRead + Unpin,
     {
-        debug!(file = name, compress = self.compress, "encrypting file (streaming mode)");
-        fs::create_dir_all(&self.root).await?;
-        let path = self.root.join(name);
-
-        let mut file = fs::File::create(&path).await
-            .with_context(|| format!("creating {:?}", &path))?;
+        let (bytes_written, _) = self.write_encrypted_stream_with(name, reader, WriteMode::Overwrite).await?;
+        Ok(bytes_written)
+    }
 
-        let flags = FormatFlags {
-            compressed: self.compress,
-        };
+    /// Like [`Self::write_encrypted_stream`], but only replaces an existing
+    /// file as `mode` allows (see [`crate::write_mode`]). Returns the bytes
+    /// written and the new version.
+    pub async fn write_encrypted_stream_with<R>(
+        &self,
+        name: &str,
+        reader: &mut R,
+        mode: WriteMode,
+    ) -> Result<(u64, FileVersion)>
+    where
+        R: AsyncRead + Unpin,
+    {
+        debug!(file = name, compress = self.compress, ?mode, "encrypting file (streaming mode)");
+        let path = self.resolve(name, true).await?;
+        // Fail fast; checked again before the file is replaced
+        write_mode::check(mode, name, &path).await?;
 
-        // Use filename as AAD for tamper detection
-        let aad = name.as_bytes();
+        let mut out = AtomicFile::create(&path).await?;
+        let (file_key, ciphers) = self.new_file_ciphers();
+        if let Some(key) = &file_key {
+            out.file().write_all(&key.header()).await?;
+        }
 
-        let bytes_written = self.stream_encryptor
-            .encrypt_stream(reader, &mut file, flags, Some(aad))
+        // On error the temporary file is dropped and any previous version stays
+        let mut reader = DigestReader::new(reader);
+        let bytes_written = self.encrypt_to(&mut reader, out.file(), &ciphers).await?;
+        let version = self
+            .commit_write(out, name, &path, mode, reader.finish(), file_key.as_ref())
             .await?;
 
-        // Record metadata
-        FileMetadata::record(&path, bytes_written).await?;
-
-        info!(file = name, bytes = bytes_written, "file encrypted successfully (streaming)");
-        Ok(bytes_written)
+        info!(file = name, bytes = bytes_written, %version, "file encrypted successfully (streaming)");
+        Ok((bytes_written, version))
     }
 
     /// Read and decrypt data to a stream destination (for large files)
@@ -124,16 +484,30 @@ impl SecureFileOps {
     where
         W: AsyncWrite + Unpin,
     {
+        if let Some(rotated) = self.rotated(name).await? {
+            return Box::pin(rotated.read_encrypted_stream(name, writer)).await;
+        }
         debug!(file = name, "decrypting file (streaming mode)");
-        let path = self.root.join(name);
+        let path = self.resolve(name, false).await?;
         let mut file = fs::File::open(&path).await
             .with_context(|| format!("opening {:?}", &path))?;
 
-        // Use filename as AAD for tamper detection
-        let aad = name.as_bytes();
+        // Peek at the header, then seek back to the content for the decryptor
+        let mut header = Vec::with_capacity(file_keys::HEADER_LEN + 2);
+        (&mut file)
+            .take(file_keys::HEADER_LEN as u64 + 2)
+            .read_to_end(&mut header)
+            .await
+            .with_context(|| format!("reading header of {:?}", &path))?;
+        let ciphers = self.file_ciphers(&header).await?;
+        let version = *header
+            .get(ciphers.offset)
+            .ok_or_else(|| SecureFsError::format(format!("{:?} is truncated", name)))?;
+        file.seek(std::io::SeekFrom::Start(ciphers.offset as u64)).await?;
+        let aad = content_aad(&header[ciphers.offset..], name);
 
-        let (bytes_read, flags) = self.stream_encryptor
-            .decrypt_stream(&mut file, writer, Some(aad))
+        let (bytes_read, flags) = self
+            .decrypt_versioned(version, &mut file, writer, aad, &ciphers)
             .await?;
 
         info!(file = name, bytes = bytes_read, compressed = flags.compressed, "file decrypted successfully (streaming)");
@@ -143,10 +517,20 @@ impl SecureFileOps {
     /// Auto-detecting read: determines format (V1 buffer or V2 streaming) and decrypts accordingly.
     /// Returns decrypted data and whether the file was compressed.
     pub async fn read_encrypted_auto(&self, name: &str) -> Result<(Vec<u8>, bool)> {
-        let path = self.root.join(name);
-        let data = fs::read(&path)
+        if let Some(rotated) = self.rotated(name).await? {
+            return Box::pin(rotated.read_encrypted_auto(name)).await;
+        }
+        let path = self.resolve(name, false).await?;
+        self.decrypt_file_auto(&path, name).await
+    }
+
+    /// Decrypts the file at `path`, stored as `name`, detecting its format
+    async fn decrypt_file_auto(&self, path: &Path, name: &str) -> Result<(Vec<u8>, bool)> {
+        let file = fs::read(path)
             .await
             .with_context(|| format!("reading {:?}", &path))?;
+        let ciphers = self.file_ciphers(&file).await?;
+        let data = &file[ciphers.offset..];
 
         if data.is_empty() {
             anyhow::bail!("encrypted file is empty");
@@ -156,34 +540,48 @@ impl SecureFileOps {
         let format_version = data[0];
         debug!(file = name, format_version, "auto-detecting file format");
 
-        if format_version == VERSION_V2_STREAM {
-            // V2 streaming format - use streaming decryptor
-            info!(file = name, "detected V2 streaming format");
+        if format_version == VERSION_V2_STREAM || format_version == VERSION_V3_RECIPIENTS {
+            // V2 streaming or V3 recipient format - use streaming decryptor
+            info!(file = name, format_version, "detected versioned streaming format");
             let mut reader = Cursor::new(data);
             let mut output = Vec::new();
 
-            // Use filename as AAD for tamper detection (matches streaming write)
-            let aad = name.as_bytes();
+            let aad = content_aad(data, name);
 
-            let (bytes_read, flags) = self.stream_encryptor
-                .decrypt_stream(&mut reader, &mut output, Some(aad))
-                .await?;
-
-            info!(file = name, bytes = bytes_read, compressed = flags.compressed, "V2 file decrypted successfully");
-            Ok((output, flags.compressed))
+            // A V1 nonce can start with a version byte by chance
+            match self
+                .decrypt_versioned(format_version, &mut reader, &mut output, aad, &ciphers)
+                .await
+            {
+                Ok((bytes_read, flags)) => {
+                    info!(file = name, bytes = bytes_read, compressed = flags.compressed, "versioned file decrypted successfully");
+                    Ok((output, flags.compressed))
+                }
+                Err(e) => {
+                    let result = self.decrypt_v1(data, &ciphers).map_err(|_| e)?;
+                    debug!(file = name, "V1 file whose nonce starts with a version byte");
+                    Ok((result, self.compress))
+                }
+            }
         } else {
             // V1 legacy buffer format - first 24 bytes are nonce
             info!(file = name, "detected V1 legacy format");
-            let result = if self.compress {
-                self.encryptor.decrypt_compressed(&data, None)?
-            } else {
-                self.encryptor.decrypt(&data, None)?
-            };
+            let result = self.decrypt_v1(data, &ciphers)?;
             info!(file = name, encrypted_size = data.len(), decrypted_size = result.len(), "V1 file decrypted successfully");
             Ok((result, self.compress))
         }
     }
 
+    /// Decrypts a whole V1 buffer, decompressing it if compression is on
+    fn decrypt_v1(&self, data: &[u8], ciphers: &FileCiphers) -> Result<Vec<u8>> {
+        let encryptor = ciphers.encryptor()?;
+        if self.compress {
+            encryptor.decrypt_compressed(data, None)
+        } else {
+            encryptor.decrypt(data, None)
+        }
+    }
+
     /// Auto-detecting stream read: determines format and streams decrypted output.
     /// Returns bytes written and compression flag.
     pub async fn read_encrypted_stream_auto<W>(
@@ -194,10 +592,15 @@ impl SecureFileOps {
     where
         W: AsyncWrite + Unpin,
     {
-        let path = self.root.join(name);
-        let data = fs::read(&path)
+        if let Some(rotated) = self.rotated(name).await? {
+            return Box::pin(rotated.read_encrypted_stream_auto(name, writer)).await;
+        }
+        let path = self.resolve(name, false).await?;
+        let file = fs::read(&path)
             .await
             .with_context(|| format!("reading {:?}", &path))?;
+        let ciphers = self.file_ciphers(&file).await?;
+        let data = &file[ciphers.offset..];
 
         if data.is_empty() {
             anyhow::bail!("encrypted file is empty");
@@ -207,46 +610,86 @@ impl SecureFileOps {
         let format_version = data[0];
         debug!(file = name, format_version, "auto-detecting file format for stream read");
 
-        if format_version == VERSION_V2_STREAM {
-            // V2 streaming format
-            info!(file = name, "detected V2 streaming format");
-            let mut reader = Cursor::new(data);
-            let aad = name.as_bytes();
-
-            let (bytes_read, flags) = self.stream_encryptor
-                .decrypt_stream(&mut reader, writer, Some(aad))
-                .await?;
+        let result = if format_version == VERSION_V2_STREAM || format_version == VERSION_V3_RECIPIENTS {
+            // V2 streaming or V3 recipient format
+            info!(file = name, format_version, "detected versioned streaming format");
+            let aad = content_aad(data, name);
 
-            info!(file = name, bytes = bytes_read, compressed = flags.compressed, "V2 file decrypted to stream");
-            Ok((bytes_read, flags.compressed))
+            // A V1 nonce can start with a version byte by chance. Such a file
+            // fails on its first chunk, whose random length is out of bounds
+            // or which fails authentication, before any output is written.
+            match self
+                .decrypt_versioned(format_version, &mut Cursor::new(data), writer, aad, &ciphers)
+                .await
+            {
+                Ok((bytes_read, flags)) => {
+                    info!(file = name, bytes = bytes_read, compressed = flags.compressed, "versioned file decrypted to stream");
+                    return Ok((bytes_read, flags.compressed));
+                }
+                Err(e) => {
+                    let result = self.decrypt_v1(data, &ciphers).map_err(|_| e)?;
+                    debug!(file = name, "V1 file whose nonce starts with a version byte");
+                    result
+                }
+            }
         } else {
             // V1 legacy buffer format
             info!(file = name, "detected V1 legacy format");
-            let result = if self.compress {
-                self.encryptor.decrypt_compressed(&data, None)?
-            } else {
-                self.encryptor.decrypt(&data, None)?
-            };
+            self.decrypt_v1(data, &ciphers)?
+        };
 
-            writer.write_all(&result).await?;
-            writer.flush().await?;
+        writer.write_all(&result).await?;
+        writer.flush().await?;
 
-            info!(file = name, bytes = result.len(), "V1 file decrypted to stream");
-            Ok((result.len() as u64, self.compress))
-        }
+        info!(file = name, bytes = result.len(), "V1 file decrypted to stream");
+        Ok((result.len() as u64, self.compress))
     }
 
-    /// Check if an encrypted file exists
+    /// Check if an encrypted file (or directory) exists.
+    /// Invalid names (see [`StorageName`]) never exist.
     pub async fn exists(&self, name: &str) -> bool {
-        let path = self.root.join(name);
-        fs::try_exists(&path).await.unwrap_or(false)
+        if let Ok(Some(rotated)) = self.rotated(name).await {
+            return Box::pin(rotated.exists(name)).await;
+        }
+        match self.resolve(name, false).await {
+            Ok(path) => fs::try_exists(&path).await.unwrap_or(false),
+            Err(_) => false,
+        }
     }
 
-    /// Delete an encrypted file and its metadata
+    /// Delete an encrypted file, its metadata and its version history, or
+    /// move them to the trash (see [`Self::with_trash`])
     pub async fn delete_file(&self, name: &str) -> Result<()> {
-        info!(file = name, "deleting encrypted file");
-        let path = self.root.join(name);
-        let meta_path = path.with_extension("meta.json");
+        self.delete_file_with(name, DeleteMode::Remove).await
+    }
+
+    /// Like [`Self::delete_file`]; with [`DeleteMode::Shred`], first destroys
+    /// the keys of the file and its archived versions (see [`crate::file_keys`])
+    pub async fn delete_file_with(&self, name: &str, mode: DeleteMode) -> Result<()> {
+        info!(file = name, ?mode, "deleting encrypted file");
+        let path = self.resolve(name, false).await?;
+        let meta_path = metadata::sidecar_path(&self.root, &path);
+        let _lock = self.write_lock().await?;
+
+        if fs::symlink_metadata(&path).await.is_ok_and(|m| m.is_dir()) {
+            return Err(SecureFsError::storage(format!(
+                "'{}' is a directory; use remove_dir to delete it",
+                name
+            ))
+            .into());
+        }
+
+        let mut keys = Vec::new();
+        match mode {
+            DeleteMode::Shred => self.shred(name, &path).await?,
+            DeleteMode::Remove if self.trash.is_some() && fs::try_exists(&path).await? => {
+                return self.move_to_trash(name, &path).await;
+            }
+            DeleteMode::Remove => {
+                keys.extend(self.key_ids(&path).await?);
+                keys.extend(self.history_key_ids(&path).await?);
+            }
+        }
 
         // Delete encrypted file
         if fs::try_exists(&path).await.unwrap_or(false) {
@@ -262,65 +705,1021 @@ impl SecureFileOps {
             fs::remove_file(&meta_path).await.ok(); // Best effort, don't fail if missing
             debug!(file = name, "metadata file deleted");
         }
+        history::remove_all(&self.root, &path).await?;
+        self.destroy_keys(&keys).await?;
 
         info!(file = name, "file deletion complete");
         Ok(())
     }
 
-    /// List all encrypted files in storage
-    /// Returns a vector of (filename, size_bytes, has_metadata) tuples
+    /// Destroys the keys of the file `name` at `path` and of its archived
+    /// versions. The caller holds the write lock.
+    async fn shred(&self, name: &str, path: &Path) -> Result<()> {
+        let table = self
+            .key_table
+            .as_ref()
+            .ok_or_else(|| SecureFsError::key("shredding needs a key table (see SecureFileOps::with_key_table)"))?;
+        let header = metadata::content_header(path).await?;
+        let own_key = match file_keys::key_id_of(&header) {
+            Some(id) => table.get(&id).await?.is_some(),
+            None => false,
+        };
+        if !own_key {
+            return Err(SecureFsError::key(format!(
+                "{:?} is encrypted under the master key, not a key of its own, so it can't be shredded",
+                name
+            ))
+            .into());
+        }
+        let mut keys = self.key_ids(path).await?;
+        let archived = history::entries(&self.root, path).await?.len();
+        let history_keys = self.history_key_ids(path).await?;
+        if history_keys.len() < archived {
+            warn!(file = name, "some archived versions predate the key table and can't be shredded");
+        }
+        keys.extend(history_keys);
+        let destroyed = self.destroy_keys(&keys).await?;
+        info!(file = name, keys = destroyed, "file keys destroyed");
+        Ok(())
+    }
+
+    /// Renames file `from` to `to`, with its metadata and version history,
+    /// failing with [`SecureFsError::AlreadyExists`] if `to` exists. Files are
+    /// bound to a file ID rather than their name, so they are moved, not
+    /// re-encrypted; only streams written before file IDs are re-encrypted
+    /// to get one (see [`crate::streaming`]).
+    pub async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        info!(from, to, "renaming file");
+        let src = self.resolve(from, false).await?;
+        let meta = fs::symlink_metadata(&src)
+            .await
+            .with_context(|| format!("inspecting {:?}", &src))?;
+        if meta.is_dir() {
+            return Err(SecureFsError::storage(format!("'{}' is a directory; only files can be renamed", from)).into());
+        }
+        let dest = self.resolve(to, true).await?;
+        let _lock = self.write_lock().await?;
+        if fs::try_exists(&dest).await? {
+            return Err(SecureFsError::AlreadyExists(to.to_string()).into());
+        }
+
+        // Archived versions first, so a failure leaves the file under its old name
+        let history = history::history_dir(&self.root, &dest);
+        for entry in history::entries(&self.root, &src).await? {
+            let moved = history.join(entry.path.file_name().expect("BUG: entries have a file name"));
+            self.move_file((&entry.path, from), (&moved, to), &entry.meta_path(), &history::meta_path(&moved))
+                .await?;
+        }
+        // Only succeeds once nothing else is left in it
+        let _ = fs::remove_dir(history::history_dir(&self.root, &src)).await;
+        self.move_file(
+            (&src, from),
+            (&dest, to),
+            &metadata::sidecar_path(&self.root, &src),
+            &metadata::sidecar_path(&self.root, &dest),
+        )
+        .await?;
+        util::sync_parent(&src).await?;
+        util::sync_parent(&dest).await?;
+        info!(from, to, "file renamed");
+        Ok(())
+    }
+
+    /// Copies file `from` to `to`, failing with [`SecureFsError::AlreadyExists`]
+    /// if `to` exists. The copy is encrypted anew, with its own file ID (and
+    /// its own key, with a key table), as a stream; its history starts empty.
+    /// Tags and the source path are copied along with the content.
+    pub async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        info!(from, to, "copying file");
+        let (tx, mut rx) = tokio::io::duplex(PIPE_SIZE);
+        let read = async {
+            let mut tx = tx;
+            let result = self.read_encrypted_stream_auto(from, &mut tx).await;
+            // Closing the pipe ends the writing side
+            drop(tx);
+            result
+        };
+        let write = self.write_encrypted_stream_with(to, &mut rx, WriteMode::CreateNew);
+        let (_, (bytes, _)) = tokio::try_join!(read, write)?;
+        if let Ok(source) = self.get_metadata(from).await {
+            self.update_metadata(to, |meta| {
+                meta.tags = source.tags;
+                meta.source_path = source.source_path;
+            })
+            .await?;
+        }
+        info!(from, to, bytes, "file copied");
+        Ok(())
+    }
+
+    /// Moves the encrypted file at `path`, stored as `name`, to `dest` as
+    /// `dest_name`, and its sidecar from `meta` to `dest_meta`, resealed
+    /// for the new name. Sidecars that don't open are dropped.
+    async fn move_file(
+        &self,
+        (path, name): (&Path, &str),
+        (dest, dest_name): (&Path, &str),
+        meta: &Path,
+        dest_meta: &Path,
+    ) -> Result<()> {
+        let mut file_meta = match fs::read(meta).await {
+            Ok(sidecar) => {
+                let key = self.metadata.as_ref().ok_or_else(|| {
+                    SecureFsError::key("metadata is sealed with the master key, which this store was opened without")
+                })?;
+                let header = metadata::content_header(path).await?;
+                match key.open(name, &sidecar, &header) {
+                    Ok(file_meta) => Some(file_meta),
+                    Err(e) => {
+                        warn!(file = name, error = %e, "dropping metadata that doesn't open");
+                        None
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(anyhow::Error::new(e).context(format!("reading metadata {:?}", meta))),
+        };
+
+        util::create_parent(dest).await?;
+        if self.rebind(path, name, dest).await? {
+            fs::remove_file(path)
+                .await
+                .with_context(|| format!("removing {:?}", path))?;
+        } else {
+            fs::rename(path, dest)
+                .await
+                .with_context(|| format!("moving {:?} to {:?}", path, dest))?;
+        }
+
+        if let (Some(file_meta), Some(key)) = (&mut file_meta, &self.metadata) {
+            file_meta.filename = dest_name.to_string();
+            let sealed = key.seal(dest_name, file_meta, &metadata::content_header(dest).await?)?;
+            util::create_parent(dest_meta).await?;
+            let mut out = AtomicFile::create(dest_meta).await?;
+            out.file().write_all(&sealed).await?;
+            out.commit().await?;
+        }
+        match fs::remove_file(meta).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(anyhow::Error::new(e).context(format!("removing {:?}", meta)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Re-encrypts the stream at `path`, stored as `name` and bound to that
+    /// name, into `dest` with a file ID, under the same key. Returns `false`,
+    /// writing nothing, for files not bound to their name.
+    async fn rebind(&self, path: &Path, name: &str, dest: &Path) -> Result<bool> {
+        let mut src = fs::File::open(path)
+            .await
+            .with_context(|| format!("opening {:?}", path))?;
+        let mut header = Vec::with_capacity(file_keys::HEADER_LEN + 2 + FILE_ID_LEN);
+        (&mut src)
+            .take((file_keys::HEADER_LEN + 2 + FILE_ID_LEN) as u64)
+            .read_to_end(&mut header)
+            .await
+            .with_context(|| format!("reading header of {:?}", path))?;
+        let ciphers = match self.file_ciphers(&header).await {
+            Ok(ciphers) => ciphers,
+            // Nothing to rebind with; the file is moved as it is
+            Err(e) if matches!(e.downcast_ref(), Some(SecureFsError::FileKeyMissing(_))) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let content = &header[ciphers.offset.min(header.len())..];
+        let flags = match content {
+            [VERSION_V2_STREAM, flags, ..] if flags & FLAG_FILE_ID == 0 => *flags,
+            [VERSION_V3_RECIPIENTS, flags, ..] if flags & FLAG_FILE_ID == 0 && rotation::is_recipient_header(content) => {
+                return Err(SecureFsError::format(format!(
+                    "{:?} was encrypted to recipients before file IDs and is bound to its name; \
+                     it can't be renamed without its recipients re-encrypting it",
+                    name
+                ))
+                .into());
+            }
+            _ => return Ok(false),
+        };
+        debug!(file = name, "re-encrypting stream bound to its name");
+
+        src.seek(std::io::SeekFrom::Start(ciphers.offset as u64)).await?;
+        let mut out = AtomicFile::create(dest).await?;
+        out.file().write_all(&header[..ciphers.offset]).await?;
+        let flags = FormatFlags {
+            file_id: Some(streaming::generate_file_id()),
+            ..FormatFlags::from_byte(flags)
+        };
+        let (tx, mut rx) = tokio::io::duplex(PIPE_SIZE);
+        let decrypt = async {
+            let mut tx = tx;
+            let result = ciphers.stream()?.decrypt_stream(&mut src, &mut tx, Some(name.as_bytes())).await;
+            // Closing the pipe ends the encrypting side
+            drop(tx);
+            result
+        };
+        let encrypt = async { ciphers.stream()?.encrypt_stream(&mut rx, out.file(), flags, None).await };
+        match tokio::try_join!(decrypt, encrypt) {
+            Ok(_) => {
+                out.commit().await?;
+                Ok(true)
+            }
+            Err(e) => {
+                drop(out);
+                // A V1 nonce can start with a version byte by chance
+                let data = fs::read(path).await?;
+                if self.decrypt_v1(&data[ciphers.offset..], &ciphers).is_ok() {
+                    return Ok(false);
+                }
+                Err(e.context(format!("re-encrypting {:?}", name)))
+            }
+        }
+    }
+
+    /// List all encrypted files in storage, including subdirectories
+    /// Returns a vector of (name, size_bytes, has_metadata) tuples, where
+    /// names of nested files are `/`-separated (e.g. `docs/2026/report.pdf`)
     pub async fn list_files(&self) -> Result<Vec<(String, u64, bool)>> {
+        Ok(self.walk_store(None, true).await?.0)
+    }
+
+    /// List the files in directory `dir`, or also those in its
+    /// subdirectories when `recursive` is set
+    pub async fn list_dir(&self, dir: &str, recursive: bool) -> Result<Vec<(String, u64, bool)>> {
+        let dir = StorageName::new(dir)?;
+        Ok(self.walk_store(Some(&dir), recursive).await?.0)
+    }
+
+    /// List the subdirectories directly inside `dir` (the root if `None`)
+    pub async fn list_subdirs(&self, dir: Option<&str>) -> Result<Vec<String>> {
+        let dir = dir.map(StorageName::new).transpose()?;
+        Ok(self.walk_store(dir.as_ref(), false).await?.1)
+    }
+
+    /// [`Self::walk`] over the storage root. During an interrupted rotation
+    /// with encrypted names, files already rotated are under their names for
+    /// the new key, and are listed too (see [`Self::with_rotation_key`]).
+    async fn walk_store(
+        &self,
+        dir: Option<&StorageName>,
+        recursive: bool,
+    ) -> Result<(Vec<(String, u64, bool)>, Vec<String>)> {
+        let (mut files, mut subdirs) = self.walk(&self.root, dir, recursive).await?;
+        if let Some(rotation) = self.rotation.as_ref().filter(|_| self.names.is_some()) {
+            let (rotated, rotated_dirs) = rotation.ops.walk(&self.root, dir, recursive).await?;
+            files.extend(rotated);
+            files.sort_by(|a, b| a.0.cmp(&b.0));
+            files.dedup_by(|a, b| a.0 == b.0);
+            subdirs.extend(rotated_dirs);
+            subdirs.sort();
+            subdirs.dedup();
+        }
+        Ok((files, subdirs))
+    }
+
+    /// Remove directory `dir`. Without `recursive` it must be empty;
+    /// with it, every file inside is deleted along with its metadata, or the
+    /// whole directory moves to the trash (see [`Self::with_trash`]).
+    pub async fn remove_dir(&self, dir: &str, recursive: bool) -> Result<()> {
+        info!(dir, recursive, "removing storage directory");
+        let path = self.resolve(dir, false).await?;
+        let _lock = self.write_lock().await?;
+        let meta = fs::symlink_metadata(&path)
+            .await
+            .with_context(|| format!("inspecting {:?}", &path))?;
+        if !meta.is_dir() {
+            return Err(SecureFsError::storage(format!("'{}' is not a directory", dir)).into());
+        }
+        if recursive && self.trash.is_some() {
+            return self.move_to_trash(dir, &path).await;
+        }
+        let mut keys = self.key_ids(&path).await?;
+        keys.extend(self.key_ids(&history::history_dir(&self.root, &path)).await?);
+        if recursive {
+            fs::remove_dir_all(&path).await
+        } else {
+            fs::remove_dir(&path).await
+        }
+        .with_context(|| format!("removing directory {:?}", &path))?;
+
+        // Whatever sidecars and history were inside went with the files
+        for mirror in [
+            metadata::sidecar_path(&self.root, &path),
+            history::history_dir(&self.root, &path),
+        ] {
+            match fs::remove_dir_all(&mirror).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(anyhow::Error::new(e).context(format!("removing {:?}", &mirror)))
+                }
+                _ => {}
+            }
+        }
+        self.destroy_keys(&keys).await?;
+        Ok(())
+    }
+
+    /// Moves `name` at `path` to the trash. The caller holds the write lock.
+    async fn move_to_trash(&self, name: &str, path: &Path) -> Result<()> {
+        let components = StorageName::new(name)?.components().count();
+        let id = trash::move_in(&self.root, path, components).await?;
+        info!(name, id, "moved to trash");
+        self.purge_expired().await?;
+        Ok(())
+    }
+
+    /// Deleted files and directories in the trash, newest first
+    pub async fn list_trash(&self) -> Result<Vec<TrashEntry>> {
+        let mut listed = Vec::new();
+        for entry in trash::entries(&self.root).await? {
+            let (name, relative) = trash::locate(&entry, self.names.as_ref()).await?;
+            let is_dir = fs::symlink_metadata(entry.dir.join(&relative)).await?.is_dir();
+            let size = self.walk(&entry.dir, None, true).await?.0.iter().map(|f| f.1).sum();
+            listed.push(TrashEntry {
+                id: entry.id,
+                name,
+                deleted_at: entry.deleted_at,
+                is_dir,
+                size,
+            });
+        }
+        Ok(listed)
+    }
+
+    /// Moves trash entry `id` back to where it was deleted from, failing
+    /// with [`SecureFsError::AlreadyExists`] if something has taken its
+    /// place. Returns its name.
+    pub async fn restore_trash(&self, id: &str) -> Result<String> {
+        let entry = trash::entry(&self.root, id).await?;
+        let (name, relative) = trash::locate(&entry, self.names.as_ref()).await?;
+        let dest = self.resolve(&name, true).await?;
+        let _lock = self.write_lock().await?;
+        if fs::try_exists(&dest).await? {
+            return Err(SecureFsError::AlreadyExists(name).into());
+        }
+        trash::move_out(&self.root, &entry, &relative, &dest).await?;
+        info!(name, id, "restored from trash");
+        Ok(name)
+    }
+
+    /// Permanently removes trash entries past the retention period of the
+    /// [`TrashPolicy`]. Runs after every deletion; returns how many were removed.
+    pub async fn purge_trash(&self) -> Result<usize> {
+        if self.trash.is_none() {
+            return Ok(0);
+        }
+        let _lock = self.write_lock().await?;
+        self.purge_expired().await
+    }
+
+    /// [`Self::purge_trash`] for a caller holding the write lock
+    async fn purge_expired(&self) -> Result<usize> {
+        let Some(policy) = &self.trash else { return Ok(0) };
+        let mut purged = 0;
+        for entry in trash::entries(&self.root).await? {
+            if trash::expired(policy, &entry) {
+                let keys = self.key_ids(&entry.dir).await?;
+                trash::remove(&entry).await?;
+                self.destroy_keys(&keys).await?;
+                purged += 1;
+            }
+        }
+        if purged > 0 {
+            info!(purged, "purged expired trash");
+        }
+        Ok(purged)
+    }
+
+    /// Permanently removes everything in the trash; returns how many entries were removed
+    pub async fn empty_trash(&self) -> Result<usize> {
+        let _lock = self.write_lock().await?;
+        let entries = trash::entries(&self.root).await?;
+        for entry in &entries {
+            let keys = self.key_ids(&entry.dir).await?;
+            trash::remove(entry).await?;
+            self.destroy_keys(&keys).await?;
+        }
+        info!(removed = entries.len(), "emptied trash");
+        Ok(entries.len())
+    }
+
+    /// Walks `dir` (the root if `None`) in the store at `root`, which is the
+    /// storage root or a trash entry, returning its files and, when not
+    /// `recursive`, its immediate subdirectories. Symlinked directories,
+    /// SecureFS's own files and legacy metadata sidecars are skipped.
+    async fn walk(
+        &self,
+        root: &Path,
+        dir: Option<&StorageName>,
+        recursive: bool,
+    ) -> Result<(Vec<(String, u64, bool)>, Vec<String>)> {
         let mut files = Vec::new();
+        let mut subdirs = Vec::new();
+        let start = match dir {
+            Some(dir) => resolve_in(root, self.names.as_ref(), dir, false).await?,
+            None => root.to_path_buf(),
+        };
 
-        // Check if storage directory exists
-        if !fs::try_exists(&self.root).await.unwrap_or(false) {
-            return Ok(files);
+        // Check if the directory exists
+        if !fs::try_exists(&start).await.unwrap_or(false) {
+            return Ok((files, subdirs));
         }
 
-        let mut dir = fs::read_dir(&self.root).await?;
+        let prefix = dir.map(|d| format!("{}/", d)).unwrap_or_default();
+        let mut pending = vec![(start, prefix)];
+        while let Some((path, prefix)) = pending.pop() {
+            let mut entries = fs::read_dir(&path).await?;
+
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+
+                // Get filename, skipping SecureFS's own files
+                let filename = match path.file_name().and_then(|n| n.to_str()) {
+                    Some(name) if !name.starts_with(RESERVED_PREFIX) => name,
+                    _ => continue,
+                };
+                let filename = match &self.names {
+                    Some(names) => match names.decrypt_component(prefix.trim_end_matches('/'), filename) {
+                        Some(filename) => filename,
+                        None => {
+                            warn!(entry = %path.display(), "skipping entry whose name doesn't decrypt");
+                            continue;
+                        }
+                    },
+                    None => filename.to_string(),
+                };
+                let name = format!("{}{}", prefix, filename);
+
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    if recursive {
+                        pending.push((path, format!("{}/", name)));
+                    } else {
+                        subdirs.push(name);
+                    }
+                    continue;
+                }
+
+                // Skip symlinked directories
+                if path.is_dir() {
+                    continue;
+                }
+                // Skip plaintext sidecars left by earlier versions
+                if let Some(legacy) = metadata::read_legacy(&path).await {
+                    debug!(
+                        sidecar = %path.display(),
+                        file = %legacy.filename,
+                        size = legacy.size,
+                        "skipping legacy metadata sidecar"
+                    );
+                    continue;
+                }
+
+                // Get file size
+                let metadata = entry.metadata().await?;
+                let size = metadata.len();
+
+                // Check if metadata file exists
+                let meta_path = metadata::sidecar_path(root, &path);
+                let has_metadata = fs::try_exists(&meta_path).await.unwrap_or(false);
+
+                files.push((name, size, has_metadata));
+            }
+        }
+
+        // Sort by name for consistent ordering
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        subdirs.sort();
+
+        Ok((files, subdirs))
+    }
+
+    /// Checks that the master key matches this store, using the vault
+    /// descriptor or, for older stores, the smallest file encrypted with the key.
+    /// Returns `None` if the store holds no master-key files to check against.
+    pub async fn verify_key(&self) -> Result<Option<bool>> {
+        // Already checked against the vault descriptor when opened
+        if self.vault.is_some() && self.encryptor.is_some() {
+            return Ok(Some(true));
+        }
 
-        while let Some(entry) = dir.next_entry().await? {
-            let path = entry.path();
+        let mut files = self.list_files().await?;
+        files.sort_by_key(|(_, size, _)| *size);
 
-            // Skip directories and metadata files
-            if path.is_dir() || path.extension().and_then(|e| e.to_str()) == Some("json") {
+        for (name, _, _) in files {
+            let path = self.resolve(&name, false).await?;
+            let data = fs::read(&path)
+                .await
+                .with_context(|| format!("reading {:?}", &path))?;
+            let Some(&version) = data.first() else { continue };
+            if version == VERSION_V3_RECIPIENTS || version == file_keys::VERSION_V4_FILE_KEY {
                 continue;
             }
 
-            // Get filename
-            let filename = match path.file_name().and_then(|n| n.to_str()) {
-                Some(name) => name.to_string(),
-                None => continue,
+            // Authenticate only; never decompress, so the store's compression
+            // setting can't cause a false mismatch
+            let authentic = if version == VERSION_V2_STREAM {
+                self.stream_encryptor()?
+                    .decrypt_stream(&mut Cursor::new(&data), &mut tokio::io::sink(), content_aad(&data, &name))
+                    .await
+                    .is_ok()
+            } else {
+                self.encryptor()?.decrypt(&data, None).is_ok()
             };
+            debug!(file = %name, authentic, "checked master key against stored file");
+            return Ok(Some(authentic));
+        }
+        Ok(None)
+    }
 
-            // Get file size
-            let metadata = entry.metadata().await?;
-            let size = metadata.len();
+    /// Plaintext sidecars left next to files by earlier versions, which
+    /// [`Self::migrate_legacy_metadata`] seals and removes
+    pub async fn legacy_sidecars(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.find_legacy_sidecars().await?.into_iter().map(|(path, _)| path).collect())
+    }
 
-            // Check if metadata file exists
-            let meta_path = path.with_extension("meta.json");
-            let has_metadata = fs::try_exists(&meta_path).await.unwrap_or(false);
+    /// Seals the metadata in plaintext sidecars from earlier versions under
+    /// `.securefs-meta/` and deletes them (see [`crate::metadata`]). A sidecar
+    /// whose file is gone or already has sealed metadata is only deleted.
+    /// Returns how many sidecars were sealed and how many were only deleted.
+    pub async fn migrate_legacy_metadata(&self) -> Result<(usize, usize)> {
+        let metadata = self
+            .metadata
+            .as_ref()
+            .ok_or_else(|| SecureFsError::key("metadata is sealed with the master key, which this store was opened without"))?;
+        let _lock = self.write_lock().await?;
 
-            files.push((filename, size, has_metadata));
+        let (mut sealed, mut removed) = (0, 0);
+        for (sidecar, legacy) in self.find_legacy_sidecars().await? {
+            let file = sidecar.with_file_name(&legacy.filename);
+            // Storage names are only on disk as-is when names aren't encrypted
+            let name = match (&self.names, file.strip_prefix(&self.root)) {
+                (None, Ok(relative)) => relative
+                    .to_str()
+                    .map(|relative| relative.replace(std::path::MAIN_SEPARATOR, "/"))
+                    .filter(|name| StorageName::new(name).is_ok()),
+                _ => None,
+            };
+            let file_meta = fs::symlink_metadata(&file).await.ok().filter(|meta| meta.is_file());
+            let describes_file = file != sidecar && file.with_extension("meta.json") == sidecar;
+
+            match (name, file_meta) {
+                (Some(name), Some(file_meta))
+                    if describes_file
+                        && !fs::try_exists(metadata::sidecar_path(&self.root, &file)).await.unwrap_or(false) =>
+                {
+                    let meta = FileMetadata {
+                        filename: name.clone(),
+                        size: legacy.size,
+                        modified: file_meta.modified().ok(),
+                        stored_size: Some(file_meta.len()),
+                        ..Default::default()
+                    };
+                    metadata.write(&self.root, &file, &name, &meta).await?;
+                    info!(file = %name, "sealed legacy metadata sidecar");
+                    sealed += 1;
+                }
+                _ => {
+                    debug!(sidecar = %sidecar.display(), "removing legacy metadata sidecar without sealing it");
+                    removed += 1;
+                }
+            }
+            fs::remove_file(&sidecar)
+                .await
+                .with_context(|| format!("removing legacy sidecar {:?}", &sidecar))?;
         }
+        Ok((sealed, removed))
+    }
 
-        // Sort by filename for consistent ordering
-        files.sort_by(|a, b| a.0.cmp(&b.0));
+    /// Every legacy sidecar in the store, with its contents, in path order
+    async fn find_legacy_sidecars(&self) -> Result<Vec<(PathBuf, metadata::LegacySidecar)>> {
+        let mut found = Vec::new();
+        if !fs::try_exists(&self.root).await? {
+            return Ok(found);
+        }
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("reading directory {:?}", &dir))?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_name().to_string_lossy().starts_with(RESERVED_PREFIX) {
+                    continue;
+                }
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    pending.push(entry.path());
+                } else if let Some(legacy) = metadata::read_legacy(&entry.path()).await {
+                    found.push((entry.path(), legacy));
+                }
+            }
+        }
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(found)
+    }
 
-        Ok(files)
+    /// Re-encrypts every master-key file under `new_key`, `jobs` files at a
+    /// time, then rebinds the vault descriptor to it (see [`crate::rotation`]).
+    /// An interrupted run resumes from its journal when called again with the
+    /// same keys; until the last file is done this store's key still opens it.
+    pub async fn reencrypt_all(&self, new_key: &KeyManager, jobs: usize) -> Result<RotationReport> {
+        let (Some(encryptor), Some(stream), Some(metadata), Some(key_id)) =
+            (&self.encryptor, &self.stream_encryptor, &self.metadata, self.key_id)
+        else {
+            return Err(SecureFsError::key("store was opened without a master key").into());
+        };
+        let old = ContentKeys {
+            encryptor: encryptor.clone(),
+            stream: stream.clone(),
+            names: self.names.clone(),
+            metadata: metadata.clone(),
+            key_id,
+        };
+        // Files in the trash are re-encrypted too, each entry being laid out like a store
+        let mut files: Vec<_> = self
+            .list_files()
+            .await?
+            .into_iter()
+            .map(|(name, _, _)| (self.root.clone(), name))
+            .collect();
+        for entry in trash::entries(&self.root).await? {
+            for (name, _, _) in self.walk(&entry.dir, None, true).await?.0 {
+                files.push((entry.dir.clone(), name));
+            }
+        }
+        rotation::reencrypt_all(
+            &self.root,
+            files,
+            old,
+            new_key,
+            self.vault.as_ref(),
+            self.key_table.as_ref(),
+            jobs,
+        )
+        .await
     }
 
-    /// Read metadata for an encrypted file
+    /// Read metadata for an encrypted file.
+    /// Fails if the sidecar was modified, belongs to another file or another
+    /// version of this one, or predates sealed metadata (see [`crate::metadata`]).
     pub async fn get_metadata(&self, name: &str) -> Result<FileMetadata> {
-        let path = self.root.join(name);
-        let meta_path = path.with_extension("meta.json");
+        if let Some(rotated) = self.rotated(name).await? {
+            return Box::pin(rotated.get_metadata(name)).await;
+        }
+        let path = self.resolve(name, false).await?;
+        let metadata = self
+            .metadata
+            .as_ref()
+            .ok_or_else(|| SecureFsError::key("metadata is sealed with the master key, which this store was opened without"))?;
+        metadata.read(&self.root, &path, name).await
+    }
+
+    /// Changes the tags of `name` with `update` and returns its updated
+    /// metadata. Only the sidecar is rewritten: the content and its
+    /// [`FileVersion`] stay as they are.
+    pub async fn update_tags<F>(&self, name: &str, update: F) -> Result<FileMetadata>
+    where
+        F: FnOnce(&mut BTreeMap<String, String>),
+    {
+        self.update_metadata(name, |meta| update(&mut meta.tags)).await
+    }
+
+    /// Records the path `name` was stored from, or clears it with `None`;
+    /// like [`Self::update_tags`], the content is left as it is
+    pub async fn set_source_path(&self, name: &str, source_path: Option<String>) -> Result<FileMetadata> {
+        self.update_metadata(name, |meta| meta.source_path = source_path).await
+    }
 
-        let content = fs::read_to_string(&meta_path).await
-            .with_context(|| format!("reading metadata from {:?}", &meta_path))?;
+    /// Rewrites the sidecar of `name` with `update` applied, under the write lock
+    async fn update_metadata<F>(&self, name: &str, update: F) -> Result<FileMetadata>
+    where
+        F: FnOnce(&mut FileMetadata),
+    {
+        let path = self.resolve(name, false).await?;
+        let metadata = self
+            .metadata
+            .as_ref()
+            .ok_or_else(|| SecureFsError::key("metadata is sealed with the master key, which this store was opened without"))?;
+        let _lock = self.write_lock().await?;
+        let mut meta = metadata.read(&self.root, &path, name).await?;
+        update(&mut meta);
+        metadata.write(&self.root, &path, name, &meta).await?;
+        Ok(meta)
+    }
 
-        let metadata: FileMetadata = serde_json::from_str(&content)
-            .with_context(|| format!("parsing metadata from {:?}", &meta_path))?;
+    /// Current version of `name`, for [`WriteMode::IfMatch`]
+    pub async fn version(&self, name: &str) -> Result<FileVersion> {
+        if let Some(rotated) = self.rotated(name).await? {
+            return Box::pin(rotated.version(name)).await;
+        }
+        let path = self.resolve(name, false).await?;
+        FileVersion::of(&path).await
+    }
 
-        Ok(metadata)
+    /// Takes the store's write lock. Master-key stores then fail if the vault
+    /// was rebound to another key, or a key rotation is pending, so nothing
+    /// new lands under a key being retired (see [`crate::rotation`]).
+    async fn write_lock(&self) -> Result<FileLock> {
+        // The lock file lives in the root, which a first delete may precede
+        fs::create_dir_all(&self.root)
+            .await
+            .with_context(|| format!("creating storage directory {:?}", &self.root))?;
+        let lock = FileLock::acquire(&self.root.join(WRITE_LOCK_FILE)).await?;
+        if let Some(key_id) = self.key_id.map(hex::encode) {
+            if let Some(vault) = VaultDescriptor::load(&self.root).await? {
+                if vault.settings.key_id != key_id {
+                    return Err(SecureFsError::KeyMismatch {
+                        expected: vault.settings.key_id,
+                        found: key_id,
+                    }
+                    .into());
+                }
+            }
+            if rotation::pending(&self.root).await?.is_some() {
+                return Err(SecureFsError::storage(
+                    "a key rotation is in progress; finish it before changing files",
+                )
+                .into());
+            }
+        }
+        Ok(lock)
+    }
+
+    /// The store under the new key of an interrupted rotation (see
+    /// [`Self::with_rotation_key`]), if `name` has moved on to it
+    async fn rotated(&self, name: &str) -> Result<Option<&SecureFileOps>> {
+        let Some(rotation) = &self.rotation else { return Ok(None) };
+        let new_key_id = hex::encode(rotation.km.key_id());
+        let rotated = match rotation::pending(&self.root).await? {
+            Some(pending) => {
+                pending.new_key_id == new_key_id && pending.done.contains(&StorageName::new(name)?.to_string())
+            }
+            // Finished if the vault names the new key by now
+            None => VaultDescriptor::load(&self.root)
+                .await?
+                .is_some_and(|vault| vault.settings.key_id == new_key_id),
+        };
+        Ok(rotated.then_some(&*rotation.ops))
+    }
+
+    /// Under the store's write lock, checks `mode` again, moves `out` into
+    /// place at `path` and records its metadata
+    async fn commit_write(
+        &self,
+        out: AtomicFile,
+        name: &str,
+        path: &Path,
+        mode: WriteMode,
+        written: Digested,
+        file_key: Option<&FileKey>,
+    ) -> Result<FileVersion> {
+        let _lock = self.write_lock().await?;
+        write_mode::check(mode, name, path).await?;
+        // Carried over to the new version; gone if the sidecar doesn't open
+        let previous = match &self.metadata {
+            Some(metadata) if fs::try_exists(path).await? => metadata.read(&self.root, path, name).await.ok(),
+            _ => None,
+        };
+        if let (Some(table), Some(key)) = (&self.key_table, file_key) {
+            table.insert(key).await?;
+        }
+        let archive = self.history.is_some() && fs::try_exists(path).await?;
+        // Keys of versions that are about to be gone for good
+        let mut unused = Vec::new();
+        if archive {
+            history::archive(&self.root, path).await?;
+        } else {
+            unused.extend(self.key_ids(path).await?);
+        }
+        out.commit().await?;
+        self.record_metadata(path, name, written, previous).await?;
+        if let (true, Some(policy)) = (archive, &self.history) {
+            let kept = self.history_key_ids(path).await?;
+            let pruned = history::prune(&self.root, path, policy).await?;
+            debug!(file = name, pruned, "pruned version history");
+            if pruned > 0 {
+                let remaining = self.history_key_ids(path).await?;
+                unused.extend(kept.into_iter().filter(|id| !remaining.contains(id)));
+            }
+        }
+        if let Some(table) = &self.key_table {
+            table.remove(&unused).await?;
+        }
+        FileVersion::of(path).await
+    }
+
+    /// IDs of the per-file keys of the files at or below `path`, if the
+    /// store has a key table (see [`crate::file_keys`])
+    async fn key_ids(&self, path: &Path) -> Result<Vec<KeyId>> {
+        match self.key_table {
+            Some(_) => file_keys::ids_under(path).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// IDs of the per-file keys of the archived versions of the file at `path`
+    async fn history_key_ids(&self, path: &Path) -> Result<Vec<KeyId>> {
+        let mut ids = Vec::new();
+        if self.key_table.is_some() {
+            for entry in history::entries(&self.root, path).await? {
+                ids.extend(file_keys::ids_under(&entry.path).await?);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Destroys the per-file keys `ids`. The caller holds the write lock.
+    async fn destroy_keys(&self, ids: &[KeyId]) -> Result<usize> {
+        let Some(table) = &self.key_table else { return Ok(0) };
+        table.remove(ids).await
+    }
+
+    /// The current version of `name` followed by its archived versions,
+    /// newest first (see [`Self::with_history`])
+    pub async fn list_versions(&self, name: &str) -> Result<Vec<VersionInfo>> {
+        if let Some(rotated) = self.rotated(name).await? {
+            return Box::pin(rotated.list_versions(name)).await;
+        }
+        let path = self.resolve(name, false).await?;
+        let mut versions = Vec::new();
+        if fs::try_exists(&path).await? {
+            versions.push(VersionInfo {
+                version: FileVersion::of(&path).await?,
+                archived_at: None,
+                size: fs::metadata(&path).await?.len(),
+            });
+        }
+        for entry in history::entries(&self.root, &path).await? {
+            versions.push(VersionInfo {
+                version: FileVersion::of(&entry.path).await?,
+                archived_at: Some(entry.archived_at),
+                size: fs::metadata(&entry.path).await?.len(),
+            });
+        }
+        Ok(versions)
+    }
+
+    /// Decrypts version `version` of `name`, current or archived
+    pub async fn read_version(&self, name: &str, version: FileVersion) -> Result<Vec<u8>> {
+        if let Some(rotated) = self.rotated(name).await? {
+            return Box::pin(rotated.read_version(name, version)).await;
+        }
+        let path = self.version_path(name, version).await?;
+        Ok(self.decrypt_file_auto(&path, name).await?.0)
+    }
+
+    /// Makes archived version `version` of `name` current again by writing
+    /// its contents as a new version; the version it replaces is archived
+    /// like any other. Returns the new version.
+    pub async fn restore(&self, name: &str, version: FileVersion) -> Result<FileVersion> {
+        info!(file = name, %version, "restoring version");
+        let data = Zeroizing::new(self.read_version(name, version).await?);
+        self.write_encrypted_with(name, &data, WriteMode::Overwrite).await
+    }
+
+    /// Path of version `version` of `name`
+    async fn version_path(&self, name: &str, version: FileVersion) -> Result<PathBuf> {
+        let path = self.resolve(name, false).await?;
+        if fs::try_exists(&path).await? && FileVersion::of(&path).await? == version {
+            return Ok(path);
+        }
+        for entry in history::entries(&self.root, &path).await? {
+            if FileVersion::of(&entry.path).await? == version {
+                return Ok(entry.path);
+            }
+        }
+        Err(SecureFsError::storage(format!("no version {} of {:?}", version, name)).into())
+    }
+
+    /// Seals and writes the sidecar for a file just written, keeping the
+    /// creation time, tags and source path of the `previous` version;
+    /// keyless stores can't seal one and skip it
+    async fn record_metadata(
+        &self,
+        path: &Path,
+        name: &str,
+        written: Digested,
+        previous: Option<FileMetadata>,
+    ) -> Result<()> {
+        let Some(metadata) = &self.metadata else {
+            debug!(file = name, "no master key; not recording metadata");
+            return Ok(());
+        };
+        let now = SystemTime::now();
+        let previous = previous.unwrap_or_default();
+        let meta = FileMetadata {
+            filename: name.to_string(),
+            size: written.size,
+            created: previous.created.or(Some(now)),
+            modified: Some(now),
+            source_path: previous.source_path,
+            content_type: Some(written.content_type),
+            sha256: Some(written.sha256),
+            stored_size: Some(fs::metadata(path).await?.len()),
+            tags: previous.tags,
+        };
+        metadata.write(&self.root, path, name, &meta).await
+    }
+}
+
+/// Content ciphers for one file: the master key's, or the file's own (see
+/// [`crate::file_keys`]), with the length of the envelope before its content
+struct FileCiphers {
+    encryptor: Option<Encryptor>,
+    stream: Option<StreamEncryptor>,
+    offset: usize,
+}
+
+impl FileCiphers {
+    fn own(key: &LockedBox<[u8; 32]>) -> Self {
+        let (encryptor, stream) = file_keys::ciphers(key);
+        Self {
+            encryptor: Some(encryptor),
+            stream: Some(stream),
+            offset: file_keys::HEADER_LEN,
+        }
+    }
+
+    fn encryptor(&self) -> Result<&Encryptor> {
+        self.encryptor
+            .as_ref()
+            .ok_or_else(|| SecureFsError::key("store was opened without a master key").into())
+    }
+
+    fn stream(&self) -> Result<&StreamEncryptor> {
+        self.stream
+            .as_ref()
+            .ok_or_else(|| SecureFsError::key("store was opened without a master key").into())
+    }
+}
+
+/// Content ciphers for `km`: derived subkeys, plus the raw master key for
+/// files written before subkeys existed
+pub(crate) fn content_keys(km: &KeyManager) -> ContentKeys {
+    ContentKeys {
+        encryptor: Encryptor::new(km.derive_subkey(subkey::BUFFER_CONTENT)).with_legacy_cipher(km.cipher()),
+        stream: StreamEncryptor::new(km.derive_subkey(subkey::STREAM_CONTENT)).with_legacy_cipher(km.cipher()),
+        names: None,
+        metadata: MetadataKey::new(km),
+        key_id: km.key_id(),
+    }
+}
+
+/// AAD for the V2 or V3 stream starting with `content`, stored as `name`:
+/// none if it has a file ID, which binds it instead (see [`crate::streaming`]),
+/// and otherwise the name, as streams were bound before file IDs
+pub(crate) fn content_aad<'a>(content: &[u8], name: &'a str) -> Option<&'a [u8]> {
+    match content.get(1) {
+        Some(flags) if flags & FLAG_FILE_ID != 0 => None,
+        _ => Some(name.as_bytes()),
+    }
+}
+
+/// Maps `name` to its path under `root`, encrypting each component with
+/// `names` if given, and optionally creating missing parent directories.
+/// Fails if a parent is a symlink or a file, so a name can't be redirected
+/// outside the root.
+pub(crate) async fn resolve_in(
+    root: &Path,
+    names: Option<&NameCipher>,
+    name: &StorageName,
+    create_parents: bool,
+) -> Result<PathBuf> {
+    let components: Vec<String> = match names {
+        Some(names) => names.encrypt_name(name)?,
+        None => name.components().map(String::from).collect(),
+    };
+    if create_parents {
+        fs::create_dir_all(root)
+            .await
+            .with_context(|| format!("creating storage directory {:?}", root))?;
+    }
+
+    let (file_name, parents) = components.split_last().expect("BUG: names always have a component");
+    let mut dir = root.to_path_buf();
+    for component in parents {
+        dir.push(component);
+        match fs::symlink_metadata(&dir).await {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(SecureFsError::invalid_name(name.as_str(), "a parent directory is a symlink").into())
+            }
+            Ok(_) => {
+                return Err(SecureFsError::invalid_name(name.as_str(), "a parent component is not a directory").into())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && create_parents => {
+                match fs::create_dir(&dir).await {
+                    Ok(()) => debug!(dir = %dir.display(), "created storage subdirectory"),
+                    // Created concurrently by another writer
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(e).with_context(|| format!("creating {:?}", &dir)),
+                }
+            }
+            // Missing parents mean a missing file; let the caller report it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut path = root.to_path_buf();
+                path.extend(&components);
+                return Ok(path);
+            }
+            Err(e) => return Err(e).with_context(|| format!("inspecting {:?}", &dir)),
+        }
     }
+    Ok(dir.join(file_name))
 }
@@ -6,33 +6,89 @@
 //! ## V2 File Format
 //!
 //! ```text
-//! [version:1][flags:1][chunk1][chunk2]...
+//! [version:1][flags:1][file_id:16]?[chunk1][chunk2]...
 //!
 //! Each chunk:
 //! [nonce:24][length:4][encrypted_data]
 //! ```
 //!
+//! Flag bit 0 marks compressed content; bit 1 ([`FLAG_SUBKEY`]) marks files
+//! encrypted with the derived stream content subkey instead of the master key.
+//!
+//! ## File IDs
+//!
+//! Bit 2 ([`FLAG_FILE_ID`]) marks files whose header ends with a random
+//! [`FileId`]. Their chunk AAD is the whole header followed by the caller's
+//! AAD, so chunks can't be spliced from one file into another even when the
+//! caller's AAD is empty. Callers can then leave out the file name, and the
+//! file can be renamed without re-encryption. Files without the flag use
+//! the caller's AAD alone.
+//!
+//! ## V3 Recipient Format
+//!
+//! ```text
+//! [version:1][flags:1][stanza_count:1][stanza1][stanza2]...[file_id:16]?[chunk1]...
+//!
+//! Each X25519 stanza:
+//! [type:1][ephemeral_public:32][wrapped_file_key:48]
+//! ```
+//!
+//! Chunks are encrypted with a random per-file key. Each stanza wraps that
+//! key for one recipient public key, so producers can write files they are
+//! unable to read back. Chunk AAD covers the full header.
+//!
 //! ## Chunk Size
 //!
 //! Files are processed in 64KB chunks, balancing memory usage against
 //! per-chunk cryptographic overhead.
 
+use crate::key_manager::{Identity, Recipient};
+use crate::secret::{LockedBox, SharedCipher};
 use anyhow::{Context, Result};
+use chacha20poly1305::aead::rand_core::RngCore;
 use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
-use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// Chunk size for streaming encryption (64KB)
 /// Balances memory usage vs. overhead from per-chunk nonces and tags
 const CHUNK_SIZE: usize = 64 * 1024;
 
+/// Longest encrypted chunk: a full chunk and its Poly1305 tag
+const MAX_CHUNK_LEN: usize = CHUNK_SIZE + 16;
+
 /// File format version for streaming encrypted files
 pub const VERSION_V2_STREAM: u8 = 2;
 
+/// File format version for files encrypted to X25519 recipients
+pub const VERSION_V3_RECIPIENTS: u8 = 3;
+
+/// Flag bit set on V2 files encrypted with a derived content subkey
+/// rather than the raw master key (see [`StreamEncryptor::with_legacy_cipher`])
+pub const FLAG_SUBKEY: u8 = 0x02;
+
+/// Flag bit set on V2 and V3 files whose header ends with a [`FileId`]
+pub const FLAG_FILE_ID: u8 = 0x04;
+
+/// Random ID binding a file's chunks to its header (see [`FLAG_FILE_ID`])
+pub type FileId = [u8; 16];
+
+/// Length of a [`FileId`]
+pub const FILE_ID_LEN: usize = 16;
+
+/// A fresh random file ID
+pub fn generate_file_id() -> FileId {
+    let mut id = [0u8; FILE_ID_LEN];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
 /// Flags for file format options
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct FormatFlags {
     pub compressed: bool,
+    /// ID the chunks are bound to; `None` for files written without one
+    pub file_id: Option<FileId>,
 }
 
 impl FormatFlags {
@@ -41,30 +97,104 @@ impl FormatFlags {
         if self.compressed {
             flags |= 0x01; // Bit 0: compression enabled
         }
+        if self.file_id.is_some() {
+            flags |= FLAG_FILE_ID;
+        }
         flags
     }
 
+    /// Flags from the flags byte alone; a file ID is read from the header separately
     pub fn from_byte(byte: u8) -> Self {
         Self {
             compressed: (byte & 0x01) != 0,
+            file_id: None,
+        }
+    }
+
+    /// Flags of the V2 file starting with `header`, file ID included
+    pub fn from_v2_header(header: &[u8]) -> Self {
+        let Some(&byte) = header.get(1) else {
+            return Self::default();
+        };
+        let mut flags = Self::from_byte(byte);
+        if byte & FLAG_FILE_ID != 0 {
+            flags.file_id = header
+                .get(2..2 + FILE_ID_LEN)
+                .map(|id| id.try_into().expect("slice is FILE_ID_LEN long"));
+        }
+        flags
+    }
+}
+
+/// Stanza type tag for an X25519 recipient in a V3 header
+pub const STANZA_X25519: u8 = 0x01;
+
+/// Upper bound on recipient stanzas accepted in a V3 header
+pub const MAX_RECIPIENTS: usize = 64;
+
+/// A single recipient stanza from a V3 header.
+/// Carries the file key wrapped for one recipient public key.
+/// Format: \[type:1\]\[ephemeral_public:32\]\[wrapped_key:48\]
+#[derive(Debug, Clone)]
+pub struct RecipientStanza {
+    pub ephemeral_public: [u8; 32],
+    pub wrapped_key: [u8; 48],
+}
+
+impl RecipientStanza {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.push(STANZA_X25519);
+        out.extend_from_slice(&self.ephemeral_public);
+        out.extend_from_slice(&self.wrapped_key);
+    }
+
+    async fn read_from<R>(reader: &mut R, header: &mut Vec<u8>) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let kind = reader.read_u8().await.context("reading stanza type")?;
+        if kind != STANZA_X25519 {
+            anyhow::bail!("unsupported recipient stanza type: {:#04x}", kind);
         }
+        let mut ephemeral_public = [0u8; 32];
+        let mut wrapped_key = [0u8; 48];
+        reader.read_exact(&mut ephemeral_public).await
+            .context("reading stanza ephemeral key")?;
+        reader.read_exact(&mut wrapped_key).await
+            .context("reading stanza wrapped key")?;
+
+        let stanza = Self { ephemeral_public, wrapped_key };
+        stanza.write_to(header);
+        Ok(stanza)
     }
 }
 
 /// StreamEncryptor handles streaming encryption/decryption for large files
 /// Uses chunked AEAD to maintain authentication while processing incrementally
+#[derive(Clone)]
 pub struct StreamEncryptor {
-    cipher: XChaCha20Poly1305,
+    cipher: SharedCipher,
+    legacy_cipher: Option<SharedCipher>,
 }
 
 impl StreamEncryptor {
-    pub fn new(cipher: XChaCha20Poly1305) -> Self {
-        Self { cipher }
+    pub fn new(cipher: SharedCipher) -> Self {
+        Self {
+            cipher,
+            legacy_cipher: None,
+        }
+    }
+
+    /// Marks `cipher` as a derived subkey. New files carry [`FLAG_SUBKEY`];
+    /// files without it were written before subkeys and are decrypted with `legacy`.
+    pub fn with_legacy_cipher(mut self, legacy: SharedCipher) -> Self {
+        self.legacy_cipher = Some(legacy);
+        self
     }
 
     /// Encrypts data from reader in chunks, writing to writer
     /// Format per chunk: \[nonce:24\]\[chunk_len:4\]\[encrypted_data:chunk_len\]
-    /// File format: \[version:1\]\[flags:1\]\[chunks...\]
+    /// File format: \[version:1\]\[flags:1\]\[file_id:16\]?\[chunks...\]
     pub async fn encrypt_stream<R, W>(
         &self,
         reader: &mut R,
@@ -77,49 +207,20 @@ impl StreamEncryptor {
         W: AsyncWrite + Unpin,
     {
         // Write file format header
-        writer.write_u8(VERSION_V2_STREAM).await?;
-        writer.write_u8(flags.to_byte()).await?;
-
-        let mut buffer = vec![0u8; CHUNK_SIZE];
-        let mut total_bytes = 0u64;
-
-        loop {
-            // Read chunk from source
-            let n = reader.read(&mut buffer).await?;
-            if n == 0 {
-                break; // EOF
-            }
-
-            let plaintext = &buffer[..n];
-
-            // Generate unique nonce for this chunk
-            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
-
-            // Encrypt chunk with optional AAD
-            let ciphertext = match aad {
-                Some(a) => self.cipher.encrypt(
-                    &nonce,
-                    Payload {
-                        msg: plaintext,
-                        aad: a,
-                    },
-                )
-                .map_err(|e| anyhow::anyhow!("encryption failed: {}", e))?,
-                None => self.cipher
-                    .encrypt(&nonce, plaintext)
-                    .map_err(|e| anyhow::anyhow!("encryption failed: {}", e))?,
-            };
-
-            // Write chunk: nonce + length + ciphertext
-            writer.write_all(&nonce).await?;
-            writer.write_u32(ciphertext.len() as u32).await?;
-            writer.write_all(&ciphertext).await?;
-
-            total_bytes += n as u64;
+        let mut flags_byte = flags.to_byte();
+        if self.legacy_cipher.is_some() {
+            flags_byte |= FLAG_SUBKEY;
         }
+        let mut header = vec![VERSION_V2_STREAM, flags_byte];
+        if let Some(id) = &flags.file_id {
+            header.extend_from_slice(id);
+        }
+        writer.write_all(&header).await?;
 
-        writer.flush().await?;
-        Ok(total_bytes)
+        match flags.file_id {
+            Some(_) => encrypt_chunks(&self.cipher, reader, writer, Some(&header_aad(&header, aad))).await,
+            None => encrypt_chunks(&self.cipher, reader, writer, aad).await,
+        }
     }
 
     /// Decrypts streaming format from reader, writing plaintext to writer
@@ -144,68 +245,267 @@ impl StreamEncryptor {
         // Read flags
         let flags_byte = reader.read_u8().await
             .context("reading flags byte")?;
-        let flags = FormatFlags::from_byte(flags_byte);
-
-        let mut total_bytes = 0u64;
-        let mut nonce_buf = [0u8; 24];
-
-        loop {
-            // Try to read nonce (24 bytes)
-            match reader.read_exact(&mut nonce_buf).await {
-                Ok(_) => {},
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                    // End of file reached
-                    break;
-                },
-                Err(e) => return Err(e.into()),
-            }
+        let mut flags = FormatFlags::from_byte(flags_byte);
+        let mut header = vec![version, flags_byte];
+        if flags_byte & FLAG_FILE_ID != 0 {
+            flags.file_id = Some(read_file_id(reader, &mut header).await?);
+        }
 
-            #[allow(deprecated)]
-            let nonce = XNonce::from_slice(&nonce_buf);
-
-            // Read chunk length
-            let chunk_len = reader.read_u32().await
-                .context("reading chunk length")? as usize;
-
-            // Read encrypted chunk
-            let mut ciphertext = vec![0u8; chunk_len];
-            reader.read_exact(&mut ciphertext).await
-                .context("reading encrypted chunk")?;
-
-            // Decrypt chunk
-            let plaintext = match aad {
-                Some(a) => self.cipher.decrypt(
-                    nonce,
-                    Payload {
-                        msg: &ciphertext,
-                        aad: a,
-                    },
-                )
-                .map_err(|e| anyhow::anyhow!("decryption failed: {}", e))?,
-                None => self.cipher
-                    .decrypt(nonce, ciphertext.as_slice())
-                    .map_err(|e| anyhow::anyhow!("decryption failed: {}", e))?,
-            };
-
-            // Write decrypted chunk
-            writer.write_all(&plaintext).await?;
-            total_bytes += plaintext.len() as u64;
+        let cipher = match &self.legacy_cipher {
+            Some(legacy) if flags_byte & FLAG_SUBKEY == 0 => legacy,
+            _ => &self.cipher,
+        };
+        let total_bytes = match flags.file_id {
+            Some(_) => decrypt_chunks(cipher, reader, writer, Some(&header_aad(&header, aad))).await?,
+            None => decrypt_chunks(cipher, reader, writer, aad).await?,
+        };
+        Ok((total_bytes, flags))
+    }
+
+    /// Encrypts data to one or more X25519 recipients (V3 format).
+    /// A fresh random file key encrypts the chunks and is wrapped once per
+    /// recipient, so no master key is needed to produce the file.
+    /// File format: \[version:1\]\[flags:1\]\[stanza_count:1\]\[stanzas...\]\[file_id:16\]?\[chunks...\]
+    pub async fn encrypt_stream_to_recipients<R, W>(
+        reader: &mut R,
+        writer: &mut W,
+        recipients: &[Recipient],
+        flags: FormatFlags,
+        aad: Option<&[u8]>,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if recipients.is_empty() {
+            anyhow::bail!("at least one recipient is required");
+        }
+        if recipients.len() > MAX_RECIPIENTS {
+            anyhow::bail!("too many recipients: {} (max {})", recipients.len(), MAX_RECIPIENTS);
+        }
+
+        let file_key = LockedBox::<[u8; 32]>::from_fn(|k| OsRng.fill_bytes(k));
+
+        let mut header = vec![VERSION_V3_RECIPIENTS, flags.to_byte(), recipients.len() as u8];
+        for recipient in recipients {
+            recipient.wrap_file_key(&file_key)?.write_to(&mut header);
         }
+        if let Some(id) = &flags.file_id {
+            header.extend_from_slice(id);
+        }
+        writer.write_all(&header).await?;
+
+        let cipher = LockedBox::new(
+            XChaCha20Poly1305::new_from_slice(&file_key[..]).expect("file key is always 32 bytes"),
+        );
+        drop(file_key);
 
-        writer.flush().await?;
+        // Chunks authenticate the whole header so stanzas and flags can't be swapped
+        let chunk_aad = header_aad(&header, aad);
+        encrypt_chunks(&cipher, reader, writer, Some(&chunk_aad)).await
+    }
+
+    /// Decrypts a V3 recipient file using the first identity that matches a stanza.
+    pub async fn decrypt_stream_with_identities<R, W>(
+        reader: &mut R,
+        writer: &mut W,
+        identities: &[Identity],
+        aad: Option<&[u8]>,
+    ) -> Result<(u64, FormatFlags)>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let version = reader.read_u8().await
+            .context("reading version byte")?;
+        if version != VERSION_V3_RECIPIENTS {
+            anyhow::bail!("unsupported file format version: {}", version);
+        }
+
+        let flags_byte = reader.read_u8().await
+            .context("reading flags byte")?;
+        let mut flags = FormatFlags::from_byte(flags_byte);
+
+        let count = reader.read_u8().await
+            .context("reading stanza count")? as usize;
+        if count == 0 || count > MAX_RECIPIENTS {
+            anyhow::bail!("invalid recipient stanza count: {}", count);
+        }
+
+        let mut header = vec![version, flags_byte, count as u8];
+        let mut stanzas = Vec::with_capacity(count);
+        for _ in 0..count {
+            stanzas.push(RecipientStanza::read_from(reader, &mut header).await?);
+        }
+        if flags_byte & FLAG_FILE_ID != 0 {
+            flags.file_id = Some(read_file_id(reader, &mut header).await?);
+        }
+
+        let file_key = identities
+            .iter()
+            .find_map(|id| stanzas.iter().find_map(|s| id.unwrap_file_key(s)))
+            .ok_or_else(|| anyhow::anyhow!("no identity matches any recipient of this file"))?;
+
+        let cipher = LockedBox::new(
+            XChaCha20Poly1305::new_from_slice(&file_key[..]).expect("file key is always 32 bytes"),
+        );
+        drop(file_key);
+
+        let chunk_aad = header_aad(&header, aad);
+        let total_bytes = decrypt_chunks(&cipher, reader, writer, Some(&chunk_aad)).await?;
         Ok((total_bytes, flags))
     }
 }
 
+/// Reads the file ID ending a header, appending it to `header`
+async fn read_file_id<R>(reader: &mut R, header: &mut Vec<u8>) -> Result<FileId>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut id = [0u8; FILE_ID_LEN];
+    reader.read_exact(&mut id).await.context("reading file ID")?;
+    header.extend_from_slice(&id);
+    Ok(id)
+}
+
+/// Builds the per-chunk AAD for V3 files and files with a file ID: the raw
+/// header followed by caller AAD
+fn header_aad(header: &[u8], aad: Option<&[u8]>) -> Vec<u8> {
+    let mut out = header.to_vec();
+    if let Some(a) = aad {
+        out.extend_from_slice(a);
+    }
+    out
+}
+
+/// Encrypts reader into writer as a sequence of independently sealed chunks
+async fn encrypt_chunks<R, W>(
+    cipher: &XChaCha20Poly1305,
+    reader: &mut R,
+    writer: &mut W,
+    aad: Option<&[u8]>,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut total_bytes = 0u64;
+
+    loop {
+        // Read chunk from source
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break; // EOF
+        }
+
+        let plaintext = &buffer[..n];
+
+        // Generate unique nonce for this chunk
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        // Encrypt chunk with optional AAD
+        let ciphertext = match aad {
+            Some(a) => cipher.encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: a,
+                },
+            )
+            .map_err(|e| anyhow::anyhow!("encryption failed: {}", e))?,
+            None => cipher
+                .encrypt(&nonce, plaintext)
+                .map_err(|e| anyhow::anyhow!("encryption failed: {}", e))?,
+        };
+
+        // Write chunk: nonce + length + ciphertext
+        writer.write_all(&nonce).await?;
+        writer.write_u32(ciphertext.len() as u32).await?;
+        writer.write_all(&ciphertext).await?;
+
+        total_bytes += n as u64;
+    }
+
+    writer.flush().await?;
+    Ok(total_bytes)
+}
+
+/// Decrypts chunks produced by [`encrypt_chunks`] until EOF
+async fn decrypt_chunks<R, W>(
+    cipher: &XChaCha20Poly1305,
+    reader: &mut R,
+    writer: &mut W,
+    aad: Option<&[u8]>,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut total_bytes = 0u64;
+    let mut nonce_buf = [0u8; 24];
+
+    loop {
+        // Try to read nonce (24 bytes)
+        match reader.read_exact(&mut nonce_buf).await {
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                // End of file reached
+                break;
+            },
+            Err(e) => return Err(e.into()),
+        }
+
+        #[allow(deprecated)]
+        let nonce = XNonce::from_slice(&nonce_buf);
+
+        // Read chunk length
+        let chunk_len = reader.read_u32().await
+            .context("reading chunk length")? as usize;
+        // Checked before allocating: the length isn't authenticated yet
+        if chunk_len > MAX_CHUNK_LEN {
+            anyhow::bail!("chunk length {} exceeds the maximum of {}", chunk_len, MAX_CHUNK_LEN);
+        }
+
+        // Read encrypted chunk
+        let mut ciphertext = vec![0u8; chunk_len];
+        reader.read_exact(&mut ciphertext).await
+            .context("reading encrypted chunk")?;
+
+        // Decrypt chunk
+        let plaintext = match aad {
+            Some(a) => cipher.decrypt(
+                nonce,
+                Payload {
+                    msg: &ciphertext,
+                    aad: a,
+                },
+            )
+            .map_err(|e| anyhow::anyhow!("decryption failed: {}", e))?,
+            None => cipher
+                .decrypt(nonce, ciphertext.as_slice())
+                .map_err(|e| anyhow::anyhow!("decryption failed: {}", e))?,
+        };
+
+        // Write decrypted chunk
+        writer.write_all(&plaintext).await?;
+        total_bytes += plaintext.len() as u64;
+    }
+
+    writer.flush().await?;
+    Ok(total_bytes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use chacha20poly1305::KeyInit;
     use std::io::Cursor;
 
-    fn make_cipher() -> XChaCha20Poly1305 {
+    fn make_cipher() -> SharedCipher {
         let key = [0x42u8; 32];
-        XChaCha20Poly1305::new_from_slice(&key).expect("valid key")
+        let cipher = XChaCha20Poly1305::new_from_slice(&key).expect("valid key");
+        std::sync::Arc::new(LockedBox::new(cipher))
     }
 
     #[tokio::test]
@@ -217,7 +517,7 @@ mod tests {
         let mut reader = Cursor::new(plaintext.to_vec());
         let mut encrypted = Vec::new();
 
-        let flags = FormatFlags { compressed: false };
+        let flags = FormatFlags::default();
         encryptor
             .encrypt_stream(&mut reader, &mut encrypted, flags, None)
             .await
@@ -245,7 +545,7 @@ mod tests {
         let mut reader = Cursor::new(plaintext.clone());
         let mut encrypted = Vec::new();
 
-        let flags = FormatFlags { compressed: false };
+        let flags = FormatFlags::default();
         encryptor
             .encrypt_stream(&mut reader, &mut encrypted, flags, None)
             .await
@@ -273,7 +573,7 @@ mod tests {
         let mut reader = Cursor::new(plaintext.to_vec());
         let mut encrypted = Vec::new();
 
-        let flags = FormatFlags { compressed: false };
+        let flags = FormatFlags::default();
         encryptor
             .encrypt_stream(&mut reader, &mut encrypted, flags, Some(aad))
             .await
@@ -301,14 +601,137 @@ mod tests {
 
     #[tokio::test]
     async fn test_flags_round_trip() {
-        let flags = FormatFlags { compressed: true };
+        let flags = FormatFlags {
+            compressed: true,
+            ..Default::default()
+        };
         let byte = flags.to_byte();
         let parsed = FormatFlags::from_byte(byte);
         assert!(parsed.compressed);
 
-        let flags = FormatFlags { compressed: false };
+        let flags = FormatFlags::default();
         let byte = flags.to_byte();
         let parsed = FormatFlags::from_byte(byte);
         assert!(!parsed.compressed);
     }
+
+    #[tokio::test]
+    async fn test_recipient_round_trip() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let recipients = vec![alice.recipient(), bob.recipient()];
+
+        let plaintext = vec![0x17u8; CHUNK_SIZE + 123];
+        let mut reader = Cursor::new(plaintext.clone());
+        let mut encrypted = Vec::new();
+
+        let flags = FormatFlags::default();
+        StreamEncryptor::encrypt_stream_to_recipients(&mut reader, &mut encrypted, &recipients, flags, Some(b"name"))
+            .await
+            .expect("encryption failed");
+        assert_eq!(encrypted[0], VERSION_V3_RECIPIENTS);
+
+        // Either recipient can decrypt
+        for identity in [alice, bob] {
+            let mut decrypted = Vec::new();
+            StreamEncryptor::decrypt_stream_with_identities(
+                &mut Cursor::new(encrypted.clone()),
+                &mut decrypted,
+                &[identity],
+                Some(b"name"),
+            )
+            .await
+            .expect("decryption failed");
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recipient_rejects_wrong_identity_and_header_tamper() {
+        let alice = Identity::generate();
+        let mallory = Identity::generate();
+
+        let mut encrypted = Vec::new();
+        let flags = FormatFlags::default();
+        StreamEncryptor::encrypt_stream_to_recipients(
+            &mut Cursor::new(b"for alice".to_vec()),
+            &mut encrypted,
+            &[alice.recipient()],
+            flags,
+            None,
+        )
+        .await
+        .expect("encryption failed");
+
+        let mut out = Vec::new();
+        let result = StreamEncryptor::decrypt_stream_with_identities(
+            &mut Cursor::new(encrypted.clone()),
+            &mut out,
+            &[mallory],
+            None,
+        )
+        .await;
+        assert!(result.is_err(), "non-recipient must not decrypt");
+
+        // Flipping the flags byte breaks chunk authentication
+        encrypted[1] ^= 0x01;
+        let result = StreamEncryptor::decrypt_stream_with_identities(
+            &mut Cursor::new(encrypted),
+            &mut out,
+            &[alice],
+            None,
+        )
+        .await;
+        assert!(result.is_err(), "tampered header must be rejected");
+    }
+
+    #[tokio::test]
+    async fn test_file_id_binds_chunks_to_their_file() {
+        let encryptor = StreamEncryptor::new(make_cipher());
+        let encrypt = |data: &'static [u8]| {
+            let encryptor = encryptor.clone();
+            async move {
+                let flags = FormatFlags {
+                    file_id: Some(generate_file_id()),
+                    ..Default::default()
+                };
+                let mut encrypted = Vec::new();
+                encryptor
+                    .encrypt_stream(&mut Cursor::new(data), &mut encrypted, flags, None)
+                    .await
+                    .expect("encryption failed");
+                (flags, encrypted)
+            }
+        };
+        let (flags, a) = encrypt(b"file a").await;
+        let (_, b) = encrypt(b"file b").await;
+        assert_eq!(FormatFlags::from_v2_header(&a), flags);
+
+        let mut decrypted = Vec::new();
+        let (_, parsed) = encryptor
+            .decrypt_stream(&mut Cursor::new(a.clone()), &mut decrypted, None)
+            .await
+            .expect("decryption failed");
+        assert_eq!(decrypted, b"file a");
+        assert_eq!(parsed, flags);
+
+        // A's header with B's chunk
+        let header_len = 2 + FILE_ID_LEN;
+        let spliced = [&a[..header_len], &b[header_len..]].concat();
+        let result = encryptor
+            .decrypt_stream(&mut Cursor::new(spliced), &mut Vec::new(), None)
+            .await;
+        assert!(result.is_err(), "chunks of another file must be rejected");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_chunk_is_rejected_before_allocation() {
+        let cipher = make_cipher();
+        let mut forged = vec![0u8; 24];
+        forged.extend_from_slice(&u32::MAX.to_be_bytes());
+        let err = decrypt_chunks(&cipher, &mut Cursor::new(forged), &mut Vec::new(), None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds the maximum"));
+    }
 }
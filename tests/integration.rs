@@ -3,7 +3,18 @@ use std::fs;
 use std::io::Cursor;
 use tempfile::TempDir;
 
-use securefs::{config, key_manager, storagefile_ops, streaming};
+use securefs::{config, encryptor, key_manager, key_provider, keyfile, storagefile_ops, streaming};
+
+/// Writes a raw key with the owner-only mode the key loader requires
+fn write_test_key(path: &std::path::Path, key: &[u8]) -> Result<()> {
+    fs::write(path, key)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
 
 #[tokio::test]
 async fn securefileops_roundtrip() -> Result<()> {
@@ -14,17 +25,17 @@ async fn securefileops_roundtrip() -> Result<()> {
 
     // write deterministic key (32 bytes)
     let key = [0x42u8; 32];
-    fs::write(&key_path, key)?;
+    write_test_key(&key_path, &key)?;
 
     // create a minimal config pointing at our temp files
-    let cfg = config::Config {
-        key_path: key_path.to_string_lossy().to_string(),
-        storage_dir: storage_dir.to_string_lossy().to_string(),
-    };
+    let cfg = config::Config::new(
+        key_path.to_string_lossy().to_string(),
+        storage_dir.to_string_lossy().to_string(),
+    );
 
     // use KeyManager and SecureFileOps
-    let km = key_manager::KeyManager::new(&cfg).await?;
-    let ops = storagefile_ops::SecureFileOps::new(km, cfg.storage_dir.clone());
+    let km = key_manager::KeyManager::open(&cfg).await?;
+    let ops = storagefile_ops::SecureFileOps::new(km, cfg.storage_dir.clone()).await?;
 
     let name = "it.txt";
     let data = b"integration secret";
@@ -46,18 +57,18 @@ async fn securefileops_roundtrip_compressed() -> Result<()> {
 
     // write deterministic key (32 bytes)
     let key = [0x42u8; 32];
-    fs::write(&key_path, key)?;
+    write_test_key(&key_path, &key)?;
 
     // create a minimal config pointing at our temp files
-    let cfg = config::Config {
-        key_path: key_path.to_string_lossy().to_string(),
-        storage_dir: storage_dir.to_string_lossy().to_string(),
-    };
+    let cfg = config::Config::new(
+        key_path.to_string_lossy().to_string(),
+        storage_dir.to_string_lossy().to_string(),
+    );
 
     // use KeyManager and SecureFileOps with compression enabled
-    let km = key_manager::KeyManager::new(&cfg).await?;
+    let km = key_manager::KeyManager::open(&cfg).await?;
     let ops =
-        storagefile_ops::SecureFileOps::new(km, cfg.storage_dir.clone()).with_compression(true);
+        storagefile_ops::SecureFileOps::new(km, cfg.storage_dir.clone()).await?.with_compression(true);
 
     let name = "compressed.txt";
     let data = b"integration secret with compression enabled for testing";
@@ -77,15 +88,15 @@ async fn setup_test_env() -> Result<(TempDir, storagefile_ops::SecureFileOps)> {
     let key_path = tmp.path().join("testkey.bin");
 
     let key = [0x42u8; 32];
-    fs::write(&key_path, key)?;
+    write_test_key(&key_path, &key)?;
 
-    let cfg = config::Config {
-        key_path: key_path.to_string_lossy().to_string(),
-        storage_dir: storage_dir.to_string_lossy().to_string(),
-    };
+    let cfg = config::Config::new(
+        key_path.to_string_lossy().to_string(),
+        storage_dir.to_string_lossy().to_string(),
+    );
 
-    let km = key_manager::KeyManager::new(&cfg).await?;
-    let ops = storagefile_ops::SecureFileOps::new(km, cfg.storage_dir.clone());
+    let km = key_manager::KeyManager::open(&cfg).await?;
+    let ops = storagefile_ops::SecureFileOps::new(km, cfg.storage_dir.clone()).await?;
 
     Ok((tmp, ops))
 }
@@ -102,7 +113,7 @@ async fn test_delete_file() -> Result<()> {
     assert!(ops.exists(name).await);
 
     // Verify metadata file exists
-    let meta_path = tmp.path().join("storage").join("to_delete.meta.json");
+    let meta_path = tmp.path().join("storage/.securefs-meta/to_delete.txt");
     assert!(meta_path.exists());
 
     // Delete file
@@ -143,6 +154,106 @@ async fn test_list_files() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_metadata_per_file() -> Result<()> {
+    let (_tmp, ops) = setup_test_env().await?;
+
+    // Names differing only in extension used to share one sidecar
+    ops.write_encrypted("report.txt", b"text").await?;
+    ops.write_encrypted("report.pdf", b"portable document").await?;
+    ops.write_encrypted("settings.json", br#"{"theme":"dark"}"#).await?;
+    assert_eq!(ops.get_metadata("report.txt").await?.size, 4);
+    assert_eq!(ops.get_metadata("report.pdf").await?.size, 17);
+
+    let files = ops.list_files().await?;
+    let names: Vec<_> = files.iter().map(|f| f.0.as_str()).collect();
+    assert_eq!(names, ["report.pdf", "report.txt", "settings.json"]);
+    assert!(files.iter().all(|(_, _, has_meta)| *has_meta));
+    assert_eq!(ops.read_encrypted("settings.json").await?, br#"{"theme":"dark"}"#);
+
+    ops.delete_file("report.txt").await?;
+    assert_eq!(ops.get_metadata("report.pdf").await?.filename, "report.pdf");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_overwrite_keeps_previous_version() -> Result<()> {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+    /// A source that breaks after its first bytes, like a dropped connection
+    struct Broken;
+    impl AsyncRead for Broken {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "source broke")))
+        }
+    }
+
+    let (tmp, ops) = setup_test_env().await?;
+    ops.write_encrypted_stream("data.bin", &mut Cursor::new(vec![1u8; 100_000])).await?;
+
+    let mut failing = Cursor::new(vec![2u8; 100_000]).chain(Broken);
+    assert!(ops.write_encrypted_stream("data.bin", &mut failing).await.is_err());
+
+    assert_eq!(ops.read_encrypted_auto("data.bin").await?.0, vec![1u8; 100_000]);
+    assert_eq!(ops.get_metadata("data.bin").await?.size, 100_000);
+    // No temporary files are left behind
+    let leftovers: Vec<_> = fs::read_dir(tmp.path().join("storage"))?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with(".securefs-tmp-"))
+        .collect();
+    assert!(leftovers.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subdirectories() -> Result<()> {
+    let (tmp, ops) = setup_test_env().await?;
+
+    ops.write_encrypted("top.txt", b"top").await?;
+    ops.write_encrypted("docs/2026/report.pdf", b"report").await?;
+    ops.write_encrypted_stream("docs/2026/q1.xlsx", &mut Cursor::new(b"q1".to_vec())).await?;
+    ops.write_encrypted("docs/notes.md", b"notes").await?;
+
+    assert_eq!(ops.read_encrypted("docs/2026/report.pdf").await?, b"report");
+    assert_eq!(ops.read_encrypted_auto("docs/2026/q1.xlsx").await?.0, b"q1");
+    assert!(ops.get_metadata("docs/notes.md").await.is_ok());
+
+    let names = |files: Vec<(String, u64, bool)>| files.into_iter().map(|f| f.0).collect::<Vec<_>>();
+    assert_eq!(
+        names(ops.list_files().await?),
+        ["docs/2026/q1.xlsx", "docs/2026/report.pdf", "docs/notes.md", "top.txt"]
+    );
+    assert_eq!(names(ops.list_dir("docs", false).await?), ["docs/notes.md"]);
+    assert_eq!(names(ops.list_dir("docs", true).await?).len(), 3);
+    assert_eq!(ops.list_subdirs(None).await?, ["docs"]);
+    assert_eq!(ops.list_subdirs(Some("docs")).await?, ["docs/2026"]);
+
+    // Directories must be removed explicitly, and only when empty unless recursive
+    assert!(ops.delete_file("docs").await.is_err());
+    assert!(ops.remove_dir("docs", false).await.is_err());
+    ops.remove_dir("docs/2026", true).await?;
+    assert!(!ops.exists("docs/2026/report.pdf").await);
+    assert_eq!(names(ops.list_files().await?), ["docs/notes.md", "top.txt"]);
+
+    // A symlinked parent can't redirect writes outside the root
+    #[cfg(unix)]
+    {
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside)?;
+        std::os::unix::fs::symlink(&outside, tmp.path().join("storage/link"))?;
+        assert!(ops.write_encrypted("link/escape.txt", b"x").await.is_err());
+        assert!(fs::read_dir(&outside)?.next().is_none());
+    }
+    #[cfg(not(unix))]
+    let _ = tmp;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_metadata_persistence() -> Result<()> {
     let (tmp, ops) = setup_test_env().await?;
@@ -158,11 +269,28 @@ async fn test_metadata_persistence() -> Result<()> {
     assert_eq!(metadata.filename, "meta_test.txt");
     assert_eq!(metadata.size, data.len() as u64);
 
-    // Verify metadata file content directly
-    let meta_path = tmp.path().join("storage").join("meta_test.meta.json");
-    let content = fs::read_to_string(meta_path)?;
-    assert!(content.contains("meta_test.txt"));
-    assert!(content.contains(&data.len().to_string()));
+    // The sidecar is sealed: neither the name nor the size is readable
+    let storage = tmp.path().join("storage");
+    let meta_path = storage.join(".securefs-meta/meta_test.txt");
+    let content = fs::read_to_string(&meta_path)?;
+    assert!(!content.contains("meta_test"));
+    assert!(!content.contains("size"));
+
+    // A sidecar copied from another file, or kept from an older version, is rejected
+    ops.write_encrypted("other.txt", b"other").await?;
+    let original = fs::read(&meta_path)?;
+    fs::copy(storage.join(".securefs-meta/other.txt"), &meta_path)?;
+    assert!(ops.get_metadata(name).await.is_err());
+    fs::write(&meta_path, &original)?;
+    assert!(ops.get_metadata(name).await.is_ok());
+    let stale = fs::read(storage.join(".securefs-meta/other.txt"))?;
+    ops.write_encrypted("other.txt", b"other, longer").await?;
+    fs::write(storage.join(".securefs-meta/other.txt"), stale)?;
+    assert!(ops.get_metadata("other.txt").await.is_err());
+
+    // Plaintext sidecars from older versions are not trusted
+    fs::write(&meta_path, r#"{"filename":"meta_test.txt","size":1}"#)?;
+    assert!(ops.get_metadata(name).await.is_err());
 
     Ok(())
 }
@@ -235,6 +363,59 @@ async fn test_concurrent_operations() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_conditional_writes() -> Result<()> {
+    use securefs::error::SecureFsError;
+    use securefs::write_mode::WriteMode;
+
+    let (_tmp, ops) = setup_test_env().await?;
+    let is = |e: &anyhow::Error, f: fn(&SecureFsError) -> bool| e.downcast_ref().is_some_and(f);
+
+    let v1 = ops.write_encrypted_with("doc.txt", b"one", WriteMode::CreateNew).await?;
+    let err = ops.write_encrypted_with("doc.txt", b"two", WriteMode::CreateNew).await.unwrap_err();
+    assert!(is(&err, |e| matches!(e, SecureFsError::AlreadyExists(_))));
+    assert_eq!(ops.version("doc.txt").await?, v1);
+
+    let (_, v2) = ops
+        .write_encrypted_stream_with("doc.txt", &mut Cursor::new(b"two".to_vec()), WriteMode::IfMatch(v1))
+        .await?;
+    assert_ne!(v1, v2);
+    let err = ops.write_encrypted_with("doc.txt", b"stale", WriteMode::IfMatch(v1)).await.unwrap_err();
+    assert!(is(&err, |e| matches!(e, SecureFsError::VersionMismatch { .. })));
+    assert!(ops.write_encrypted_with("missing.txt", b"x", WriteMode::IfMatch(v1)).await.is_err());
+    assert_eq!(ops.read_encrypted_auto("doc.txt").await?.0, b"two");
+
+    // Concurrent read-modify-write loops lose no increments
+    ops.write_encrypted("counter", b"0").await?;
+    let ops = std::sync::Arc::new(ops);
+    let mut tasks = Vec::new();
+    for _ in 0..8 {
+        let ops = ops.clone();
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..5 {
+                loop {
+                    let version = ops.version("counter").await?;
+                    let n: u64 = String::from_utf8(ops.read_encrypted("counter").await?)?.parse()?;
+                    let next = (n + 1).to_string();
+                    match ops.write_encrypted_with("counter", next.as_bytes(), WriteMode::IfMatch(version)).await {
+                        Ok(_) => break,
+                        Err(e) if is(&e, |e| matches!(e, SecureFsError::VersionMismatch { .. })) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            anyhow::Ok(())
+        }));
+    }
+    for task in tasks {
+        task.await??;
+    }
+    assert_eq!(ops.read_encrypted("counter").await?, b"40");
+    assert_eq!(ops.get_metadata("counter").await?.size, 2);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_streaming_roundtrip() -> Result<()> {
     let (_tmp, ops) = setup_test_env().await?;
@@ -264,15 +445,15 @@ async fn test_auto_format_detection() -> Result<()> {
     let key_path = tmp.path().join("testkey.bin");
 
     let key = [0x42u8; 32];
-    fs::write(&key_path, key)?;
+    write_test_key(&key_path, &key)?;
 
-    let cfg = config::Config {
-        key_path: key_path.to_string_lossy().to_string(),
-        storage_dir: storage_dir.to_string_lossy().to_string(),
-    };
+    let cfg = config::Config::new(
+        key_path.to_string_lossy().to_string(),
+        storage_dir.to_string_lossy().to_string(),
+    );
 
-    let km = key_manager::KeyManager::new(&cfg).await?;
-    let ops = storagefile_ops::SecureFileOps::new(km, cfg.storage_dir.clone());
+    let km = key_manager::KeyManager::open(&cfg).await?;
+    let ops = storagefile_ops::SecureFileOps::new(km, cfg.storage_dir.clone()).await?;
 
     // Write V1 format (buffer mode)
     let v1_name = "v1_file.txt";
@@ -298,5 +479,894 @@ async fn test_auto_format_detection() -> Result<()> {
     let raw_v2 = fs::read(v2_path)?;
     assert_eq!(raw_v2[0], streaming::VERSION_V2_STREAM);
 
+    // V1 files whose random nonce starts with a version byte still read as V1
+    let path = storage_dir.join("lookalike.txt");
+    for attempt in 0.. {
+        ops.write_encrypted("lookalike.txt", b"lookalike").await?;
+        let first = fs::read(&path)?[0];
+        if first == streaming::VERSION_V2_STREAM || first == streaming::VERSION_V3_RECIPIENTS {
+            break;
+        }
+        assert!(attempt < 10_000, "no nonce with a version byte in 10000 writes");
+    }
+    assert_eq!(ops.read_encrypted_auto("lookalike.txt").await?.0, b"lookalike");
+    let mut out = Vec::new();
+    ops.read_encrypted_stream_auto("lookalike.txt", &mut out).await?;
+    assert_eq!(out, b"lookalike");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keyless_recipient_write() -> Result<()> {
+    let tmp = TempDir::new()?;
+    let storage_dir = tmp.path().join("storage");
+    let identity_path = tmp.path().join("reader.identity");
+
+    let identity = key_manager::KeyManager::generate_identity(&identity_path).await?;
+    let recipient: key_manager::Recipient = identity.recipient().to_string().parse()?;
+
+    // Producer holds only the public recipient
+    let producer = storagefile_ops::SecureFileOps::keyless(&storage_dir)
+        .await?
+        .with_recipients(vec![recipient]);
+    producer.write_encrypted("ingest.log", b"event data").await?;
+    let mut reader = Cursor::new(b"streamed event data".to_vec());
+    producer.write_encrypted_stream("ingest-stream.log", &mut reader).await?;
+
+    // Producer can't read back what it wrote
+    assert!(producer.read_encrypted_auto("ingest.log").await.is_err());
+
+    // Identity holder can
+    let identity = key_manager::KeyManager::load_identity(&identity_path).await?;
+    let consumer = storagefile_ops::SecureFileOps::keyless(&storage_dir).await?.with_identity(identity);
+    let (data, _) = consumer.read_encrypted_auto("ingest.log").await?;
+    assert_eq!(data, b"event data");
+
+    let mut output = Vec::new();
+    consumer.read_encrypted_stream("ingest-stream.log", &mut output).await?;
+    assert_eq!(output, b"streamed event data");
+
+    // A vault with encrypted names can't take a write under a plaintext name
+    let vault_dir = tmp.path().join("vault");
+    let km = key_manager::KeyManager::from_key_file(&keyfile::KeyFile::new([0x42u8; 32]));
+    storagefile_ops::SecureFileOps::init_with_options(
+        km,
+        &vault_dir,
+        securefs::vault::VaultOptions {
+            encrypted_names: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert!(storagefile_ops::SecureFileOps::keyless(&vault_dir).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_structured_key_file() -> Result<()> {
+    let tmp = TempDir::new()?;
+    let key_path = tmp.path().join("generated.key");
+    let cfg = config::Config::new(
+        key_path.to_string_lossy().to_string(),
+        tmp.path().join("storage").to_string_lossy().to_string(),
+    );
+
+    // Newly generated keys use the structured format and reload to the same key
+    let km = key_manager::KeyManager::create(&cfg).await?;
+    let key_id = km.key_id();
+    let raw = fs::read(&key_path)?;
+    assert_eq!(raw.len(), keyfile::KEY_FILE_LEN);
+    assert!(raw.starts_with(&keyfile::KEY_FILE_MAGIC));
+    assert_eq!(key_manager::KeyManager::open(&cfg).await?.key_id(), key_id);
+
+    // A corrupted key file is rejected with a clear error
+    let mut corrupted = raw.clone();
+    corrupted[50] ^= 0x01;
+    fs::write(&key_path, &corrupted)?;
+    let err = key_manager::KeyManager::open(&cfg).await.err().expect("corrupted key must fail");
+    assert!(format!("{:#}", err).contains("checksum mismatch"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_open_never_creates_keys() -> Result<()> {
+    let tmp = TempDir::new()?;
+    let key_path = tmp.path().join("typo.key");
+    let cfg = config::Config::new(
+        key_path.to_string_lossy().to_string(),
+        tmp.path().join("storage").to_string_lossy().to_string(),
+    );
+
+    // A missing key is an error, not a prompt to mint a new one
+    let err = key_manager::KeyManager::open(&cfg).await.err().expect("missing key must fail");
+    assert!(matches!(
+        err.downcast_ref::<securefs::error::SecureFsError>(),
+        Some(securefs::error::SecureFsError::KeyNotFound(_))
+    ));
+    assert!(!key_path.exists());
+
+    // create refuses to replace an existing key
+    let key_id = key_manager::KeyManager::create(&cfg).await?.key_id();
+    assert!(key_manager::KeyManager::create(&cfg).await.is_err());
+    assert_eq!(key_manager::KeyManager::open(&cfg).await?.key_id(), key_id);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_env_and_command_key_providers() -> Result<()> {
+    let tmp = TempDir::new()?;
+    let storage_dir = tmp.path().join("storage").to_string_lossy().to_string();
+    let key_hex = hex::encode([0x42u8; 32]);
+
+    // Write with the file provider
+    let key_path = tmp.path().join("testkey.bin");
+    write_test_key(&key_path, &[0x42u8; 32])?;
+    let cfg = config::Config::new(key_path.to_string_lossy(), storage_dir.clone());
+    let ops = storagefile_ops::SecureFileOps::new(key_manager::KeyManager::open(&cfg).await?, &storage_dir).await?;
+    ops.write_encrypted("shared.txt", b"same key, different source").await?;
+
+    // Read back with the same key supplied by an environment variable
+    std::env::set_var("SECUREFS_TEST_PROVIDER_KEY", &key_hex);
+    let cfg = config::Config::new("", storage_dir.clone()).with_key_source(config::KeySource::Env {
+        var: "SECUREFS_TEST_PROVIDER_KEY".to_string(),
+    });
+    cfg.validate()?;
+    let ops = storagefile_ops::SecureFileOps::new(key_manager::KeyManager::open(&cfg).await?, &storage_dir).await?;
+    assert_eq!(ops.read_encrypted("shared.txt").await?, b"same key, different source");
+
+    // ... and by a helper command
+    let cfg = config::Config::new("", storage_dir.clone()).with_key_source(config::KeySource::Command {
+        command: "sh".to_string(),
+        args: vec!["-c".to_string(), format!("echo {}", key_hex)],
+    });
+    let ops = storagefile_ops::SecureFileOps::new(key_manager::KeyManager::open(&cfg).await?, &storage_dir).await?;
+    assert_eq!(ops.read_encrypted("shared.txt").await?, b"same key, different source");
+
+    // Read-only sources can't mint a new key
+    let cfg = config::Config::new("", storage_dir).with_key_source(config::KeySource::Env {
+        var: "SECUREFS_TEST_PROVIDER_UNSET".to_string(),
+    });
+    let err = key_manager::KeyManager::create(&cfg).await.err().expect("unset env var must fail");
+    assert!(err.to_string().contains("read-only"));
+    assert!(key_provider::from_config(&cfg).describe().contains("SECUREFS_TEST_PROVIDER_UNSET"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_paper_backup_verifies_against_store() -> Result<()> {
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let cfg = config::Config::new(
+        tmp.path().join("key.bin").to_string_lossy().to_string(),
+        storage.to_string_lossy().to_string(),
+    );
+    let km = key_manager::KeyManager::create(&cfg).await?;
+    let key_id = km.key_id();
+    let mnemonic = km.export_mnemonic();
+    let paper = km.export_paper_key();
+
+    let ops = storagefile_ops::SecureFileOps::new(km, &storage).await?;
+    assert_eq!(ops.verify_key().await?, None);
+    ops.write_encrypted_stream("big.bin", &mut Cursor::new(vec![7u8; 4096])).await?;
+    ops.write_encrypted("small.txt", b"hello").await?;
+
+    // Both backup forms restore the same key, which authenticates stored files
+    for backup in [mnemonic.as_str(), paper.as_str()] {
+        let restored = key_manager::KeyManager::import_backup(backup)?;
+        assert_eq!(restored.key_id, key_id);
+        let ops = storagefile_ops::SecureFileOps::new(
+            key_manager::KeyManager::from_key_file(&restored),
+            &storage,
+        ).await?;
+        assert_eq!(ops.verify_key().await?, Some(true));
+    }
+
+    // A different key is detected as a mismatch
+    let other = keyfile::KeyFile::new([0x11u8; 32]);
+    let ops = storagefile_ops::SecureFileOps::new(key_manager::KeyManager::from_key_file(&other), &storage).await?;
+    assert_eq!(ops.verify_key().await?, Some(false));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subkeys_keep_legacy_files_readable() -> Result<()> {
+    let tmp = TempDir::new()?;
+    let storage_dir = tmp.path().join("storage");
+    let key_path = tmp.path().join("testkey.bin");
+    write_test_key(&key_path, &[0x42u8; 32])?;
+    let cfg = config::Config::new(
+        key_path.to_string_lossy().to_string(),
+        storage_dir.to_string_lossy().to_string(),
+    );
+    let km = key_manager::KeyManager::open(&cfg).await?;
+
+    // Subkeys are independent of the master key and of each other
+    use key_manager::subkey;
+    let buffer = encryptor::Encryptor::new(km.derive_subkey(subkey::BUFFER_CONTENT));
+    let sealed = buffer.encrypt(b"data", None)?;
+    assert!(encryptor::Encryptor::new(km.cipher()).decrypt(&sealed, None).is_err());
+    assert!(encryptor::Encryptor::new(km.derive_subkey(subkey::STREAM_CONTENT)).decrypt(&sealed, None).is_err());
+    assert_eq!(
+        encryptor::Encryptor::new(km.derive_subkey(subkey::BUFFER_CONTENT)).decrypt(&sealed, None)?,
+        b"data"
+    );
+
+    // Files written with the raw master key by earlier versions
+    fs::create_dir_all(&storage_dir)?;
+    let legacy_v1 = encryptor::Encryptor::new(km.cipher()).encrypt(b"old buffer", None)?;
+    fs::write(storage_dir.join("old_v1"), legacy_v1)?;
+    let mut legacy_v2 = Vec::new();
+    streaming::StreamEncryptor::new(km.cipher())
+        .encrypt_stream(
+            &mut Cursor::new(b"old stream".to_vec()),
+            &mut legacy_v2,
+            streaming::FormatFlags::default(),
+            Some(b"old_v2"),
+        )
+        .await?;
+    fs::write(storage_dir.join("old_v2"), legacy_v2)?;
+
+    let ops = storagefile_ops::SecureFileOps::new(km, &storage_dir).await?;
+    assert_eq!(ops.read_encrypted_auto("old_v1").await?.0, b"old buffer");
+    assert_eq!(ops.read_encrypted_auto("old_v2").await?.0, b"old stream");
+
+    // New stream files are marked as using the subkey
+    ops.write_encrypted_stream("new_v2", &mut Cursor::new(b"new".to_vec())).await?;
+    let raw = fs::read(storage_dir.join("new_v2"))?;
+    assert_eq!(raw[1] & streaming::FLAG_SUBKEY, streaming::FLAG_SUBKEY);
+    assert_eq!(ops.read_encrypted_auto("new_v2").await?.0, b"new");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vault_descriptor_rejects_wrong_key() -> Result<()> {
+    use securefs::error::SecureFsError;
+    use storagefile_ops::SecureFileOps;
+
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let key = keyfile::KeyFile::new([0x42u8; 32]);
+    let km = || key_manager::KeyManager::from_key_file(&key);
+
+    let ops = SecureFileOps::init(km(), &storage, true).await?;
+    ops.write_encrypted("a.txt", b"vault data").await?;
+    assert!(SecureFileOps::init(km(), &storage, false).await.is_err());
+
+    // Reopening with the right key picks up the recorded settings
+    let ops = SecureFileOps::new(km(), &storage).await?;
+    assert!(ops.compression());
+    assert_eq!(ops.verify_key().await?, Some(true));
+    assert_eq!(ops.read_encrypted("a.txt").await?, b"vault data");
+
+    // The wrong key is rejected before any file is touched
+    let wrong = keyfile::KeyFile::new([0x24u8; 32]);
+    let err = SecureFileOps::new(key_manager::KeyManager::from_key_file(&wrong), &storage)
+        .await
+        .err()
+        .expect("wrong key must fail");
+    assert!(matches!(
+        err.downcast_ref::<SecureFsError>(),
+        Some(SecureFsError::KeyMismatch { .. })
+    ));
+
+    // Edited settings fail authentication
+    let path = storage.join(securefs::vault::VAULT_FILE);
+    let json = fs::read_to_string(&path)?;
+    fs::write(&path, json.replace("\"compression\": true", "\"compression\": false"))?;
+    let err = SecureFileOps::new(km(), &storage).await.err().expect("tampered descriptor must fail");
+    assert!(err.to_string().contains("failed authentication"));
+
+    // init refuses a directory already holding another key's files
+    let legacy = tmp.path().join("legacy");
+    let ops = SecureFileOps::new(key_manager::KeyManager::from_key_file(&wrong), &legacy).await?;
+    ops.write_encrypted("old.txt", b"old").await?;
+    assert!(SecureFileOps::init(km(), &legacy, false).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rejects_names_escaping_storage() -> Result<()> {
+    use securefs::error::SecureFsError;
+
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let km = key_manager::KeyManager::from_key_file(&keyfile::KeyFile::new([0x42u8; 32]));
+    let ops = storagefile_ops::SecureFileOps::new(km, &storage).await?;
+
+    let victim = tmp.path().join("victim.txt");
+    fs::write(&victim, b"keep me")?;
+    let outside = format!("../{}", victim.file_name().unwrap().to_string_lossy());
+
+    for name in [outside.as_str(), victim.to_str().unwrap(), ".securefs-vault.json"] {
+        let err = ops.write_encrypted(name, b"x").await.expect_err("write must fail");
+        assert!(matches!(
+            err.downcast_ref::<SecureFsError>(),
+            Some(SecureFsError::InvalidName { .. })
+        ));
+        assert!(ops.delete_file(name).await.is_err());
+        assert!(ops.read_encrypted_auto(name).await.is_err());
+        assert!(!ops.exists(name).await);
+    }
+    assert!(ops
+        .write_encrypted_stream(&outside, &mut Cursor::new(b"x".to_vec()))
+        .await
+        .is_err());
+    assert_eq!(fs::read(&victim)?, b"keep me");
+
+    // Only SecureFS's own names are reserved
+    ops.write_encrypted("a.meta.json", b"x").await?;
+    assert_eq!(ops.read_encrypted("a.meta.json").await?, b"x");
+    let listed: Vec<String> = ops.list_files().await?.into_iter().map(|(name, ..)| name).collect();
+    assert_eq!(listed, ["a.meta.json"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reencrypt_all_resumes_after_failure() -> Result<()> {
+    use securefs::error::SecureFsError;
+    use storagefile_ops::SecureFileOps;
+
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let old = keyfile::KeyFile::new([0x42u8; 32]);
+    let new = keyfile::KeyFile::new([0x24u8; 32]);
+    let old_km = || key_manager::KeyManager::from_key_file(&old);
+    let new_km = key_manager::KeyManager::from_key_file(&new);
+
+    let ops = SecureFileOps::init(old_km(), &storage, false).await?;
+    for i in 0..8 {
+        ops.write_encrypted(&format!("buf{}.txt", i), format!("buffer {}", i).as_bytes()).await?;
+    }
+    ops.write_encrypted_stream("stream.bin", &mut Cursor::new(vec![7u8; 200_000])).await?;
+    ops.with_compression(true).write_encrypted("packed.txt", &[b'z'; 4096]).await?;
+
+    // A file the old key can't read stops the rotation before the vault flips
+    fs::write(storage.join("broken.txt"), [0u8; 64])?;
+    let ops = SecureFileOps::new(old_km(), &storage).await?;
+    assert!(ops.reencrypt_all(&new_km, 3).await.is_err());
+    assert!(securefs::rotation::in_progress(&storage).await);
+    let reopened = SecureFileOps::new(old_km(), &storage).await?;
+    let mut unreadable = 0;
+    for i in 0..8 {
+        unreadable += reopened.read_encrypted(&format!("buf{}.txt", i)).await.is_err() as usize;
+    }
+    assert!(unreadable > 0);
+
+    // Given the new key, every file reads, but nothing can be written
+    let reopened = reopened.with_rotation_key(key_manager::KeyManager::from_key_file(&new));
+    for i in 0..8 {
+        let name = format!("buf{}.txt", i);
+        assert_eq!(reopened.read_encrypted(&name).await?, format!("buffer {}", i).as_bytes());
+        assert_eq!(reopened.get_metadata(&name).await?.size, 8);
+    }
+    assert_eq!(reopened.read_encrypted_auto("stream.bin").await?.0, vec![7u8; 200_000]);
+    assert!(reopened.write_encrypted("buf0.txt", b"late").await.is_err());
+    assert!(reopened.write_encrypted("late.txt", b"late").await.is_err());
+    // Deletes are serialized with writes the same way
+    assert!(reopened.delete_file("buf1.txt").await.is_err());
+    assert!(reopened.exists("buf1.txt").await);
+
+    // Resuming with the same keys finishes the remaining files
+    fs::remove_file(storage.join("broken.txt"))?;
+    let report = ops.reencrypt_all(&new_km, 3).await?;
+    assert_eq!(report.rotated + report.resumed, 10);
+    assert!(report.resumed > 0);
+    assert!(!securefs::rotation::in_progress(&storage).await);
+
+    let ops = SecureFileOps::new(key_manager::KeyManager::from_key_file(&new), &storage).await?;
+    assert_eq!(ops.read_encrypted("buf5.txt").await?, b"buffer 5");
+    assert_eq!(ops.get_metadata("buf5.txt").await?.size, 8);
+    assert_eq!(ops.read_encrypted_auto("stream.bin").await?.0, vec![7u8; 200_000]);
+    assert_eq!(ops.with_compression(true).read_encrypted("packed.txt").await?, vec![b'z'; 4096]);
+
+    let err = SecureFileOps::new(old_km(), &storage).await.err().expect("old key must be retired");
+    assert!(matches!(
+        err.downcast_ref::<SecureFsError>(),
+        Some(SecureFsError::KeyMismatch { .. })
+    ));
+    // Stores still open with the old key read under the new one, and can't write under the old one
+    assert_eq!(reopened.read_encrypted("buf0.txt").await?, b"buffer 0");
+    let err = reopened.write_encrypted("late.txt", b"late").await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<SecureFsError>(),
+        Some(SecureFsError::KeyMismatch { .. })
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_encrypted_names() -> Result<()> {
+    use securefs::vault::VaultOptions;
+    use storagefile_ops::SecureFileOps;
+
+    /// Every path below `dir`, relative to it
+    fn on_disk(dir: &std::path::Path) -> Vec<String> {
+        walkdir(dir, dir)
+    }
+    fn walkdir(root: &std::path::Path, dir: &std::path::Path) -> Vec<String> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir).expect("readable") {
+            let path = entry.expect("entry").path();
+            found.push(path.strip_prefix(root).expect("below root").to_string_lossy().into_owned());
+            if path.is_dir() {
+                found.extend(walkdir(root, &path));
+            }
+        }
+        found
+    }
+
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let old = keyfile::KeyFile::new([0x42u8; 32]);
+    let new = keyfile::KeyFile::new([0x24u8; 32]);
+    let options = VaultOptions {
+        encrypted_names: true,
+        ..Default::default()
+    };
+
+    let ops =
+        SecureFileOps::init_with_options(key_manager::KeyManager::from_key_file(&old), &storage, options).await?;
+    ops.write_encrypted("docs/payroll-2026.xlsx", b"salaries").await?;
+    ops.write_encrypted_stream("top.bin", &mut Cursor::new(b"top".to_vec())).await?;
+
+    let before = on_disk(&storage);
+    assert!(before.iter().all(|p| !p.contains("payroll") && !p.contains("docs") && !p.contains("top")));
+
+    let names = |files: Vec<(String, u64, bool)>| files.into_iter().map(|f| f.0).collect::<Vec<_>>();
+    assert_eq!(names(ops.list_files().await?), ["docs/payroll-2026.xlsx", "top.bin"]);
+    assert_eq!(ops.list_subdirs(None).await?, ["docs"]);
+    assert_eq!(ops.read_encrypted("docs/payroll-2026.xlsx").await?, b"salaries");
+    assert!(ops.write_encrypted(&"x".repeat(128), b"too long").await.is_err());
+
+    // Rotation re-encrypts names under the new key as well
+    ops.reencrypt_all(&key_manager::KeyManager::from_key_file(&new), 2).await?;
+    let after = on_disk(&storage);
+    assert!(after.iter().all(|p| !before.contains(p) || p.starts_with(".securefs")));
+
+    let ops = SecureFileOps::new(key_manager::KeyManager::from_key_file(&new), &storage).await?;
+    assert_eq!(names(ops.list_files().await?), ["docs/payroll-2026.xlsx", "top.bin"]);
+    assert_eq!(ops.read_encrypted_auto("top.bin").await?.0, b"top");
+    assert_eq!(ops.get_metadata("docs/payroll-2026.xlsx").await?.filename, "docs/payroll-2026.xlsx");
+
+    // Names can only be encrypted in a store that starts out empty
+    let plain = tmp.path().join("plain");
+    SecureFileOps::init(key_manager::KeyManager::from_key_file(&old), &plain, false)
+        .await?
+        .write_encrypted("a.txt", b"a")
+        .await?;
+    assert!(
+        SecureFileOps::init_with_options(key_manager::KeyManager::from_key_file(&old), &plain, options)
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_version_history() -> Result<()> {
+    use securefs::history::{HistoryPolicy, HISTORY_DIR};
+    use securefs::vault::VaultOptions;
+    use storagefile_ops::SecureFileOps;
+
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let old = keyfile::KeyFile::new([0x42u8; 32]);
+    let new = keyfile::KeyFile::new([0x24u8; 32]);
+    let options = VaultOptions {
+        encrypted_names: true,
+        ..Default::default()
+    };
+    let ops = SecureFileOps::init_with_options(key_manager::KeyManager::from_key_file(&old), &storage, options)
+        .await?
+        .with_history(HistoryPolicy::keep_last(2));
+
+    for content in ["one", "two", "three"] {
+        ops.write_encrypted("docs/a.txt", content.as_bytes()).await?;
+    }
+    ops.write_encrypted_stream("docs/a.txt", &mut Cursor::new(b"four".to_vec())).await?;
+
+    // The current version, then the two most recent it replaced
+    let versions = ops.list_versions("docs/a.txt").await?;
+    assert_eq!(versions.len(), 3);
+    assert!(versions[0].archived_at.is_none() && versions[1].archived_at > versions[2].archived_at);
+    assert_eq!(versions[0].version, ops.version("docs/a.txt").await?);
+    assert_eq!(ops.read_version("docs/a.txt", versions[0].version).await?, b"four");
+    assert_eq!(ops.read_version("docs/a.txt", versions[2].version).await?, b"two");
+
+    let restored = ops.restore("docs/a.txt", versions[2].version).await?;
+    assert_eq!(ops.read_encrypted("docs/a.txt").await?, b"two");
+    assert_eq!(ops.get_metadata("docs/a.txt").await?.size, 3);
+    let versions = ops.list_versions("docs/a.txt").await?;
+    assert_eq!(versions[0].version, restored);
+    assert_eq!(ops.read_version("docs/a.txt", versions[1].version).await?, b"four");
+    // Archived versions stay out of listings
+    assert_eq!(ops.list_files().await?.len(), 1);
+
+    // Archived versions follow the file through a key rotation
+    ops.reencrypt_all(&key_manager::KeyManager::from_key_file(&new), 2).await?;
+    let ops = SecureFileOps::new(key_manager::KeyManager::from_key_file(&new), &storage)
+        .await?
+        .with_history(HistoryPolicy::keep_last(2));
+    let versions = ops.list_versions("docs/a.txt").await?;
+    assert_eq!(versions.len(), 3);
+    assert_eq!(ops.read_version("docs/a.txt", versions[2].version).await?, b"three");
+
+    ops.delete_file("docs/a.txt").await?;
+    assert!(ops.list_versions("docs/a.txt").await?.is_empty());
+    assert!(ops.read_version("docs/a.txt", versions[1].version).await.is_err());
+    fn files_below(dir: &std::path::Path) -> usize {
+        fs::read_dir(dir)
+            .expect("readable")
+            .map(|e| e.expect("entry").path())
+            .map(|p| if p.is_dir() { files_below(&p) } else { 1 })
+            .sum()
+    }
+    assert_eq!(files_below(&storage.join(HISTORY_DIR)), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trash() -> Result<()> {
+    use securefs::error::SecureFsError;
+    use securefs::history::HistoryPolicy;
+    use securefs::trash::{TrashPolicy, TRASH_DIR};
+    use securefs::vault::VaultOptions;
+    use storagefile_ops::SecureFileOps;
+
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let old = keyfile::KeyFile::new([0x42u8; 32]);
+    let new = keyfile::KeyFile::new([0x24u8; 32]);
+    let options = VaultOptions {
+        encrypted_names: true,
+        ..Default::default()
+    };
+    let ops = SecureFileOps::init_with_options(key_manager::KeyManager::from_key_file(&old), &storage, options)
+        .await?
+        .with_history(HistoryPolicy::default())
+        .with_trash(TrashPolicy::retain_days(30));
+
+    ops.write_encrypted("docs/a.txt", b"a1").await?;
+    ops.write_encrypted("docs/a.txt", b"a2").await?;
+    ops.write_encrypted("docs/b.txt", b"b").await?;
+    ops.write_encrypted("top.txt", b"top").await?;
+
+    ops.delete_file("docs/a.txt").await?;
+    ops.remove_dir("docs", true).await?;
+    assert_eq!(ops.list_files().await?.len(), 1);
+    let trashed = ops.list_trash().await?;
+    let names: Vec<_> = trashed.iter().map(|e| (e.name.as_str(), e.is_dir)).collect();
+    assert_eq!(names, [("docs", true), ("docs/a.txt", false)]);
+
+    // Trashed files follow a key rotation
+    ops.reencrypt_all(&key_manager::KeyManager::from_key_file(&new), 2).await?;
+    let ops = SecureFileOps::new(key_manager::KeyManager::from_key_file(&new), &storage)
+        .await?
+        .with_trash(TrashPolicy::retain_days(30));
+
+    assert_eq!(ops.restore_trash(&trashed[0].id).await?, "docs");
+    assert_eq!(ops.read_encrypted("docs/b.txt").await?, b"b");
+    assert_eq!(ops.restore_trash(&trashed[1].id).await?, "docs/a.txt");
+    assert_eq!(ops.read_encrypted("docs/a.txt").await?, b"a2");
+    assert_eq!(ops.get_metadata("docs/a.txt").await?.size, 2);
+    let versions = ops.list_versions("docs/a.txt").await?;
+    assert_eq!(ops.read_version("docs/a.txt", versions[1].version).await?, b"a1");
+    assert!(ops.list_trash().await?.is_empty());
+
+    // Restoring never overwrites a file written since
+    ops.delete_file("top.txt").await?;
+    ops.write_encrypted("top.txt", b"new top").await?;
+    let id = ops.list_trash().await?[0].id.clone();
+    let err = ops.restore_trash(&id).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(SecureFsError::AlreadyExists(_))));
+
+    // Entries past retention are purged
+    let trash = storage.join(TRASH_DIR);
+    fs::rename(trash.join(&id), trash.join(format!("{:020}", 1)))?;
+    ops.write_encrypted("old.txt", b"old").await?;
+    ops.delete_file("old.txt").await?;
+    assert_eq!(ops.list_trash().await?.len(), 1);
+    assert_eq!(ops.empty_trash().await?, 1);
+    assert!(ops.list_trash().await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rename_and_copy() -> Result<()> {
+    use securefs::error::SecureFsError;
+    use securefs::history::HistoryPolicy;
+    use securefs::vault::VaultOptions;
+    use storagefile_ops::SecureFileOps;
+
+    let tmp = TempDir::new()?;
+    let key = keyfile::KeyFile::new([0x42u8; 32]);
+    let options = VaultOptions {
+        encrypted_names: true,
+        ..Default::default()
+    };
+    let ops = SecureFileOps::init_with_options(key_manager::KeyManager::from_key_file(&key), tmp.path().join("names"), options)
+        .await?
+        .with_history(HistoryPolicy::default());
+
+    ops.write_encrypted_stream("docs/a.bin", &mut Cursor::new(b"a1".to_vec())).await?;
+    ops.write_encrypted_stream("docs/a.bin", &mut Cursor::new(b"a2".to_vec())).await?;
+    ops.write_encrypted("b.txt", b"b").await?;
+    let archived = ops.list_versions("docs/a.bin").await?[1].version;
+
+    // Moves keep the file, its metadata and its history readable
+    ops.rename("docs/a.bin", "archive/2026/a.bin").await?;
+    assert!(!ops.exists("docs/a.bin").await);
+    assert_eq!(ops.read_encrypted_auto("archive/2026/a.bin").await?.0, b"a2");
+    assert_eq!(ops.get_metadata("archive/2026/a.bin").await?.filename, "archive/2026/a.bin");
+    assert_eq!(ops.read_version("archive/2026/a.bin", archived).await?, b"a1");
+    ops.rename("b.txt", "c.txt").await?;
+    assert_eq!(ops.read_encrypted("c.txt").await?, b"b");
+
+    let err = ops.rename("c.txt", "archive/2026/a.bin").await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(SecureFsError::AlreadyExists(_))));
+
+    // Copies are independent files
+    ops.copy("archive/2026/a.bin", "copy.bin").await?;
+    assert_eq!(ops.read_encrypted_auto("copy.bin").await?.0, b"a2");
+    assert_ne!(ops.version("copy.bin").await?, ops.version("archive/2026/a.bin").await?);
+    assert_eq!(ops.list_versions("copy.bin").await?.len(), 1);
+    let err = ops.copy("c.txt", "copy.bin").await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(SecureFsError::AlreadyExists(_))));
+
+    // Streams no longer depend on their name: even a plain `mv` on disk works
+    let storage = tmp.path().join("plain");
+    let km = key_manager::KeyManager::from_key_file(&key);
+    let mut legacy = Vec::new();
+    streaming::StreamEncryptor::new(km.derive_subkey(key_manager::subkey::STREAM_CONTENT))
+        .with_legacy_cipher(km.cipher())
+        .encrypt_stream(&mut Cursor::new(b"old".to_vec()), &mut legacy, Default::default(), Some(b"old.bin"))
+        .await?;
+    let ops = SecureFileOps::init(km, &storage, false).await?;
+    ops.write_encrypted_stream("new.bin", &mut Cursor::new(b"new".to_vec())).await?;
+    fs::rename(storage.join("new.bin"), storage.join("moved.bin"))?;
+    assert_eq!(ops.read_encrypted_auto("moved.bin").await?.0, b"new");
+
+    // Streams written before file IDs get one when renamed
+    fs::write(storage.join("old.bin"), legacy)?;
+    ops.rename("old.bin", "renamed.bin").await?;
+    assert_eq!(ops.read_encrypted_auto("renamed.bin").await?.0, b"old");
+    let raw = fs::read(storage.join("renamed.bin"))?;
+    assert_eq!(raw[1] & streaming::FLAG_FILE_ID, streaming::FLAG_FILE_ID);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_crypto_shredding() -> Result<()> {
+    use securefs::error::SecureFsError;
+    use securefs::file_keys::{DeleteMode, KeyTable};
+    use securefs::history::HistoryPolicy;
+    use storagefile_ops::SecureFileOps;
+
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let table_path = tmp.path().join("keys.json");
+    let old = keyfile::KeyFile::new([0x42u8; 32]);
+    let new = keyfile::KeyFile::new([0x24u8; 32]);
+    let open = |key: &keyfile::KeyFile| {
+        let km = key_manager::KeyManager::from_key_file(key);
+        let table = KeyTable::new(&km, &table_path);
+        let storage = storage.clone();
+        async move {
+            Ok::<_, anyhow::Error>(
+                SecureFileOps::new(km, storage)
+                    .await?
+                    .with_key_table(table)
+                    .with_history(HistoryPolicy::default()),
+            )
+        }
+    };
+
+    // A file written before the table can't be shredded
+    let legacy = SecureFileOps::init(key_manager::KeyManager::from_key_file(&old), &storage, false).await?;
+    legacy.write_encrypted("legacy.txt", b"legacy").await?;
+
+    let ops = open(&old).await?;
+    ops.write_encrypted("secret.txt", b"v1").await?;
+    ops.write_encrypted("secret.txt", b"v2").await?;
+    let mut reader = Cursor::new(vec![7u8; 100_000]);
+    ops.write_encrypted_stream("big.bin", &mut reader).await?;
+    let on_disk = fs::read(storage.join("secret.txt"))?;
+    assert_eq!(on_disk[0], securefs::file_keys::VERSION_V4_FILE_KEY);
+    assert_eq!(ops.read_encrypted("secret.txt").await?, b"v2");
+    assert_eq!(ops.read_encrypted("legacy.txt").await?, b"legacy");
+
+    let err = ops.delete_file_with("legacy.txt", DeleteMode::Shred).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(SecureFsError::Key(_))));
+
+    // Files with their own keys survive a rotation
+    let report = ops.reencrypt_all(&key_manager::KeyManager::from_key_file(&new), 2).await?;
+    assert_eq!((report.rotated, report.own_key), (1, 2));
+    let ops = open(&new).await?;
+    assert_eq!(ops.read_encrypted("secret.txt").await?, b"v2");
+    assert_eq!(ops.read_encrypted("legacy.txt").await?, b"legacy");
+    let mut out = Vec::new();
+    ops.read_encrypted_stream("big.bin", &mut out).await?;
+    assert_eq!(out, vec![7u8; 100_000]);
+
+    // Once shredded, no saved copy of the file or its history decrypts
+    let saved = fs::read(storage.join("secret.txt"))?;
+    ops.delete_file_with("secret.txt", DeleteMode::Shred).await?;
+    assert!(!ops.exists("secret.txt").await);
+    fs::write(storage.join("secret.txt"), &saved)?;
+    let err = ops.read_encrypted("secret.txt").await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(SecureFsError::FileKeyMissing(_))));
+    let err = ops.read_encrypted_auto("secret.txt").await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(SecureFsError::FileKeyMissing(_))));
+    let table: serde_json::Value = serde_json::from_slice(&fs::read(&table_path)?)?;
+    assert_eq!(table["keys"].as_object().map(|keys| keys.len()), Some(1));
+
+    // Plain deletes drop the key too
+    ops.delete_file("big.bin").await?;
+    let table: serde_json::Value = serde_json::from_slice(&fs::read(&table_path)?)?;
+    assert_eq!(table["keys"].as_object().map(|keys| keys.len()), Some(0));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_key_command_helper_protocol() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let db = tmp.path().join("helper.db");
+    let log = tmp.path().join("helper.log");
+    let script = tmp.path().join("key-helper.sh");
+    fs::write(
+        &script,
+        format!(
+            r#"#!/bin/sh
+# Stand-in for a secret manager: keeps the key in a file
+request=$(cat)
+echo "$1 $(echo "$request" | grep '^storage_dir=')" >> '{log}'
+case "$1" in
+  get) [ -f '{db}' ] && cat '{db}' ;;
+  store) echo "$request" | grep -E '^key(_id)?=' > '{db}' ;;
+  erase) rm -f '{db}' ;;
+  *) echo "unknown action $1" >&2; exit 2 ;;
+esac
+exit 0
+"#,
+            db = db.display(),
+            log = log.display()
+        ),
+    )?;
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+
+    let cfg = config::Config::new("", storage.to_string_lossy().to_string())
+        .with_key_command(script.to_string_lossy().to_string());
+    cfg.validate()?;
+
+    // create stores through the helper; open reads it back
+    let key_id = key_manager::KeyManager::create(&cfg).await?.key_id();
+    assert!(fs::read_to_string(&db)?.contains(&format!("key_id={}", hex::encode(key_id))));
+    assert_eq!(key_manager::KeyManager::open(&cfg).await?.key_id(), key_id);
+    assert!(fs::read_to_string(&log)?.contains(&format!("get storage_dir={}", storage.display())));
+
+    // erase removes it, after which open reports a missing key
+    key_provider::from_config(&cfg).erase(&key_id)?;
+    let err = key_manager::KeyManager::open(&cfg).await.err().expect("erased key must be gone");
+    assert!(matches!(
+        err.downcast_ref::<securefs::error::SecureFsError>(),
+        Some(securefs::error::SecureFsError::KeyNotFound(_))
+    ));
+
+    // key_command and key_source are mutually exclusive
+    let both = cfg.with_key_source(config::KeySource::Stdin);
+    assert!(both.validate().is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rich_metadata_and_tags() -> Result<()> {
+    use storagefile_ops::SecureFileOps;
+
+    let tmp = TempDir::new()?;
+    let key = keyfile::KeyFile::new([0x42u8; 32]);
+    let ops = SecureFileOps::new(key_manager::KeyManager::from_key_file(&key), tmp.path().join("storage")).await?;
+
+    let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".repeat(100);
+    ops.write_encrypted_stream("image.png", &mut Cursor::new(png.clone())).await?;
+    let meta = ops.get_metadata("image.png").await?;
+    assert_eq!(meta.size, png.len() as u64);
+    assert_eq!(meta.content_type.as_deref(), Some("image/png"));
+    assert_eq!(meta.sha256, Some(hex::encode(<sha2::Sha256 as sha2::Digest>::digest(&png))));
+    assert_eq!(meta.stored_size, Some(fs::metadata(tmp.path().join("storage/image.png"))?.len()));
+    assert!(meta.compression_ratio().is_some());
+    let created = meta.created.expect("created is recorded");
+
+    // Tags change without a new version of the content
+    let version = ops.version("image.png").await?;
+    let meta = ops
+        .update_tags("image.png", |tags| {
+            tags.insert("project".into(), "apollo".into());
+            tags.insert("draft".into(), "yes".into());
+        })
+        .await?;
+    assert_eq!(meta.tags.len(), 2);
+    ops.update_tags("image.png", |tags| {
+        tags.remove("draft");
+    })
+    .await?;
+    ops.set_source_path("image.png", Some("/home/me/image.png".into())).await?;
+    assert_eq!(ops.version("image.png").await?, version);
+
+    // Overwrites, renames and copies keep tags, source and creation time
+    ops.write_encrypted("image.png", b"now plain text").await?;
+    ops.rename("image.png", "notes.txt").await?;
+    ops.copy("notes.txt", "copy.txt").await?;
+    for name in ["notes.txt", "copy.txt"] {
+        let meta = ops.get_metadata(name).await?;
+        assert_eq!(meta.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(meta.tags.get("project").map(String::as_str), Some("apollo"));
+        assert!(!meta.tags.contains_key("draft"));
+        assert_eq!(meta.source_path.as_deref(), Some("/home/me/image.png"));
+    }
+    let meta = ops.get_metadata("notes.txt").await?;
+    assert_eq!(meta.created, Some(created));
+    assert!(meta.modified >= meta.created);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upgraded_store_skips_legacy_sidecars() -> Result<()> {
+    use storagefile_ops::SecureFileOps;
+
+    // A store as the first release left it: raw master key files with a
+    // plaintext `<stem>.meta.json` sidecar each, and no vault descriptor
+    let tmp = TempDir::new()?;
+    let storage = tmp.path().join("storage");
+    let key = keyfile::KeyFile::new([0x42u8; 32]);
+    let km = || key_manager::KeyManager::from_key_file(&key);
+    fs::create_dir_all(&storage)?;
+    let data = encryptor::Encryptor::new(km().cipher()).encrypt(b"quarterly numbers", None)?;
+    fs::write(storage.join("report.txt"), data)?;
+    fs::write(storage.join("report.meta.json"), r#"{"filename":"report.txt","size":17}"#)?;
+
+    let ops = SecureFileOps::new(km(), &storage).await?;
+    ops.write_encrypted("notes.meta.json", b"mine").await?;
+    let listed: Vec<String> = ops.list_files().await?.into_iter().map(|(name, ..)| name).collect();
+    assert_eq!(listed, ["notes.meta.json", "report.txt"]);
+    assert_eq!(ops.verify_key().await?, Some(true));
+    drop(ops);
+
+    // init and key rotation don't trip over the sidecar
+    let ops = SecureFileOps::init(km(), &storage, false).await?;
+    let new = keyfile::KeyFile::new([0x24u8; 32]);
+    let report = ops.reencrypt_all(&key_manager::KeyManager::from_key_file(&new), 2).await?;
+    assert_eq!(report.rotated, 2);
+    let ops = SecureFileOps::new(key_manager::KeyManager::from_key_file(&new), &storage).await?;
+    assert_eq!(ops.read_encrypted_auto("report.txt").await?.0, b"quarterly numbers");
+
+    // The sidecar is then sealed under `.securefs-meta/` and removed
+    assert_eq!(ops.legacy_sidecars().await?, [storage.join("report.meta.json")]);
+    assert_eq!(ops.migrate_legacy_metadata().await?, (1, 0));
+    assert!(!storage.join("report.meta.json").exists());
+    assert!(ops.legacy_sidecars().await?.is_empty());
+    let meta = ops.get_metadata("report.txt").await?;
+    assert_eq!((meta.filename.as_str(), meta.size), ("report.txt", 17));
+    assert_eq!(ops.read_encrypted("notes.meta.json").await?, b"mine");
+
     Ok(())
 }
@@ -0,0 +1,266 @@
+//! Trash for deleted files.
+//!
+//! When a store is opened with a [`TrashPolicy`] (see
+//! [`SecureFileOps::with_trash`](crate::storagefile_ops::SecureFileOps::with_trash)),
+//! deleting a file or directory moves it, with its metadata and version
+//! history, into the trash instead of removing it. Entries older than the
+//! retention period are purged after each deletion, or on demand.
+//!
+//! ## Layout
+//!
+//! ```text
+//! .securefs-trash/<deleted_at>/.securefs-entry.json         {"components":2}
+//! .securefs-trash/<deleted_at>/<path>                       the file or directory as it was
+//! .securefs-trash/<deleted_at>/.securefs-meta/<path>        its metadata sidecars
+//! .securefs-trash/<deleted_at>/.securefs-history/<path>     its archived versions
+//! ```
+//!
+//! `<deleted_at>` is the time of deletion in nanoseconds since the Unix
+//! epoch and doubles as the entry's ID. `<path>` is the deleted item's path
+//! relative to the storage root (encrypted, if names are), and `components`
+//! the number of path components that make up its name. Each entry is laid
+//! out like a storage root holding only the deleted item, so key rotation
+//! re-encrypts it like the store itself, and nothing in it is readable
+//! without the master key.
+
+use crate::error::SecureFsError;
+use crate::history;
+use crate::metadata;
+use crate::name_cipher::NameCipher;
+use crate::util::{self, RESERVED_PREFIX};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::debug;
+
+/// Directory in the storage root holding deleted files
+pub const TRASH_DIR: &str = ".securefs-trash";
+
+/// Description of an entry, inside the entry
+const ENTRY_FILE: &str = ".securefs-entry.json";
+
+/// How long deleted files stay in the trash.
+/// Without `retention_days`, they stay until the trash is emptied.
+///
+/// ```json
+/// { "trash": { "retention_days": 30 } }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashPolicy {
+    /// Purge entries deleted more than this many days ago
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u64>,
+}
+
+impl TrashPolicy {
+    /// Keeps deleted files for `days` days
+    pub fn retain_days(days: u64) -> Self {
+        Self {
+            retention_days: Some(days),
+        }
+    }
+
+    /// Whether an entry deleted at `deleted_at` is past retention at `now`
+    fn expired(&self, deleted_at: SystemTime, now: SystemTime) -> bool {
+        self.retention_days.is_some_and(|days| {
+            now.duration_since(deleted_at)
+                .is_ok_and(|elapsed| elapsed > Duration::from_secs(days * 24 * 60 * 60))
+        })
+    }
+}
+
+/// A deleted file or directory, as listed by
+/// [`SecureFileOps::list_trash`](crate::storagefile_ops::SecureFileOps::list_trash)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    /// ID to restore the entry by
+    pub id: String,
+    /// Storage name the entry had
+    pub name: String,
+    pub deleted_at: SystemTime,
+    pub is_dir: bool,
+    /// Encrypted size in bytes of everything in the entry
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EntryInfo {
+    components: usize,
+}
+
+/// A trash entry on disk
+pub(crate) struct Entry {
+    pub id: String,
+    pub dir: PathBuf,
+    pub deleted_at: SystemTime,
+}
+
+/// Moves the file or directory at `path` under `root`, whose storage name
+/// has `components` components, into a new trash entry. Returns its ID.
+pub(crate) async fn move_in(root: &Path, path: &Path, components: usize) -> Result<String> {
+    let trash = root.join(TRASH_DIR);
+    fs::create_dir_all(&trash)
+        .await
+        .with_context(|| format!("creating trash directory {:?}", &trash))?;
+    let mut at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    // Deletions aren't serialized, and a coarse clock can repeat a timestamp
+    let (id, dir) = loop {
+        let id = format!("{:020}", at);
+        let dir = trash.join(&id);
+        match fs::create_dir(&dir).await {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => at += 1,
+            result => break result.map(|_| (id, dir)).with_context(|| format!("creating {:?}", &trash))?,
+        }
+    };
+    fs::write(dir.join(ENTRY_FILE), serde_json::to_vec(&EntryInfo { components })?)
+        .await
+        .with_context(|| format!("writing trash entry {:?}", &dir))?;
+
+    let relative = path
+        .strip_prefix(root)
+        .expect("BUG: stored files always lie under the storage root");
+    let dest = dir.join(relative);
+    move_item(path, &dest).await?;
+    move_item(&metadata::sidecar_path(root, path), &metadata::sidecar_path(&dir, &dest)).await?;
+    move_item(&history::history_dir(root, path), &history::history_dir(&dir, &dest)).await?;
+    util::sync_parent(path).await?;
+    debug!(entry = %dir.display(), "moved to trash");
+    Ok(id)
+}
+
+/// Moves the item of trash entry `entry`, found at `relative` in it, back
+/// to `dest` under `root`, and removes the entry
+pub(crate) async fn move_out(root: &Path, entry: &Entry, relative: &Path, dest: &Path) -> Result<()> {
+    let src = entry.dir.join(relative);
+    move_item(&src, dest).await?;
+    move_item(&metadata::sidecar_path(&entry.dir, &src), &metadata::sidecar_path(root, dest)).await?;
+    move_item(&history::history_dir(&entry.dir, &src), &history::history_dir(root, dest)).await?;
+    util::sync_parent(dest).await?;
+    remove(entry).await
+}
+
+/// Renames `src` to `dest`, creating its parent; missing sources are skipped
+async fn move_item(src: &Path, dest: &Path) -> Result<()> {
+    if !fs::try_exists(src).await? {
+        return Ok(());
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("creating directory {:?}", parent))?;
+    }
+    fs::rename(src, dest)
+        .await
+        .with_context(|| format!("moving {:?} to {:?}", src, dest))
+}
+
+/// Trash entries under `root`, newest first
+pub(crate) async fn entries(root: &Path) -> Result<Vec<Entry>> {
+    let trash = root.join(TRASH_DIR);
+    let mut entries = Vec::new();
+    let mut read_dir = match fs::read_dir(&trash).await {
+        Ok(read_dir) => read_dir,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("reading trash {:?}", &trash))),
+    };
+    while let Some(item) = read_dir.next_entry().await? {
+        let Some(id) = item.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        let Ok(nanos) = id.parse::<u64>() else { continue };
+        entries.push(Entry {
+            id,
+            dir: item.path(),
+            deleted_at: UNIX_EPOCH + Duration::from_nanos(nanos),
+        });
+    }
+    entries.sort_by_key(|e| std::cmp::Reverse(e.deleted_at));
+    Ok(entries)
+}
+
+/// Trash entry `id` under `root`
+pub(crate) async fn entry(root: &Path, id: &str) -> Result<Entry> {
+    entries(root)
+        .await?
+        .into_iter()
+        .find(|e| e.id == id)
+        .ok_or_else(|| SecureFsError::storage(format!("no trash entry {:?}", id)).into())
+}
+
+/// Storage name of the item in `entry`, and its path relative to the entry
+pub(crate) async fn locate(entry: &Entry, names: Option<&NameCipher>) -> Result<(String, PathBuf)> {
+    let info = fs::read(entry.dir.join(ENTRY_FILE))
+        .await
+        .with_context(|| format!("reading trash entry {:?}", &entry.dir))?;
+    let info: EntryInfo = serde_json::from_slice(&info)
+        .with_context(|| format!("parsing trash entry {:?}", &entry.dir))?;
+
+    let mut name = String::new();
+    let mut relative = PathBuf::new();
+    for _ in 0..info.components {
+        let dir = entry.dir.join(&relative);
+        let mut read_dir = fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("reading {:?}", &dir))?;
+        let mut found = None;
+        while let Some(item) = read_dir.next_entry().await? {
+            let file_name = item.file_name();
+            match file_name.to_str() {
+                Some(component) if !component.starts_with(RESERVED_PREFIX) => {
+                    found = Some(component.to_string());
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let component = found
+            .ok_or_else(|| SecureFsError::storage(format!("trash entry {} is incomplete", entry.id)))?;
+        let plain = match names {
+            Some(names) => names
+                .decrypt_component(&name, &component)
+                .ok_or_else(|| SecureFsError::decryption(format!("name in trash entry {} doesn't decrypt", entry.id)))?,
+            None => component.clone(),
+        };
+        if !name.is_empty() {
+            name.push('/');
+        }
+        name.push_str(&plain);
+        relative.push(component);
+    }
+    Ok((name, relative))
+}
+
+/// Whether `policy` drops `entry` now
+pub(crate) fn expired(policy: &TrashPolicy, entry: &Entry) -> bool {
+    policy.expired(entry.deleted_at, SystemTime::now())
+}
+
+/// Permanently removes `entry`
+pub(crate) async fn remove(entry: &Entry) -> Result<()> {
+    fs::remove_dir_all(&entry.dir)
+        .await
+        .with_context(|| format!("removing trash entry {:?}", &entry.dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_expires_old_entries_only() {
+        let now = UNIX_EPOCH + Duration::from_secs(100 * 86400);
+        let days_ago = |d: u64| now - Duration::from_secs(d * 86400);
+
+        let policy = TrashPolicy::retain_days(30);
+        assert!(!policy.expired(days_ago(29), now));
+        assert!(policy.expired(days_ago(31), now));
+        assert!(!policy.expired(now + Duration::from_secs(60), now));
+        assert!(!TrashPolicy::default().expired(days_ago(99), now));
+    }
+}
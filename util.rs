@@ -1,4 +1,15 @@
+use anyhow::{Context, Result};
+use rand_core::{OsRng, RngCore};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Prefix of names reserved for SecureFS's own files in the storage root
+pub const RESERVED_PREFIX: &str = ".securefs";
+
+/// Prefix of temporary files written by [`AtomicFile`]
+pub const TEMP_PREFIX: &str = ".securefs-tmp-";
 
 #[allow(dead_code)]
 pub fn time_it<T, F: FnOnce() -> T>(label: &str, f: F) -> T {
@@ -7,3 +18,135 @@ pub fn time_it<T, F: FnOnce() -> T>(label: &str, f: F) -> T {
     println!("{label} took {:?}", start.elapsed());
     result
 }
+
+/// A file written to a temporary name and renamed over its destination on
+/// [`commit`](Self::commit), so readers see either the old or the new contents.
+/// Dropped without committing, the temporary file is removed.
+pub struct AtomicFile {
+    file: fs::File,
+    tmp: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Creates a temporary file next to `dest`
+    pub async fn create(dest: impl Into<PathBuf>) -> Result<Self> {
+        Self::create_with_mode(dest.into(), 0o666).await
+    }
+
+    /// Like [`create`](Self::create), but readable only by the owner
+    /// (mode 0600 on Unix) from the moment it exists
+    pub async fn create_private(dest: impl Into<PathBuf>) -> Result<Self> {
+        Self::create_with_mode(dest.into(), 0o600).await
+    }
+
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    async fn create_with_mode(dest: PathBuf, mode: u32) -> Result<Self> {
+        let name = dest
+            .file_name()
+            .with_context(|| format!("{} has no file name", dest.display()))?
+            .to_string_lossy();
+        let mut tmp_name = format!("{}{:016x}-", TEMP_PREFIX, OsRng.next_u64());
+        // Keep within the usual 255-byte limit; the random part keeps it unique
+        let mut keep = name.len().min(255 - tmp_name.len());
+        while !name.is_char_boundary(keep) {
+            keep -= 1;
+        }
+        tmp_name.push_str(&name[..keep]);
+        let tmp = dest.with_file_name(tmp_name);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(mode);
+        let file = options
+            .open(&tmp)
+            .await
+            .with_context(|| format!("creating {}", tmp.display()))?;
+        Ok(Self {
+            file,
+            tmp,
+            dest,
+            committed: false,
+        })
+    }
+
+    pub fn file(&mut self) -> &mut fs::File {
+        &mut self.file
+    }
+
+    /// Flushes and fsyncs the data, renames it into place and fsyncs the directory
+    pub async fn commit(mut self) -> Result<()> {
+        self.file.flush().await?;
+        self.file
+            .sync_all()
+            .await
+            .with_context(|| format!("syncing {}", self.tmp.display()))?;
+        fs::rename(&self.tmp, &self.dest)
+            .await
+            .with_context(|| format!("renaming {} to {}", self.tmp.display(), self.dest.display()))?;
+        self.committed = true;
+        sync_parent(&self.dest).await
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// An exclusive lock on a file, held until dropped. It excludes other
+/// processes as well as other handles in this one.
+pub struct FileLock {
+    _file: std::fs::File,
+}
+
+impl FileLock {
+    /// Creates `path` if needed and waits for an exclusive lock on it
+    pub async fn acquire(path: &Path) -> Result<Self> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("opening lock file {}", path.display()))?;
+            file.lock()
+                .with_context(|| format!("locking {}", path.display()))?;
+            Ok(Self { _file: file })
+        })
+        .await
+        .context("lock task panicked")?
+    }
+}
+
+/// Creates the directory that will hold `path`, and any missing parents
+pub async fn create_parent(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("creating directory {:?}", dir))?;
+    }
+    Ok(())
+}
+
+/// Fsyncs the directory holding `path`, making a rename or creation durable
+pub async fn sync_parent(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        fs::File::open(dir)
+            .await
+            .with_context(|| format!("opening directory {}", dir.display()))?
+            .sync_all()
+            .await
+            .with_context(|| format!("syncing directory {}", dir.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
@@ -0,0 +1,237 @@
+//! Vault descriptor binding a storage directory to its master key.
+//!
+//! This module provides [`VaultDescriptor`], written by
+//! [`SecureFileOps::init`](crate::storagefile_ops::SecureFileOps::init) into the
+//! storage root and checked by
+//! [`SecureFileOps::new`](crate::storagefile_ops::SecureFileOps::new), so a wrong
+//! key is rejected up front instead of surfacing as per-file AEAD errors.
+//!
+//! ## Format
+//!
+//! `.securefs-vault.json` in the storage root:
+//!
+//! ```json
+//! {
+//!   "format_version": 1,
+//!   "created_at": 1760000000,
+//!   "compression": false,
+//!   "key_id": "<hex>",
+//!   "encrypted_names": true,
+//!   "key_check": "<hex>",
+//!   "mac": "<hex>"
+//! }
+//! ```
+//!
+//! - `encrypted_names` is omitted unless names are encrypted
+//! - `key_check` is HMAC-SHA256 of a fixed constant under the vault subkey
+//!   (see [`crate::key_manager::subkey::VAULT`]); it identifies the key
+//!   without revealing anything about it
+//! - `mac` is HMAC-SHA256 over the settings (every field except `key_check`
+//!   and `mac`), so the recorded settings can't be edited
+
+use crate::error::SecureFsError;
+use crate::key_manager::{subkey, KeyManager};
+use crate::util::AtomicFile;
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+/// Name of the descriptor file in the storage root
+pub const VAULT_FILE: &str = ".securefs-vault.json";
+
+/// Current descriptor format version
+pub const VAULT_FORMAT_VERSION: u32 = 1;
+
+const KEY_CHECK_INPUT: &[u8] = b"securefs/vault/key-check/v1";
+const DESCRIPTOR_MAC_DOMAIN: &[u8] = b"securefs/vault/descriptor/v1";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Settings recorded when the vault was created.
+///
+/// Fields added later must be skipped when at their default, so descriptors
+/// written by older versions serialize (and authenticate) unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VaultSettings {
+    pub format_version: u32,
+    /// Unix timestamp (seconds) of creation
+    pub created_at: u64,
+    /// Whether new files are compressed by default
+    pub compression: bool,
+    /// Hex key ID of the master key (see [`crate::keyfile::key_id_for`])
+    pub key_id: String,
+    /// Whether names are encrypted on disk (see [`crate::name_cipher`])
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted_names: bool,
+}
+
+/// Choices fixed when a vault is created
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VaultOptions {
+    /// Compress new files by default
+    pub compression: bool,
+    /// Encrypt file and directory names on disk
+    pub encrypted_names: bool,
+}
+
+/// Authenticated record of which key and settings a storage directory uses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultDescriptor {
+    #[serde(flatten)]
+    pub settings: VaultSettings,
+    key_check: String,
+    mac: String,
+}
+
+impl VaultDescriptor {
+    /// Builds a descriptor for a new vault owned by `km`
+    pub fn new(km: &KeyManager, compression: bool) -> Self {
+        Self::with_options(
+            km,
+            VaultOptions {
+                compression,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Builds a descriptor for a new vault owned by `km` with `options`
+    pub fn with_options(km: &KeyManager, options: VaultOptions) -> Self {
+        let settings = VaultSettings {
+            format_version: VAULT_FORMAT_VERSION,
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            compression: options.compression,
+            key_id: hex::encode(km.key_id()),
+            encrypted_names: options.encrypted_names,
+        };
+        let key_check = hex::encode(key_check_mac(km).finalize().into_bytes());
+        let mac = hex::encode(
+            descriptor_mac(km, &settings)
+                .expect("BUG: settings always serialize")
+                .finalize()
+                .into_bytes(),
+        );
+        Self {
+            settings,
+            key_check,
+            mac,
+        }
+    }
+
+    /// Rebinds the descriptor to `new_km`, keeping the recorded settings.
+    /// Used when the store is re-encrypted (see [`crate::rotation`]).
+    pub fn rekey(&self, new_km: &KeyManager) -> Self {
+        let settings = VaultSettings {
+            key_id: hex::encode(new_km.key_id()),
+            ..self.settings.clone()
+        };
+        let key_check = hex::encode(key_check_mac(new_km).finalize().into_bytes());
+        let mac = hex::encode(
+            descriptor_mac(new_km, &settings)
+                .expect("BUG: settings always serialize")
+                .finalize()
+                .into_bytes(),
+        );
+        Self {
+            settings,
+            key_check,
+            mac,
+        }
+    }
+
+    /// Checks that `km` is the vault's key and the descriptor is unmodified
+    pub fn verify(&self, km: &KeyManager) -> Result<(), SecureFsError> {
+        if self.settings.format_version > VAULT_FORMAT_VERSION {
+            return Err(SecureFsError::format(format!(
+                "vault format version {} is newer than this version of securefs supports ({})",
+                self.settings.format_version, VAULT_FORMAT_VERSION
+            )));
+        }
+
+        let key_check = hex::decode(&self.key_check)
+            .map_err(|_| SecureFsError::format("vault key check value is not valid hex"))?;
+        if key_check_mac(km).verify_slice(&key_check).is_err() {
+            return Err(SecureFsError::KeyMismatch {
+                expected: self.settings.key_id.clone(),
+                found: hex::encode(km.key_id()),
+            });
+        }
+
+        let mac = hex::decode(&self.mac)
+            .map_err(|_| SecureFsError::format("vault descriptor MAC is not valid hex"))?;
+        descriptor_mac(km, &self.settings)?
+            .verify_slice(&mac)
+            .map_err(|_| SecureFsError::format("vault descriptor failed authentication (modified or corrupted)"))
+    }
+
+    /// Reads the descriptor from `root`, or `None` if the vault predates descriptors
+    pub async fn load(root: &Path) -> Result<Option<Self>> {
+        let path = root.join(VAULT_FILE);
+        if !fs::try_exists(&path).await
+            .with_context(|| format!("checking existence of {}", path.display()))?
+        {
+            return Ok(None);
+        }
+        let json = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("reading vault descriptor {}", path.display()))?;
+        let descriptor = serde_json::from_str(&json)
+            .with_context(|| format!("parsing vault descriptor {}", path.display()))?;
+        Ok(Some(descriptor))
+    }
+
+    /// Writes the descriptor into `root`; fails if one already exists
+    pub async fn write(&self, root: &Path) -> Result<()> {
+        let path = root.join(VAULT_FILE);
+        let json = serde_json::to_string_pretty(self)?;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("creating vault descriptor {}", path.display()))?;
+        file.write_all(json.as_bytes()).await?;
+        file.sync_all().await?;
+        info!(path = %path.display(), key_id = %self.settings.key_id, "wrote vault descriptor");
+        Ok(())
+    }
+
+    /// Atomically replaces the descriptor in `root`
+    pub async fn replace(&self, root: &Path) -> Result<()> {
+        let path = root.join(VAULT_FILE);
+        let json = serde_json::to_string_pretty(self)?;
+        let mut file = AtomicFile::create(&path).await?;
+        file.file().write_all(json.as_bytes()).await?;
+        file.commit().await?;
+        info!(path = %path.display(), key_id = %self.settings.key_id, "replaced vault descriptor");
+        Ok(())
+    }
+}
+
+fn vault_mac(km: &KeyManager) -> HmacSha256 {
+    let key = km.derive_subkey_bytes(subkey::VAULT);
+    <HmacSha256 as Mac>::new_from_slice(&key[..]).expect("HMAC accepts any key length")
+}
+
+fn key_check_mac(km: &KeyManager) -> HmacSha256 {
+    let mut mac = vault_mac(km);
+    mac.update(KEY_CHECK_INPUT);
+    mac
+}
+
+fn descriptor_mac(km: &KeyManager, settings: &VaultSettings) -> Result<HmacSha256, SecureFsError> {
+    let encoded = serde_json::to_vec(settings)
+        .map_err(|e| SecureFsError::format(format!("encoding vault settings: {}", e)))?;
+    let mut mac = vault_mac(km);
+    mac.update(DESCRIPTOR_MAC_DOMAIN);
+    mac.update(&encoded);
+    Ok(mac)
+}
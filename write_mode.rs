@@ -0,0 +1,138 @@
+//! Conditional writes.
+//!
+//! This module provides [`WriteMode`], accepted by
+//! [`SecureFileOps::write_encrypted_with`](crate::storagefile_ops::SecureFileOps::write_encrypted_with)
+//! and
+//! [`SecureFileOps::write_encrypted_stream_with`](crate::storagefile_ops::SecureFileOps::write_encrypted_stream_with),
+//! and the [`FileVersion`] token those compare against.
+//!
+//! ## Versions
+//!
+//! A file's version is the first 16 bytes of the SHA-256 of its leading
+//! [`BINDING_LEN`] encrypted bytes. Every format starts with fresh random
+//! bytes (a nonce, or an ephemeral public key for recipient files), so each
+//! write yields a new version without reading the whole file. Versions are
+//! opaque: they identify a write but don't order writes.
+//!
+//! ## Locking
+//!
+//! Every write to a store checks its mode and renames its file into place
+//! while holding an exclusive lock on [`WRITE_LOCK_FILE`] in the storage
+//! root (see [`crate::util::FileLock`]), so a check can't be overtaken by
+//! another writer, in this process or another. Deletes, directory removals
+//! and trash moves take the same lock.
+//!
+//! A typical read-modify-write loop:
+//!
+//! ```no_run
+//! # use securefs::{storagefile_ops::SecureFileOps, write_mode::WriteMode, SecureFsError};
+//! # async fn bump(ops: &SecureFileOps) -> anyhow::Result<()> {
+//! loop {
+//!     let version = ops.version("counter").await?;
+//!     let n: u64 = String::from_utf8(ops.read_encrypted("counter").await?)?.parse()?;
+//!     let next = (n + 1).to_string();
+//!     match ops.write_encrypted_with("counter", next.as_bytes(), WriteMode::IfMatch(version)).await {
+//!         Err(e) if matches!(e.downcast_ref(), Some(SecureFsError::VersionMismatch { .. })) => continue,
+//!         result => return result.map(|_| ()),
+//!     }
+//! }
+//! # }
+//! ```
+
+use crate::error::SecureFsError;
+use crate::metadata::{content_header, BINDING_LEN};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Name of the lock file in the storage root
+pub const WRITE_LOCK_FILE: &str = ".securefs-write.lock";
+
+/// How a write treats an existing file of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// Replace any existing file
+    #[default]
+    Overwrite,
+    /// Fail with [`SecureFsError::AlreadyExists`] if the file exists
+    CreateNew,
+    /// Replace the file only if it is still at this version; fail with
+    /// [`SecureFsError::VersionMismatch`] if it changed or is missing
+    IfMatch(FileVersion),
+}
+
+/// Opaque token identifying one write of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileVersion([u8; 16]);
+
+impl FileVersion {
+    /// Version of the encrypted file at `path`
+    pub async fn of(path: &Path) -> Result<Self> {
+        Ok(Self::from_header(&content_header(path).await?))
+    }
+
+    /// Version of an encrypted file starting with `header`
+    pub fn from_header(header: &[u8]) -> Self {
+        let digest = Sha256::digest(&header[..header.len().min(BINDING_LEN)]);
+        let mut version = [0u8; 16];
+        version.copy_from_slice(&digest[..16]);
+        Self(version)
+    }
+}
+
+impl fmt::Display for FileVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl FromStr for FileVersion {
+    type Err = SecureFsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut version = [0u8; 16];
+        hex::decode_to_slice(s, &mut version)
+            .map_err(|_| SecureFsError::format(format!("{:?} is not a file version (32 hex digits)", s)))?;
+        Ok(Self(version))
+    }
+}
+
+/// Checks `mode` against the current file at `path`, named `name`.
+/// Callers hold the store's write lock.
+pub(crate) async fn check(mode: WriteMode, name: &str, path: &Path) -> Result<()> {
+    let exists = tokio::fs::try_exists(path).await?;
+    match mode {
+        WriteMode::Overwrite => Ok(()),
+        WriteMode::CreateNew if exists => Err(SecureFsError::AlreadyExists(name.to_string()).into()),
+        WriteMode::CreateNew => Ok(()),
+        WriteMode::IfMatch(expected) => {
+            let found = if exists { Some(FileVersion::of(path).await?) } else { None };
+            if found == Some(expected) {
+                Ok(())
+            } else {
+                Err(SecureFsError::VersionMismatch {
+                    name: name.to_string(),
+                    expected: expected.to_string(),
+                    found: found.map_or_else(|| "none (file is missing)".to_string(), |v| v.to_string()),
+                }
+                .into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_round_trip_through_hex() {
+        let version = FileVersion::from_header(b"\x02\x02random nonce bytes");
+        assert_eq!(version.to_string().parse::<FileVersion>().expect("parse"), version);
+        assert_ne!(version, FileVersion::from_header(b"\x02\x02other nonce bytes"));
+        assert!("not hex".parse::<FileVersion>().is_err());
+        assert!("abcd".parse::<FileVersion>().is_err());
+    }
+}